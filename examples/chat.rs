@@ -1,34 +1,906 @@
-use anyhow::Result;
-use dashmap::DashMap;
-use futures::{stream::SplitStream, SinkExt, StreamExt};
-use std::{fmt, net::SocketAddr, sync::Arc};
+use anyhow::{anyhow, Result};
+use axum::{
+    extract::{
+        ws::{Message as WsMessage, WebSocket, WebSocketUpgrade},
+        ConnectInfo, State as AxumState,
+    },
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use chrono::{DateTime, NaiveDate, Utc};
+use clap::{Parser, ValueEnum};
+use dashmap::{mapref::entry::Entry, DashMap};
+use futures::{future, stream::SplitStream, Sink, SinkExt, Stream, StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashSet, VecDeque},
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::Write,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use thiserror::Error;
 use tokio::{
     net::{TcpListener, TcpStream},
-    sync::mpsc,
+    sync::{mpsc, Notify},
+    task::JoinHandle,
+    time::{self, Instant},
+};
+use tokio_util::{
+    codec::{Decoder, Encoder, Framed, LengthDelimitedCodec, LinesCodec, LinesCodecError},
+    sync::CancellationToken,
+    task::TaskTracker,
 };
-use tokio_util::codec::{Framed, LinesCodec};
 use tracing::{info, level_filters::LevelFilter, warn};
 use tracing_subscriber::{fmt::Layer, layer::SubscriberExt, util::SubscriberInitExt, Layer as _};
 
 const MAX_MESSAGES: usize = 128;
+// 用户连接后默认加入的房间
+const DEFAULT_ROOM: &str = "lobby";
+// 用户名的最大长度
+const MAX_USERNAME_LEN: usize = 20;
+// 等待写入聊天日志的最大记录数
+const MAX_LOG_RECORDS: usize = 1024;
+// 用于去重的最近事件 ID 数
+const MAX_SEEN_EVENTS: usize = 4096;
+// 和对端服务器断开后重连的间隔
+const PEER_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+// 广播耗时直方图的上界，单位为秒
+const FAN_OUT_BUCKETS: [f64; 10] = [0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+// 插件发送消息时使用的用户名
+const BOT_NAME: &str = "bot";
+// 文件传输中单个数据块的最大字节数
+const TRANSFER_CHUNK_SIZE: usize = 64 * 1024;
+// 上传端和下载端之间缓冲的数据块数
+const TRANSFER_BUFFER: usize = 16;
+// IRC 协议中服务端的名称
+const IRC_SERVER_NAME: &str = "chat";
+// 派生密码哈希时使用的上下文
+const PASSWORD_CONTEXT: &str = "ecosystem chat 2024-05-20 password hash";
+
+// 按行收发的双向连接，TCP 和 WebSocket 连接都会转换为该形式
+trait LineStream:
+    Stream<Item = Result<String>> + Sink<String, Error = anyhow::Error> + Send + Unpin + 'static
+{
+}
+
+impl<T> LineStream for T where
+    T: Stream<Item = Result<String>> + Sink<String, Error = anyhow::Error> + Send + Unpin + 'static
+{
+}
+
+type Lines = Box<dyn LineStream>;
+
+// LinesCodec 遇到超长的行会返回错误，Framed 随后就会结束整个流
+// 这里把超长的行作为一项错误返回，连接可以继续读取后面的行
+#[derive(Debug)]
+struct BoundedLinesCodec(LinesCodec);
 
 // State：包含所有连接的客户端的状态。
-#[derive(Debug, Default)]
+#[derive(Debug)]
 struct State {
-    peers: DashMap<SocketAddr, mpsc::Sender<Arc<Message>>>,
+    config: Config,
+    peers: DashMap<SocketAddr, Arc<Outbox>>,
+    // 房间名 -> 房间内的成员，房间在最后一个成员离开后删除
+    rooms: DashMap<String, HashSet<SocketAddr>>,
+    // 用户名 -> 地址，用于私聊时查找用户
+    users: DashMap<String, SocketAddr>,
+    // 最近的房间消息，超过 history_size 条时丢弃最早的消息
+    history: Mutex<VecDeque<Arc<Message>>>,
+    // 请求关闭服务，主循环停止接受新连接
+    shutdown: CancellationToken,
+    // 已通知用户服务即将关闭，所有连接开始退出
+    closing: CancellationToken,
+    // 每个用户的写任务，关闭服务时等待它们发送完剩余消息
+    writers: TaskTracker,
+    // 聊天日志的写入队列，未配置日志目录或关闭服务后为 None
+    log: Mutex<Option<mpsc::Sender<LogRecord>>>,
+    // 聊天日志的写线程，关闭服务时等待它写完队列中的记录
+    log_writer: Mutex<Option<JoinHandle<()>>>,
+    // 用户账号，未配置账号文件时不需要密码
+    accounts: Option<Accounts>,
+    // 被封禁的用户和 IP
+    bans: Bans,
+    // 被禁言的用户及禁言结束时间，None 表示永久禁言
+    mutes: DashMap<String, Option<DateTime<Utc>>>,
+    // 在线用户的连接时间和最后活跃时间
+    presence: DashMap<SocketAddr, Presence>,
+    // 和其他聊天服务器互联
+    federation: Federation,
+    // 写任务也会更新指标，因此用 Arc 共享
+    metrics: Arc<Metrics>,
+    // 按配置顺序调用的插件
+    plugins: Vec<Box<dyn ChatPlugin>>,
+    // 等待接受和正在进行的文件传输
+    transfers: Transfers,
+    // 下一条聊天消息的 ID
+    message_id: AtomicU64,
+    // 房间内最近的聊天消息 ID 和发送者，用于修改和删除，每个房间最多 edit_window 条
+    recent: DashMap<String, VecDeque<(String, String)>>,
+}
+
+// 聊天插件，在用户加入、离开房间和发送聊天消息时被调用
+// 新的插件实现该 trait 并在 PluginKind 中注册即可通过配置启用
+trait ChatPlugin: fmt::Debug + Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn on_join(&self, _room: &str, _username: &str) -> Vec<PluginAction> {
+        Vec::new()
+    }
+
+    fn on_leave(&self, _room: &str, _username: &str) -> Vec<PluginAction> {
+        Vec::new()
+    }
+
+    fn on_chat(&self, _room: &str, _username: &str, _content: &str) -> Vec<PluginAction> {
+        Vec::new()
+    }
+}
+
+#[derive(Debug)]
+enum PluginAction {
+    // 丢弃聊天消息，之后的插件不再被调用
+    Drop,
+    // 修改聊天消息的内容
+    Rewrite(String),
+    // 只发送给触发插件的用户
+    Reply(Message),
+    // 发送到房间内所有成员，聊天消息的 ID 在广播时分配
+    Emit(Message),
+}
+
+// 依次调用所有插件后的结果
+#[derive(Debug, Default)]
+struct PluginOutcome {
+    dropped: bool,
+    content: String,
+    replies: Vec<Message>,
+    emits: Vec<Message>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum PluginHook {
+    Join,
+    Leave,
+    Chat,
+}
+
+// 内置的插件
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+enum PluginKind {
+    // 将脏话替换为 *，或者直接拦截
+    Profanity,
+    // !echo <text> 让机器人重复一遍
+    Echo,
+    // !time 让机器人报告当前时间
+    Time,
+}
+
+#[derive(Debug)]
+struct ProfanityFilter {
+    words: HashSet<String>,
+    // 拦截包含脏话的消息而不是替换
+    drop: bool,
+}
+
+#[derive(Debug)]
+struct EchoBot;
+
+#[derive(Debug)]
+struct TimeBot;
+
+// Prometheus 指标，由 /metrics 以文本格式输出
+#[derive(Debug, Default)]
+struct Metrics {
+    connections: AtomicU64,
+    broadcasts: AtomicU64,
+    bytes_sent: AtomicU64,
+    // 按原因统计的发送失败次数
+    write_errors: AtomicU64,
+    dropped_oldest: AtomicU64,
+    dropped_new: AtomicU64,
+    disconnected: AtomicU64,
+    fan_out: Histogram,
+}
+
+// 固定区间的直方图，每个区间单独计数，输出时再累加
+#[derive(Debug, Default)]
+struct Histogram {
+    buckets: [AtomicU64; FAN_OUT_BUCKETS.len()],
+    count: AtomicU64,
+    // 总耗时，单位为纳秒
+    sum: AtomicU64,
+}
+
+// 服务器之间转发房间内的加入、离开和聊天事件
+// 事件带有来源服务器 ID 和消息 ID，重复收到的事件直接丢弃，不会循环转发
+#[derive(Debug)]
+struct Federation {
+    server_id: String,
+    // 对端服务器 ID 到该连接发送队列的映射
+    links: DashMap<String, mpsc::Sender<FederationEvent>>,
+    seen: Mutex<SeenEvents>,
+}
+
+// 最近处理过的事件 ID，超过容量后淘汰最早的
+#[derive(Debug, Default)]
+struct SeenEvents {
+    order: VecDeque<String>,
+    ids: HashSet<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct FederationEvent {
+    id: String,
+    origin: String,
+    message: Arc<Message>,
+}
+
+// 服务器之间的连接上每帧为一个 JSON 对象，连接后先交换服务器 ID
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum LinkFrame {
+    Hello { server_id: String },
+    Event(FederationEvent),
+}
+
+// 文件传输，/send 发起后等待接收者 /accept 或 /reject
+// 接受后双方各自用一次性的 token 连接传输端口，文件内容不经过聊天连接
+#[derive(Debug, Default)]
+struct Transfers {
+    // 还没有被接受的传输
+    offers: DashMap<String, Arc<Transfer>>,
+    // 已接受的传输，连接传输端口时按 token 取出
+    tokens: DashMap<String, (Arc<Transfer>, TransferRole)>,
+}
+
+#[derive(Debug)]
+struct Transfer {
+    id: String,
+    sender: String,
+    recipient: String,
+    name: String,
+    // 上传端和下载端都可能报告结果，只通知一次
+    finished: AtomicBool,
+    // 接受后创建，上传端取走发送端，下载端取走接收端
+    upload: Mutex<Option<mpsc::Sender<TransferFrame>>>,
+    download: Mutex<Option<mpsc::Receiver<TransferFrame>>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TransferRole {
+    Upload,
+    Download,
+}
+
+// 传输端口上的一帧，第一个字节为帧类型，其余为内容
+#[derive(Debug, Clone, PartialEq)]
+enum TransferFrame {
+    // 连接后的第一帧，内容为 token
+    Hello(String),
+    Data(Bytes),
+    // 文件结束，内容为 32 字节的 blake3 校验和
+    End(blake3::Hash),
+    Error(String),
 }
 
+type TransferStream = Framed<TcpStream, LengthDelimitedCodec>;
+
+#[derive(Debug, Clone, Copy)]
+struct Presence {
+    connected_at: DateTime<Utc>,
+    last_active: DateTime<Utc>,
+}
+
+// 保存在 TOML 文件中的用户账号
+#[derive(Debug)]
+struct Accounts {
+    path: PathBuf,
+    users: Mutex<BTreeMap<String, Account>>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct AccountsFile {
+    #[serde(default)]
+    users: BTreeMap<String, Account>,
+}
+
+// 密码使用 blake3 加盐派生后保存，每个用户使用不同的盐
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Account {
+    salt: String,
+    password_hash: String,
+    #[serde(default)]
+    role: Role,
+}
+
+// 用户角色，版主可以踢人和禁言，管理员还可以封禁
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Role {
+    #[default]
+    User,
+    Moderator,
+    Admin,
+}
+
+// 封禁列表，指定了文件时每次修改后写回文件
+#[derive(Debug, Default)]
+struct Bans {
+    path: Option<PathBuf>,
+    bans: Mutex<Vec<Ban>>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct BansFile {
+    #[serde(default)]
+    bans: Vec<Ban>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Ban {
+    #[serde(flatten)]
+    target: BanTarget,
+    by: String,
+    // 封禁结束时间，None 表示永久封禁
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    until: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum BanTarget {
+    User(String),
+    Ip(IpAddr),
+}
+
+#[derive(Debug, Error)]
+#[error("Invalid username or password")]
+struct InvalidCredentials;
+
+// 聊天日志中的一条记录，每条记录序列化为一行 JSON
+#[derive(Debug, Serialize, Deserialize)]
+struct LogRecord {
+    timestamp: DateTime<Utc>,
+    sender: SocketAddr,
+    room: Option<String>,
+    message: Arc<Message>,
+}
+
+// 只追加写入的聊天日志，按天切换文件，单个文件超过 max_size 时切换到新的序号
+// 文件名形如 chat.2024-05-20.jsonl、chat.2024-05-20.1.jsonl
+#[derive(Debug)]
+struct ChatLog {
+    dir: PathBuf,
+    max_size: u64,
+    date: NaiveDate,
+    index: u32,
+    file: File,
+    size: u64,
+}
+
+// 每个用户待发送的消息队列，由该用户的写任务负责发送
+// 队列满时按 SlowConsumerPolicy 处理，避免一个慢用户阻塞整个广播
+#[derive(Debug)]
+struct Outbox {
+    queue: Mutex<VecDeque<Arc<Message>>>,
+    capacity: usize,
+    // 有新消息时唤醒写任务
+    readable: Notify,
+    // 写任务取走消息后唤醒等待空位的发送者
+    writable: Notify,
+    // 连接关闭或被断开时取消，读写两端都会退出
+    closed: CancellationToken,
+    // 用户退出后不再有新消息，写任务发送完剩余消息后退出
+    finished: AtomicBool,
+    // 被踢出时取消，读循环退出，写任务仍会发送完剩余消息
+    kicked: CancellationToken,
+    // 因队列已满而丢弃的消息数
+    dropped: AtomicU64,
+}
+
+// 用户的消息队列已满时的处理策略
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+enum SlowConsumerPolicy {
+    // 丢弃队列中最早的消息
+    DropOldest,
+    // 丢弃新消息
+    DropNew,
+    // 等待超时后断开该用户
+    Disconnect,
+}
+
+// 令牌桶：每秒补充 rate 个令牌，最多积攒 burst 个，每条消息消耗一个令牌
 #[derive(Debug)]
+struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+// 刷屏检测：第一次违规警告，第二次禁言，禁言结束后再次违规则断开连接
+#[derive(Debug)]
+struct FloodGuard {
+    bucket: TokenBucket,
+    mute_duration: Duration,
+    violations: u32,
+    muted_until: Option<Instant>,
+}
+
+#[derive(Debug, PartialEq)]
+enum FloodVerdict {
+    Allow,
+    Warn,
+    Mute(Duration),
+    // 禁言期间的消息直接丢弃
+    Muted,
+    Disconnect,
+}
+
 struct Peer {
     username: String,
-    stream: SplitStream<Framed<TcpStream, LinesCodec>>,
+    // 当前所在的房间，普通聊天消息会发送到该房间
+    room: Option<String>,
+    protocol: Protocol,
+    outbox: Arc<Outbox>,
+    stream: SplitStream<Lines>,
 }
 
-#[derive(Debug)]
+// JSON 协议下每条消息序列化为一行，type 字段表示消息类型
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
 enum Message {
-    UserJoined(String),
-    UserLeft(String),
-    Chat { sender: String, content: String },
+    #[serde(rename = "join")]
+    UserJoined {
+        room: String,
+        username: String,
+        timestamp: DateTime<Utc>,
+    },
+    #[serde(rename = "left")]
+    UserLeft {
+        room: String,
+        username: String,
+        timestamp: DateTime<Utc>,
+    },
+    // id 为 "服务器 ID:序号"，序号在本服务器内单调递增，互联的服务器之间不会重复
+    // 其他服务器转发的消息保留原来的 ID
+    Chat {
+        #[serde(default)]
+        id: String,
+        room: String,
+        sender: String,
+        content: String,
+        timestamp: DateTime<Utc>,
+    },
+    // 发送者修改或删除了自己的消息，id 为原消息的 ID
+    Edited {
+        id: String,
+        room: String,
+        sender: String,
+        content: String,
+        timestamp: DateTime<Utc>,
+    },
+    Deleted {
+        id: String,
+        room: String,
+        sender: String,
+        timestamp: DateTime<Utc>,
+    },
+    #[serde(rename = "rename")]
+    UserRenamed {
+        old: String,
+        new: String,
+        timestamp: DateTime<Utc>,
+    },
+    // 私聊消息，只发送给接收者和发送者本人
+    Direct {
+        sender: String,
+        recipient: String,
+        content: String,
+        timestamp: DateTime<Utc>,
+    },
+    // 告诉发送者新消息分配的 ID，之后可以用这个 ID 修改或删除
+    Sent {
+        id: String,
+        room: String,
+        timestamp: DateTime<Utc>,
+    },
+    // 服务端发送给单个用户的提示信息
+    System {
+        content: String,
+        timestamp: DateTime<Utc>,
+    },
+    // 服务端发送给单个用户的错误信息
+    Error {
+        content: String,
+        timestamp: DateTime<Utc>,
+    },
+    // 回放的历史消息，用于和实时消息区分
+    History {
+        message: Arc<Message>,
+    },
+    // 连接空闲时发送的心跳，客户端需要回复 PONG
+    Ping {
+        timestamp: DateTime<Utc>,
+    },
+    // 对客户端心跳的回复
+    Pong {
+        token: String,
+        timestamp: DateTime<Utc>,
+    },
+    // 房间的成员列表
+    Names {
+        room: String,
+        users: Vec<String>,
+        timestamp: DateTime<Utc>,
+    },
+    // 文件传输的状态变化，只发送给传输的双方
+    Transfer {
+        id: String,
+        sender: String,
+        recipient: String,
+        name: String,
+        #[serde(flatten)]
+        status: TransferStatus,
+        timestamp: DateTime<Utc>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+enum TransferStatus {
+    Offered,
+    Rejected,
+    // 接受后发给双方，各自使用自己的 token 连接传输端口
+    Ready { addr: String, token: String },
+    Completed { size: u64, checksum: String },
+    Failed { reason: String },
+}
+
+// 客户端输入的一行内容，文本协议下以 / 开头的为命令，其余为聊天消息
+// 管理命令中的 duration 以秒为单位，未指定时为永久
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Command {
+    Join {
+        room: String,
+    },
+    Leave {
+        room: Option<String>,
+    },
+    Rooms,
+    Msg {
+        to: String,
+        content: String,
+    },
+    Nick {
+        username: String,
+    },
+    Login {
+        username: String,
+        password: String,
+    },
+    Register {
+        username: String,
+        password: String,
+    },
+    History {
+        n: Option<usize>,
+    },
+    Kick {
+        username: String,
+    },
+    Ban {
+        target: String,
+        duration: Option<u64>,
+    },
+    Unban {
+        target: String,
+    },
+    Mute {
+        username: String,
+        duration: Option<u64>,
+    },
+    Unmute {
+        username: String,
+    },
+    Who,
+    Names {
+        room: Option<String>,
+    },
+    Ping {
+        token: String,
+    },
+    // path 只用于取文件名，文件内容由客户端在接受后上传
+    Send {
+        to: String,
+        path: String,
+    },
+    Accept {
+        id: String,
+    },
+    Reject {
+        id: String,
+    },
+    Pong,
+    Exit,
+    // 未指定房间时发送到当前房间
+    Chat {
+        room: Option<String>,
+        content: String,
+    },
+    Edit {
+        id: String,
+        content: String,
+    },
+    Delete {
+        id: String,
+    },
+}
+
+// 一行 IRC 消息，最后一个参数可以用 : 开头以包含空格
+#[derive(Debug, PartialEq)]
+struct IrcLine<'a> {
+    command: String,
+    params: Vec<&'a str>,
+}
+
+// 客户端使用的协议，默认为文本协议，连接后发送 /json 切换为 JSON lines 协议
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+enum Protocol {
+    #[default]
+    Text,
+    Json,
+    // IRC 客户端连接到单独的端口，只支持 RFC 1459/2812 的一个子集
+    Irc,
+}
+
+#[derive(Clone, Debug, Parser)]
+#[command(name = "chat", version, author, about, long_about = None)]
+struct Config {
+    // 监听地址
+    #[arg(long, default_value = "0.0.0.0:3090", help = "listen address")]
+    listen_addr: String,
+
+    // WebSocket 监听地址
+    #[arg(
+        long,
+        default_value = "0.0.0.0:3091",
+        help = "websocket listen address"
+    )]
+    ws_addr: String,
+
+    // IRC 监听地址
+    #[arg(long, default_value = "0.0.0.0:6667", help = "IRC listen address")]
+    irc_addr: String,
+
+    // 本服务器的 ID，互联的服务器之间不能重复
+    #[arg(long, default_value_t = nanoid::nanoid!(8), help = "unique id of this server")]
+    server_id: String,
+
+    // 接受其他服务器连接的地址，服务器之间的连接没有认证，只应在可信网络中开放
+    #[arg(long, help = "address to accept links from peer servers")]
+    federation_addr: Option<String>,
+
+    // 启动后主动连接的服务器
+    #[arg(
+        long = "peer",
+        value_name = "ADDR",
+        help = "federation address of a peer server to link with, can be repeated"
+    )]
+    peers: Vec<String>,
+
+    // 启用的插件，按顺序调用
+    #[arg(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        help = "plugins to enable, separated by commas"
+    )]
+    plugins: Vec<PluginKind>,
+
+    // 脏话过滤插件屏蔽的词
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_values = ["damn", "crap", "shit", "fuck"],
+        help = "words masked by the profanity plugin"
+    )]
+    profanity_words: Vec<String>,
+
+    #[arg(long, help = "block messages with profanity instead of masking them")]
+    profanity_drop: bool,
+
+    // Prometheus 指标的监听地址
+    #[arg(
+        long,
+        default_value = "0.0.0.0:3092",
+        help = "listen address of the Prometheus metrics endpoint"
+    )]
+    metrics_addr: String,
+
+    // 每个房间可以修改和删除的最近消息数
+    #[arg(
+        long,
+        default_value_t = 100,
+        help = "recent messages per room that can be edited or deleted"
+    )]
+    edit_window: usize,
+
+    // 保存的历史消息条数
+    #[arg(
+        long,
+        default_value_t = MAX_MESSAGES,
+        help = "number of recent messages kept for replay"
+    )]
+    history_size: usize,
+
+    // 用户消息队列已满时的处理策略
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = SlowConsumerPolicy::DropOldest,
+        help = "what to do when a peer's message queue is full"
+    )]
+    slow_consumer_policy: SlowConsumerPolicy,
+
+    // disconnect 策略下等待队列空位的时间
+    #[arg(
+        long,
+        default_value_t = 5,
+        help = "seconds to wait for a slow peer before disconnecting it"
+    )]
+    slow_consumer_timeout: u64,
+
+    // 单行消息的最大字节数
+    #[arg(
+        long,
+        default_value_t = 1024,
+        help = "maximum length of a single line in bytes"
+    )]
+    max_line_length: usize,
+
+    // 每个连接每秒允许发送的消息数
+    #[arg(
+        long,
+        default_value_t = 5.0,
+        help = "messages per second per connection"
+    )]
+    rate_limit: f64,
+
+    // 令牌桶容量，允许短时间内突发的消息数
+    #[arg(
+        long,
+        default_value_t = 10,
+        help = "burst of messages allowed above the rate limit"
+    )]
+    rate_burst: u32,
+
+    // 刷屏用户的禁言时长
+    #[arg(long, default_value_t = 30, help = "seconds a flooding peer is muted")]
+    flood_mute_duration: u64,
+
+    // 关闭服务时等待连接发送完剩余消息的时间
+    #[arg(
+        long,
+        default_value_t = 5,
+        help = "seconds to wait for connections to flush on shutdown"
+    )]
+    shutdown_timeout: u64,
+
+    // 聊天日志目录，未指定时不保存聊天记录
+    #[arg(long, help = "directory of the persistent chat log")]
+    log_dir: Option<PathBuf>,
+
+    // 单个聊天日志文件的最大字节数
+    #[arg(
+        long,
+        default_value_t = 10 * 1024 * 1024,
+        help = "maximum size of a chat log file in bytes"
+    )]
+    log_max_size: u64,
+
+    // 账号文件，指定后用户需要登录
+    #[arg(long, help = "TOML file of user accounts, enables password login")]
+    users_file: Option<PathBuf>,
+
+    // 是否允许用户通过 /register 注册账号
+    #[arg(long, help = "allow new users to register accounts")]
+    allow_register: bool,
+
+    // 登录失败次数达到上限后断开连接
+    #[arg(
+        long,
+        default_value_t = 3,
+        help = "failed login attempts before disconnecting"
+    )]
+    max_login_attempts: u32,
+
+    // 登录的超时时间
+    #[arg(
+        long,
+        default_value_t = 60,
+        help = "seconds an unauthenticated connection may stay open"
+    )]
+    login_timeout: u64,
+
+    // 管理员和版主，只有启用账号后才能确认用户身份
+    #[arg(
+        long = "admin",
+        value_name = "USERNAME",
+        requires = "users_file",
+        help = "grant the admin role to a user, can be repeated"
+    )]
+    admins: Vec<String>,
+
+    #[arg(
+        long = "moderator",
+        value_name = "USERNAME",
+        requires = "users_file",
+        help = "grant the moderator role to a user, can be repeated"
+    )]
+    moderators: Vec<String>,
+
+    // 封禁列表文件，未指定时封禁只保存在内存中
+    #[arg(long, help = "TOML file the ban list is persisted to")]
+    bans_file: Option<PathBuf>,
+
+    // 文件传输的监听地址
+    #[arg(
+        long,
+        default_value = "0.0.0.0:3093",
+        help = "listen address of the file transfer channel"
+    )]
+    transfer_addr: String,
+
+    // 发给客户端的文件传输地址，监听 0.0.0.0 时需要指定客户端可以访问的地址
+    #[arg(
+        long,
+        help = "address clients connect to for file transfers, defaults to the listen address"
+    )]
+    transfer_public_addr: Option<String>,
+
+    // 单个文件的最大字节数
+    #[arg(
+        long,
+        default_value_t = 100 * 1024 * 1024,
+        help = "maximum size of a transferred file in bytes"
+    )]
+    max_transfer_size: u64,
+
+    // 传输连接等待数据的时间，超时则中止传输
+    #[arg(
+        long,
+        default_value_t = 30,
+        help = "seconds a file transfer may stall before it is aborted"
+    )]
+    transfer_timeout: u64,
+
+    // 连接空闲多久后发送心跳
+    #[arg(
+        long,
+        default_value_t = 60,
+        help = "seconds of silence before the server sends a PING"
+    )]
+    heartbeat_interval: u64,
+
+    // 发送心跳后等待回复的时间，超时则断开连接
+    #[arg(
+        long,
+        default_value_t = 30,
+        help = "seconds to wait for a PONG before dropping the peer"
+    )]
+    heartbeat_timeout: u64,
 }
 
 #[tokio::main]
@@ -37,187 +909,4940 @@ async fn main() -> Result<()> {
     tracing_subscriber::registry().with(layer).init();
     // console_subscriber::init();
 
-    let addr = "0.0.0.0:3090";
+    let config = Config::parse();
+
+    let addr = &config.listen_addr;
     let listener = TcpListener::bind(addr).await?;
     info!("Starting chat server on {}", addr);
 
-    let state = Arc::new(State::default());
-
-    loop {
-        let (stream, addr) = listener.accept().await?;
-        info!("Accepted connection from: {}", addr);
-        let state_cloned = state.clone();
+    let mut state = State::new(config.clone());
 
-        tokio::spawn(async move {
-            if let Err(r) = handle_client(state_cloned, stream, addr).await {
-                warn!("Failed to handle client {}: {}", addr, r);
-            }
-        });
+    // 从聊天日志恢复历史消息，之后的消息继续追加写入
+    if let Some(dir) = &config.log_dir {
+        let records = ChatLog::tail(dir, config.history_size, |record| record.room.is_some())?;
+        info!("Restored {} messages from {}", records.len(), dir.display());
+        state.restore(records);
+        let log = ChatLog::open(dir, config.log_max_size, Utc::now())?;
+        let (tx, writer) = log.spawn();
+        state.log = Mutex::new(Some(tx));
+        state.log_writer = Mutex::new(Some(writer));
     }
-}
+    if let Some(path) = &config.users_file {
+        let accounts = Accounts::load(path)?;
+        info!("Loaded accounts from {}", path.display());
+        state.accounts = Some(accounts);
+    }
+    if let Some(path) = &config.bans_file {
+        state.bans = Bans::load(path)?;
+        info!("Loaded ban list from {}", path.display());
+    }
+    let state = Arc::new(state);
 
-async fn handle_client(state: Arc<State>, stream: TcpStream, addr: SocketAddr) -> Result<()> {
-    let mut stream: Framed<TcpStream, LinesCodec> = Framed::new(stream, LinesCodec::new());
+    // 收到 Ctrl-C 或 SIGTERM 后开始关闭服务
+    let shutdown = state.shutdown.clone();
+    tokio::spawn(async move {
+        match wait_for_signal().await {
+            Ok(()) => info!("Shutdown signal received"),
+            Err(e) => warn!("Failed to listen for shutdown signal: {}", e),
+        }
+        shutdown.cancel();
+    });
 
-    let username = prompt_for_username(&mut stream).await?;
-    let mut peer = state.add(addr, username, stream).await;
+    // WebSocket 网关和 TCP 服务共享同一个 State
+    let ws_listener = TcpListener::bind(&config.ws_addr).await?;
+    info!("Starting websocket gateway on {}", config.ws_addr);
+    let app = Router::new()
+        .route("/ws", get(ws_handler))
+        .with_state(state.clone());
+    let shutdown = state.shutdown.clone();
+    tokio::spawn(async move {
+        let service = app.into_make_service_with_connect_info::<SocketAddr>();
+        let ret = axum::serve(ws_listener, service)
+            .with_graceful_shutdown(shutdown.cancelled_owned())
+            .await;
+        if let Err(e) = ret {
+            warn!("Websocket gateway stopped: {}", e);
+        }
+    });
 
-    // 广播用户加入
-    broadcast_user_joined(&state, &peer.username, addr).await;
+    // 指标和聊天服务使用不同的端口
+    let metrics_listener = TcpListener::bind(&config.metrics_addr).await?;
+    info!("Serving metrics on {}", config.metrics_addr);
+    let app = Router::new()
+        .route("/metrics", get(metrics_handler))
+        .with_state(state.clone());
+    let shutdown = state.shutdown.clone();
+    tokio::spawn(async move {
+        let ret = axum::serve(metrics_listener, app)
+            .with_graceful_shutdown(shutdown.cancelled_owned())
+            .await;
+        if let Err(e) = ret {
+            warn!("Metrics endpoint stopped: {}", e);
+        }
+    });
 
-    // 接收客户端发送的消息
-    while let Some(line) = peer.stream.next().await {
-        let line = match line {
-            Ok(line) => line,
-            Err(e) => {
-                warn!("Failed to read from {}: {}", addr, e);
-                break;
-            }
-        };
+    // IRC 客户端和其他客户端共享房间和消息
+    let irc_listener = TcpListener::bind(&config.irc_addr).await?;
+    info!("Starting IRC gateway on {}", config.irc_addr);
+    tokio::spawn(accept(state.clone(), irc_listener, Protocol::Irc));
 
-        // 用户退出
-        if line.trim() == "exit!" {
-            info!("User {} requested to exit", peer.username);
-            break;
-        }
-        broadcast_chat_message(&state, &peer.username, line, addr).await;
+    // 文件内容通过单独的端口传输，不会阻塞聊天消息
+    let transfer_listener = TcpListener::bind(&config.transfer_addr).await?;
+    info!("Accepting file transfers on {}", config.transfer_addr);
+    if config.transfer_public_addr.is_none()
+        && transfer_listener.local_addr()?.ip().is_unspecified()
+    {
+        warn!(
+            "Clients will be told to connect to {} for file transfers, set --transfer-public-addr",
+            config.transfer_addr
+        );
     }
+    tokio::spawn(accept_transfers(state.clone(), transfer_listener));
 
-    // 用户离开
-    handle_user_exit(&state, addr, peer).await;
+    // 服务器互联，既可以接受其他服务器的连接，也可以主动连接
+    if let Some(addr) = &config.federation_addr {
+        let listener = TcpListener::bind(addr).await?;
+        info!("Accepting peer servers on {} as {}", addr, config.server_id);
+        tokio::spawn(accept_peers(state.clone(), listener));
+    }
+    for peer in &config.peers {
+        tokio::spawn(dial_peer(state.clone(), peer.clone()));
+    }
+
+    serve(state, listener).await
+}
 
+// 接受 TCP 连接直到收到关闭请求，然后关闭所有连接
+async fn serve(state: Arc<State>, listener: TcpListener) -> Result<()> {
+    accept(state.clone(), listener, Protocol::Text).await?;
+    state.close().await;
     Ok(())
 }
 
-// 提示用户输入用户名
-async fn prompt_for_username(stream: &mut Framed<TcpStream, LinesCodec>) -> Result<String> {
-    stream.send("Enter your username:").await?;
+// 接受 TCP 连接直到收到关闭请求，连接使用指定的协议
+async fn accept(state: Arc<State>, listener: TcpListener, protocol: Protocol) -> Result<()> {
+    loop {
+        let (stream, addr) = tokio::select! {
+            ret = listener.accept() => ret?,
+            _ = state.shutdown.cancelled() => break,
+        };
+        // 被封禁的 IP 直接断开
+        if state
+            .bans
+            .find(&BanTarget::Ip(addr.ip()), Utc::now())
+            .is_some()
+        {
+            info!("Rejected connection from banned address {}", addr);
+            continue;
+        }
+        info!("Accepted connection from: {}", addr);
+        let state_cloned = state.clone();
 
-    match stream.next().await {
-        Some(Ok(username)) => Ok(username),
-        Some(Err(e)) => Err(e.into()),
-        None => Err(anyhow::anyhow!("No username provided")),
+        tokio::spawn(async move {
+            let stream = tcp_lines(stream, state_cloned.config.max_line_length);
+            if let Err(r) = handle_client(state_cloned, stream, addr, protocol).await {
+                warn!("Failed to handle client {}: {}", addr, r);
+            }
+        });
     }
+    Ok(())
 }
 
-// 广播用户加入
-async fn broadcast_user_joined(state: &Arc<State>, username: &str, addr: SocketAddr) {
-    let message = Arc::new(Message::user_joined(username));
-    info!("{}", message);
-    state.broadcast(addr, message).await;
-}
+#[cfg(unix)]
+async fn wait_for_signal() -> Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
 
-// 广播聊天消息
-async fn broadcast_chat_message(
-    state: &Arc<State>,
-    username: &str,
-    content: String,
-    addr: SocketAddr,
-) {
-    let message = Arc::new(Message::chat(username, content));
-    state.broadcast(addr, message).await;
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        ret = tokio::signal::ctrl_c() => ret?,
+        _ = terminate.recv() => {}
+    }
+    Ok(())
 }
 
-// 用户离开
-async fn handle_user_exit(state: &Arc<State>, addr: SocketAddr, peer: Peer) {
-    state.peers.remove(&addr);
-    let message = Arc::new(Message::user_left(&peer.username));
-    info!("{}", message);
-    state.broadcast(addr, message).await;
-    drop(peer); // 确保资源释放
+#[cfg(not(unix))]
+async fn wait_for_signal() -> Result<()> {
+    tokio::signal::ctrl_c().await?;
+    Ok(())
 }
 
-impl State {
-    async fn add(
-        &self,
-        addr: SocketAddr,
-        username: String,
-        stream: Framed<TcpStream, LinesCodec>,
-    ) -> Peer {
-        let (tx, mut rx) = mpsc::channel(MAX_MESSAGES);
-
-        self.peers.insert(addr, tx);
-
-        let (mut stream_sender, stream_receiver) = stream.split();
-        // receive messages from others, and send them to the client
+// 升级为 WebSocket 连接，之后和 TCP 客户端走相同的处理流程
+async fn ws_handler(
+    ws: WebSocketUpgrade,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    AxumState(state): AxumState<Arc<State>>,
+) -> Response {
+    if state
+        .bans
+        .find(&BanTarget::Ip(addr.ip()), Utc::now())
+        .is_some()
+    {
+        info!("Rejected websocket connection from banned address {}", addr);
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    info!("Accepted websocket connection from: {}", addr);
+    ws.on_upgrade(move |socket| async move {
+        if let Err(r) = handle_client(state, ws_lines(socket), addr, Protocol::Text).await {
+            warn!("Failed to handle websocket client {}: {}", addr, r);
+        }
+    })
+}
 
+// 接受其他服务器的连接
+async fn accept_peers(state: Arc<State>, listener: TcpListener) -> Result<()> {
+    loop {
+        let (stream, addr) = tokio::select! {
+            ret = listener.accept() => ret?,
+            _ = state.shutdown.cancelled() => return Ok(()),
+        };
+        info!("Accepted peer server connection from {}", addr);
+        let state = state.clone();
         tokio::spawn(async move {
-            while let Some(message) = rx.recv().await {
-                if let Err(e) = stream_sender.send(message.to_string()).await {
-                    warn!("Failed to send message to {}: {}", addr, e);
-                    break;
-                }
+            if let Err(e) = run_link(&state, stream).await {
+                warn!("Link with peer server {} failed: {}", addr, e);
             }
         });
+    }
+}
 
-        Peer {
-            username,
-            stream: stream_receiver,
+// 连接配置的对端服务器，断开后定时重连
+async fn dial_peer(state: Arc<State>, addr: String) {
+    loop {
+        match TcpStream::connect(&addr).await {
+            Ok(stream) => {
+                info!("Connected to peer server {}", addr);
+                if let Err(e) = run_link(&state, stream).await {
+                    warn!("Link with peer server {} failed: {}", addr, e);
+                }
+            }
+            Err(e) => warn!("Failed to connect to peer server {}: {}", addr, e),
+        }
+        tokio::select! {
+            _ = time::sleep(PEER_RETRY_INTERVAL) => {}
+            _ = state.shutdown.cancelled() => return,
         }
     }
+}
 
-    // async fn broadcast1(&self, addr: SocketAddr, message: Arc<Message>) {
-    //     for peer in self.peers.iter() {
-    //         if peer.key() == &addr {
-    //             // 表示当前连接的客户端，不需要广播消息
-    //             continue;
-    //         }
-
-    //         if let Err(e) = peer.value().send(message.clone()).await {
-    //             warn!("Failed to send message to {}: {}", peer.key(), e);
-    //             // 如果发送失败，那么表明该客户端已经断开连接，需要从map中移除
-    //             self.peers.remove(peer.key());
-    //         }
-    //     }
-    // }
+// 交换服务器 ID 后双向转发事件，直到连接断开或服务关闭
+async fn run_link(state: &Arc<State>, stream: TcpStream) -> Result<()> {
+    let addr = stream.peer_addr()?;
+    let mut framed = Framed::new(stream, LengthDelimitedCodec::new());
+    let hello = LinkFrame::Hello {
+        server_id: state.federation.server_id.clone(),
+    };
+    framed
+        .send(Bytes::from(serde_json::to_vec(&hello)?))
+        .await?;
+    let remote = match framed.next().await {
+        Some(frame) => serde_json::from_slice::<LinkFrame>(&frame?)?,
+        None => return Err(anyhow!("peer closed the link before handshake")),
+    };
+    let LinkFrame::Hello { server_id: remote } = remote else {
+        return Err(anyhow!("expected hello from peer"));
+    };
+    if remote == state.federation.server_id {
+        return Err(anyhow!("refusing to link with self"));
+    }
 
-    async fn broadcast(&self, addr: SocketAddr, message: Arc<Message>) {
-        let tasks = self
-            .peers
-            .iter()
-            .filter(|peer| peer.key() != &addr)
-            .map(|peer| {
-                let message_cloned = message.clone();
+    let (sender, mut receiver) = mpsc::channel(MAX_MESSAGES);
+    match state.federation.links.entry(remote.clone()) {
+        Entry::Occupied(_) => return Err(anyhow!("already linked with {}", remote)),
+        Entry::Vacant(entry) => entry.insert(sender),
+    };
+    info!("Linked with peer server {}", remote);
 
-                async move {
-                    if let Err(e) = peer.value().send(message_cloned).await {
-                        warn!("Failed to send message to {}: {}", peer.key(), e);
-                        // 如果发送失败，那么表明该客户端已经断开连接，需要从map中移除
-                        self.peers.remove(peer.key());
+    let ret = async {
+        loop {
+            tokio::select! {
+                event = receiver.recv() => {
+                    let Some(event) = event else {
+                        break;
+                    };
+                    let frame = serde_json::to_vec(&LinkFrame::Event(event))?;
+                    framed.send(Bytes::from(frame)).await?;
+                }
+                frame = framed.next() => {
+                    let Some(frame) = frame else {
+                        break;
+                    };
+                    match serde_json::from_slice::<LinkFrame>(&frame?)? {
+                        LinkFrame::Event(event) => {
+                            state.receive_remote(event, &remote, addr).await
+                        }
+                        LinkFrame::Hello { .. } => warn!("Unexpected hello from {}", remote),
                     }
                 }
-            })
-            .collect::<Vec<_>>();
-
-        futures::future::join_all(tasks).await;
-    }
-}
-
-impl Message {
-    fn user_joined(username: &str) -> Self {
-        let content = format!("{} has joined the chat", username);
-        Self::UserJoined(content)
+                _ = state.shutdown.cancelled() => break,
+            }
+        }
+        Ok::<_, anyhow::Error>(())
     }
+    .await;
 
-    fn user_left(username: &str) -> Self {
-        let content = format!("{} has left the chat", username);
-        Self::UserLeft(content)
-    }
+    state.federation.links.remove(&remote);
+    info!("Link with peer server {} closed", remote);
+    ret
+}
 
-    fn chat(sender: impl Into<String>, content: impl Into<String>) -> Self {
-        Self::Chat {
-            sender: sender.into(),
-            content: content.into(),
+// 接受文件传输连接，连接后先发送 token 表明身份
+async fn accept_transfers(state: Arc<State>, listener: TcpListener) -> Result<()> {
+    loop {
+        let (stream, addr) = tokio::select! {
+            ret = listener.accept() => ret?,
+            _ = state.shutdown.cancelled() => return Ok(()),
+        };
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_transfer(&state, stream).await {
+                warn!("File transfer with {} failed: {}", addr, e);
+            }
+        });
+    }
+}
+
+async fn handle_transfer(state: &Arc<State>, stream: TcpStream) -> Result<()> {
+    // 数据帧比数据块多一个类型字节
+    let codec = LengthDelimitedCodec::builder()
+        .max_frame_length(TRANSFER_CHUNK_SIZE + 1)
+        .new_codec();
+    let mut framed = Framed::new(stream, codec);
+    let timeout = Duration::from_secs(state.config.transfer_timeout);
+    let token = match time::timeout(timeout, framed.next()).await {
+        Ok(Some(frame)) => match TransferFrame::decode(frame?)? {
+            TransferFrame::Hello(token) => token,
+            _ => return Err(anyhow!("expected hello")),
+        },
+        Ok(None) => return Err(anyhow!("closed before hello")),
+        Err(_) => return Err(anyhow!("timed out waiting for hello")),
+    };
+    let Some((transfer, role)) = state.transfers.claim(&token) else {
+        let frame = TransferFrame::Error("Invalid transfer token".to_string());
+        framed.send(frame.encode()).await?;
+        return Err(anyhow!("invalid transfer token"));
+    };
+    match role {
+        TransferRole::Upload => upload_file(state, &transfer, framed).await,
+        TransferRole::Download => download_file(state, &transfer, framed).await,
+    }
+}
+
+// 上传端发送完整个文件后收到结束帧作为确认，失败时收到错误帧
+async fn upload_file(
+    state: &Arc<State>,
+    transfer: &Transfer,
+    mut framed: TransferStream,
+) -> Result<()> {
+    let Some(sender) = transfer.upload.lock().unwrap().take() else {
+        return Err(anyhow!("transfer {} already started", transfer.id));
+    };
+    match relay_upload(state, &mut framed, &sender).await {
+        Ok(hash) => framed.send(TransferFrame::End(hash).encode()).await?,
+        Err(e) => {
+            let reason = e.to_string();
+            // 下载端可能还没有连接，队列已满时丢弃错误帧，下载端会发现队列已关闭
+            let _ = sender.try_send(TransferFrame::Error(reason.clone()));
+            let status = TransferStatus::Failed {
+                reason: reason.clone(),
+            };
+            state.finish_transfer(transfer, status).await;
+            framed.send(TransferFrame::Error(reason).encode()).await?;
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+// 读取上传的数据块并转发给下载端，同时检查文件大小并计算校验和
+async fn relay_upload(
+    state: &State,
+    framed: &mut TransferStream,
+    sender: &mpsc::Sender<TransferFrame>,
+) -> Result<blake3::Hash> {
+    let timeout = Duration::from_secs(state.config.transfer_timeout);
+    let mut hasher = blake3::Hasher::new();
+    let mut size = 0;
+    loop {
+        let frame = match time::timeout(timeout, framed.next()).await {
+            Ok(Some(frame)) => TransferFrame::decode(frame?)?,
+            Ok(None) => return Err(anyhow!("Sender disconnected before the end of the file")),
+            Err(_) => return Err(anyhow!("Upload stalled")),
+        };
+        let end = match &frame {
+            TransferFrame::Data(chunk) => {
+                size += chunk.len() as u64;
+                if size > state.config.max_transfer_size {
+                    return Err(anyhow!(
+                        "File is larger than {} bytes",
+                        state.config.max_transfer_size
+                    ));
+                }
+                hasher.update(chunk);
+                None
+            }
+            TransferFrame::End(hash) if *hash == hasher.finalize() => Some(*hash),
+            TransferFrame::End(_) => return Err(anyhow!("Checksum mismatch")),
+            _ => return Err(anyhow!("Unexpected frame from sender")),
+        };
+        // 下载端读取较慢时在这里等待，只影响这一个传输
+        match time::timeout(timeout, sender.send(frame)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(_)) => return Err(anyhow!("Recipient disconnected")),
+            Err(_) => return Err(anyhow!("Recipient is not downloading")),
+        }
+        if let Some(hash) = end {
+            return Ok(hash);
         }
     }
 }
 
-impl fmt::Display for Message {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            Self::UserJoined(content) => write!(f, "[{}]", content),
-            Self::UserLeft(content) => write!(f, "[{} :(]", content),
-            Self::Chat { sender, content } => write!(f, "{}: {}", sender, content),
+// 把上传端转发的帧原样发给下载端，发送完结束帧后传输完成
+async fn download_file(
+    state: &Arc<State>,
+    transfer: &Transfer,
+    mut framed: TransferStream,
+) -> Result<()> {
+    let Some(mut receiver) = transfer.download.lock().unwrap().take() else {
+        return Err(anyhow!("transfer {} already started", transfer.id));
+    };
+    // 上传端一直没有连接或者停止发送时，等待超时后中止
+    let timeout = Duration::from_secs(state.config.transfer_timeout);
+    let mut size = 0;
+    let reason = loop {
+        let frame = match time::timeout(timeout, receiver.recv()).await {
+            Ok(Some(frame)) => frame,
+            Ok(None) => break "Sender disconnected before the end of the file",
+            Err(_) => break "Upload stalled",
+        };
+        let status = match &frame {
+            TransferFrame::Data(chunk) => {
+                size += chunk.len() as u64;
+                None
+            }
+            TransferFrame::End(hash) => Some(TransferStatus::Completed {
+                size,
+                checksum: hash.to_hex().to_string(),
+            }),
+            TransferFrame::Error(reason) => Some(TransferStatus::Failed {
+                reason: reason.clone(),
+            }),
+            TransferFrame::Hello(_) => None,
+        };
+        if let Err(e) = framed.send(frame.encode()).await {
+            let status = TransferStatus::Failed {
+                reason: "Recipient disconnected".to_string(),
+            };
+            state.finish_transfer(transfer, status).await;
+            return Err(e.into());
+        }
+        if let Some(status) = status {
+            state.finish_transfer(transfer, status).await;
+            return Ok(());
+        }
+    };
+    let status = TransferStatus::Failed {
+        reason: reason.to_string(),
+    };
+    state.finish_transfer(transfer, status).await;
+    framed
+        .send(TransferFrame::Error(reason.to_string()).encode())
+        .await?;
+    Ok(())
+}
+
+async fn metrics_handler(AxumState(state): AxumState<Arc<State>>) -> impl IntoResponse {
+    let body = state.metrics.render(state.peers.len());
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], body)
+}
+
+// TCP 连接按换行符分隔消息，限制单行长度避免超长的行耗尽内存
+fn tcp_lines(stream: TcpStream, max_length: usize) -> Lines {
+    let codec = BoundedLinesCodec(LinesCodec::new_with_max_length(max_length));
+    let framed = Framed::new(stream, codec).map(|line| Ok(line??));
+    Box::new(SinkExt::<String>::sink_map_err(framed, anyhow::Error::from))
+}
+
+// WebSocket 连接中每个文本帧为一行，忽略 ping/pong 等控制帧
+fn ws_lines(socket: WebSocket) -> Lines {
+    let lines = socket
+        .map_err(anyhow::Error::from)
+        .sink_map_err(anyhow::Error::from)
+        .with(|line: String| future::ready(Ok::<_, anyhow::Error>(WsMessage::Text(line))))
+        .try_filter_map(|message| match message {
+            WsMessage::Text(text) => future::ready(Ok(Some(text))),
+            _ => future::ready(Ok(None)),
+        });
+    Box::new(lines)
+}
+
+async fn handle_client(
+    state: Arc<State>,
+    mut stream: Lines,
+    addr: SocketAddr,
+    mut protocol: Protocol,
+) -> Result<()> {
+    state.metrics.connections.fetch_add(1, Ordering::Relaxed);
+
+    // 登录过程中服务关闭则直接断开，超时未登录也会断开
+    // 登录时可能切换到 JSON 协议，超时的提示按切换后的协议发送
+    let login_timeout = Duration::from_secs(state.config.login_timeout);
+    let login = async {
+        match protocol {
+            Protocol::Irc => irc_register(&state, &mut stream, addr).await,
+            _ => prompt_for_username(&state, &mut stream, addr, &mut protocol).await,
+        }
+    };
+    let username = tokio::select! {
+        ret = time::timeout(login_timeout, login) => {
+            match ret {
+                Ok(ret) => ret?,
+                Err(_) => {
+                    let message = Message::error("Login timed out");
+                    send_prompt(&mut stream, protocol, message).await?;
+                    return Err(anyhow!("login timed out"));
+                }
+            }
+        }
+        _ = state.closing.cancelled() => return Ok(()),
+    };
+    let mut peer = state.add(addr, username, protocol, stream).await;
+
+    // 回放默认房间的历史消息
+    replay_history(&state, addr, &[DEFAULT_ROOM.to_string()], None).await;
+
+    // 加入默认房间并广播用户加入
+    join_room(&state, &mut peer, addr, DEFAULT_ROOM.to_string()).await;
+
+    let mut flood_guard = FloodGuard::new(&state.config);
+
+    // 连接空闲时发送心跳，心跳超时未回复说明连接已失效
+    let heartbeat_interval = Duration::from_secs(state.config.heartbeat_interval);
+    let heartbeat_timeout = Duration::from_secs(state.config.heartbeat_timeout);
+    let mut idle_deadline = Instant::now() + heartbeat_interval;
+    let mut pinged = false;
+
+    // 接收客户端发送的消息，连接被服务端断开时退出
+    loop {
+        let line = tokio::select! {
+            line = peer.stream.next() => line,
+            _ = time::sleep_until(idle_deadline) => {
+                if pinged {
+                    warn!("{} did not reply to ping, disconnecting", peer.username);
+                    peer.outbox.close();
+                    break;
+                }
+                state.send_to(addr, Arc::new(Message::ping())).await;
+                pinged = true;
+                idle_deadline = Instant::now() + heartbeat_timeout;
+                continue;
+            }
+            _ = state.closing.cancelled() => break,
+            _ = peer.outbox.closed() => {
+                info!("Connection to {} closed by server", addr);
+                break;
+            }
+            _ = peer.outbox.kicked() => break,
+        };
+        let Some(line) = line else {
+            break;
+        };
+        // 收到任何数据都说明连接仍然有效
+        let now = Instant::now();
+        idle_deadline = now + heartbeat_interval;
+        let awaiting_pong = std::mem::take(&mut pinged);
+
+        // 超长的行和发送过快都视为刷屏
+        let verdict = match &line {
+            Ok(line) if line.len() > state.config.max_line_length => flood_guard.violation(now),
+            Ok(_) => flood_guard.check(now),
+            Err(e) if is_line_too_long(e) => flood_guard.violation(now),
+            Err(e) => {
+                warn!("Failed to read from {}: {}", addr, e);
+                break;
+            }
+        };
+        match verdict {
+            FloodVerdict::Allow => {}
+            FloodVerdict::Warn => {
+                reply_error(&state, addr, "You are sending messages too fast, slow down").await;
+                continue;
+            }
+            FloodVerdict::Mute(duration) => {
+                warn!("Muting {} for flooding", peer.username);
+                let content = format!(
+                    "You have been muted for {}s for flooding",
+                    duration.as_secs()
+                );
+                reply_error(&state, addr, content).await;
+                continue;
+            }
+            FloodVerdict::Muted => continue,
+            FloodVerdict::Disconnect => {
+                warn!("Disconnecting {} for flooding", peer.username);
+                reply_error(&state, addr, "You have been disconnected for flooding").await;
+                break;
+            }
+        }
+        let Ok(line) = line else {
+            continue;
+        };
+
+        match peer.protocol.decode(&line) {
+            // 用户退出
+            Ok(Command::Exit) => {
+                info!("User {} requested to exit", peer.username);
+                break;
+            }
+            // 心跳回复不算作用户活跃
+            Ok(Command::Pong) if awaiting_pong || peer.protocol != Protocol::Text => {}
+            // 没有等待心跳回复时，文本协议中的 PONG 只是一条聊天消息
+            Ok(Command::Pong) => {
+                state.touch(addr, Utc::now());
+                let command = Command::Chat {
+                    room: None,
+                    content: line,
+                };
+                handle_command(&state, &mut peer, addr, command).await
+            }
+            Ok(command) => {
+                state.touch(addr, Utc::now());
+                handle_command(&state, &mut peer, addr, command).await
+            }
+            Err(e) => reply_error(&state, addr, e.to_string()).await,
+        }
+    }
+
+    // 用户离开
+    handle_user_exit(&state, addr, peer).await;
+
+    Ok(())
+}
+
+fn is_line_too_long(e: &anyhow::Error) -> bool {
+    matches!(
+        e.downcast_ref::<LinesCodecError>(),
+        Some(LinesCodecError::MaxLineLengthExceeded)
+    )
+}
+
+// 处理用户输入的命令
+async fn handle_command(state: &Arc<State>, peer: &mut Peer, addr: SocketAddr, command: Command) {
+    match command {
+        Command::Join { room } => join_room(state, peer, addr, room).await,
+        Command::Leave { room } => leave_room(state, peer, addr, room).await,
+        Command::Rooms => {
+            let rooms = state
+                .rooms()
+                .into_iter()
+                .map(|(room, count)| format!("#{} ({})", room, count))
+                .collect::<Vec<_>>();
+            let content = if rooms.is_empty() {
+                "No rooms yet, use /join <room> to create one".to_string()
+            } else {
+                format!("Rooms: {}", rooms.join(", "))
+            };
+            reply(state, addr, content).await;
+        }
+        Command::Msg { .. } | Command::Chat { .. } | Command::Edit { .. }
+            if state.is_muted(&peer.username, Utc::now()) =>
+        {
+            reply_error(state, addr, "You are muted").await
+        }
+        Command::Msg { to, content } => send_direct_message(state, peer, addr, to, content).await,
+        Command::Nick { username } => change_nick(state, peer, addr, username).await,
+        Command::History { n } => {
+            let rooms = state.rooms_of(addr);
+            if rooms.is_empty() {
+                reply_error(state, addr, "You are not in any room, use /join <room>").await;
+            } else {
+                replay_history(state, addr, &rooms, n).await;
+            }
+        }
+        Command::Chat { room, content } => match room.or_else(|| peer.room.clone()) {
+            Some(room) if state.in_room(&room, addr) => {
+                // IRC 客户端不支持修改和删除，不需要告知消息 ID
+                let notify = peer.protocol != Protocol::Irc;
+                broadcast_chat_message(state, &room, &peer.username, content, addr, notify).await
+            }
+            Some(room) => reply_error(state, addr, format!("You are not in #{}", room)).await,
+            None => reply_error(state, addr, "You are not in any room, use /join <room>").await,
+        },
+        Command::Names { room } => match room.or_else(|| peer.room.clone()) {
+            Some(room) => send_names(state, addr, room).await,
+            None => reply_error(state, addr, "You are not in any room, use /join <room>").await,
+        },
+        Command::Ping { token } => {
+            state.send_to(addr, Arc::new(Message::pong(token))).await;
+        }
+        Command::Who => {
+            let now = Utc::now();
+            let users = state
+                .who()
+                .into_iter()
+                .map(|(username, presence)| {
+                    let connected = (now - presence.connected_at).num_seconds();
+                    let idle = (now - presence.last_active).num_seconds();
+                    format!(
+                        "{} (connected {}, idle {})",
+                        username,
+                        format_elapsed(connected),
+                        format_elapsed(idle)
+                    )
+                })
+                .collect::<Vec<_>>();
+            reply(state, addr, format!("Online users: {}", users.join(", "))).await;
+        }
+        Command::Edit { id, content } => {
+            correct_message(state, peer, addr, id, Some(content)).await
+        }
+        Command::Delete { id } => correct_message(state, peer, addr, id, None).await,
+        Command::Send { to, path } => offer_file(state, peer, addr, to, path).await,
+        Command::Accept { id } => accept_file(state, peer, addr, id).await,
+        Command::Reject { id } => reject_file(state, peer, addr, id).await,
+        Command::Login { .. } | Command::Register { .. } => {
+            reply_error(state, addr, "You are already logged in").await
+        }
+        Command::Kick { .. }
+        | Command::Ban { .. }
+        | Command::Unban { .. }
+        | Command::Mute { .. }
+        | Command::Unmute { .. } => moderate(state, peer, addr, command).await,
+        // 读循环已经处理，不会到达这里
+        Command::Pong | Command::Exit => {
+            warn!("Unexpected command from {}: {:?}", addr, command);
+            reply_error(state, addr, "Unexpected command").await
+        }
+    }
+}
+
+// 执行管理命令，检查权限后广播处理结果
+async fn moderate(state: &Arc<State>, peer: &Peer, addr: SocketAddr, command: Command) {
+    let role = state.role(&peer.username);
+    if role < command.required_role() {
+        reply_error(state, addr, "Permission denied").await;
+        return;
+    }
+    // 只能处理角色低于自己的用户，对象是 IP 时检查该 IP 上所有在线的用户
+    if let Some(target) = command.moderation_target() {
+        let usernames = match target.parse::<IpAddr>() {
+            Ok(ip) => state.usernames_on(ip),
+            Err(_) => vec![target.to_string()],
+        };
+        if usernames
+            .iter()
+            .any(|username| *username == peer.username || state.role(username) >= role)
+        {
+            reply_error(state, addr, format!("You cannot moderate {}", target)).await;
+            return;
+        }
+    }
+
+    let by = &peer.username;
+    let now = Utc::now();
+    let content = match command {
+        Command::Kick { username } => {
+            let reason = format!("You have been kicked by {}", by);
+            if !state.kick_user(&username, &reason).await {
+                reply_error(state, addr, format!("User {} is not online", username)).await;
+                return;
+            }
+            format!("{} has been kicked by {}", username, by)
+        }
+        Command::Ban { target, duration } => {
+            let target = match parse_ban_target(&target) {
+                Ok(target) => target,
+                Err(e) => return reply_error(state, addr, e.to_string()).await,
+            };
+            let until = duration.map(|secs| now + Duration::from_secs(secs));
+            let ban = Ban {
+                target: target.clone(),
+                by: by.clone(),
+                until,
+            };
+            if let Err(e) = state.bans.ban(ban, now) {
+                warn!("Failed to save ban list: {}", e);
+                reply_error(state, addr, "Failed to save the ban list").await;
+                return;
+            }
+            let reason = format!("You have been banned by {}", by);
+            match &target {
+                BanTarget::User(username) => state.kick_user(username, &reason).await,
+                BanTarget::Ip(ip) => state.kick_ip(*ip, &reason).await,
+            };
+            format!(
+                "{} has been banned by {}{}",
+                target,
+                by,
+                for_duration(duration)
+            )
+        }
+        Command::Unban { target } => {
+            let target = match parse_ban_target(&target) {
+                Ok(target) => target,
+                Err(e) => return reply_error(state, addr, e.to_string()).await,
+            };
+            match state.bans.unban(&target, now) {
+                Ok(true) => format!("{} has been unbanned by {}", target, by),
+                Ok(false) => {
+                    return reply_error(state, addr, format!("{} is not banned", target)).await
+                }
+                Err(e) => {
+                    warn!("Failed to save ban list: {}", e);
+                    return reply_error(state, addr, "Failed to save the ban list").await;
+                }
+            }
+        }
+        Command::Mute { username, duration } => {
+            if !state.user_exists(&username) {
+                reply_error(state, addr, format!("User {} does not exist", username)).await;
+                return;
+            }
+            let until = duration.map(|secs| now + Duration::from_secs(secs));
+            state.mutes.insert(username.clone(), until);
+            format!(
+                "{} has been muted by {}{}",
+                username,
+                by,
+                for_duration(duration)
+            )
+        }
+        Command::Unmute { username } => {
+            if state.mutes.remove(&username).is_none() {
+                reply_error(state, addr, format!("{} is not muted", username)).await;
+                return;
+            }
+            format!("{} has been unmuted by {}", username, by)
+        }
+        // handle_command 只会传入管理命令
+        command => {
+            warn!("Unexpected moderation command from {}: {:?}", addr, command);
+            return reply_error(state, addr, "Unexpected command").await;
+        }
+    };
+
+    info!("{}", content);
+    state
+        .broadcast_all(Arc::new(Message::system(content)))
+        .await;
+}
+
+// 封禁对象可以是 IP 地址或用户名
+fn parse_ban_target(target: &str) -> Result<BanTarget> {
+    match target.parse::<IpAddr>() {
+        Ok(ip) => Ok(BanTarget::Ip(ip)),
+        Err(_) => Ok(BanTarget::User(parse_username(target)?)),
+    }
+}
+
+// 解析时长，支持 s、m、h、d 后缀，没有后缀时为秒
+fn parse_duration(duration: &str) -> Result<u64> {
+    let (value, unit) = match duration.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => (&duration[..i], c),
+        _ => (duration, 's'),
+    };
+    let unit = match unit {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        _ => return Err(anyhow!("Invalid duration: {}", duration)),
+    };
+    match value.parse::<u64>() {
+        Ok(value) if value > 0 => Ok(value * unit),
+        _ => Err(anyhow!("Invalid duration: {}", duration)),
+    }
+}
+
+// 经过的时间，只显示最大的两个单位
+fn format_elapsed(secs: i64) -> String {
+    let secs = secs.max(0);
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m{}s", secs / 60, secs % 60),
+        _ => format!("{}h{}m", secs / 3600, secs % 3600 / 60),
+    }
+}
+
+fn for_duration(duration: Option<u64>) -> String {
+    match duration {
+        Some(secs) if secs % (24 * 60 * 60) == 0 => format!(" for {}d", secs / (24 * 60 * 60)),
+        Some(secs) if secs % (60 * 60) == 0 => format!(" for {}h", secs / (60 * 60)),
+        Some(secs) if secs % 60 == 0 => format!(" for {}m", secs / 60),
+        Some(secs) => format!(" for {}s", secs),
+        None => String::new(),
+    }
+}
+
+// 提示用户输入用户名，用户名不合法或已被占用时重新提示
+// 文本协议下直接输入用户名，输入 /json 切换为 JSON 协议后需要发送 nick 命令
+async fn prompt_for_username(
+    state: &Arc<State>,
+    stream: &mut Lines,
+    addr: SocketAddr,
+    protocol: &mut Protocol,
+) -> Result<String> {
+    let mut failures = 0;
+    loop {
+        send_prompt(stream, *protocol, Message::system("Enter your username:")).await?;
+        let line = next_line(stream).await?;
+
+        if *protocol == Protocol::Text && line.trim() == "/json" {
+            *protocol = Protocol::Json;
+            continue;
+        }
+
+        // 文本协议下直接输入用户名，也可以使用 /login 或 /register 命令
+        let command = match *protocol {
+            Protocol::Text if line.trim_start().starts_with('/') => Command::parse(&line),
+            Protocol::Text => parse_username(&line).map(|username| Command::Nick { username }),
+            Protocol::Json | Protocol::Irc => protocol.decode(&line),
+        };
+        let username = match command {
+            Ok(command) => authenticate(state, stream, *protocol, command).await?,
+            Err(e) => Err(e),
+        };
+
+        // 校验通过后立即占用用户名，避免并发登录时重名
+        let message = match username {
+            Ok(username) if state.is_banned(&username) => {
+                info!("Rejected login from banned user {}", username);
+                send_prompt(stream, *protocol, Message::error("You are banned")).await?;
+                return Err(anyhow!("user {} is banned", username));
+            }
+            Ok(username) if state.reserve_username(&username, addr) => return Ok(username),
+            Ok(username) => Message::error(format!("Username {} is already taken", username)),
+            Err(e) if e.is::<InvalidCredentials>() => {
+                failures += 1;
+                if failures >= state.config.max_login_attempts {
+                    let message = Message::error("Too many failed login attempts");
+                    send_prompt(stream, *protocol, message).await?;
+                    return Err(anyhow!("too many failed login attempts"));
+                }
+                Message::error(e.to_string())
+            }
+            Err(e) => Message::error(e.to_string()),
+        };
+        send_prompt(stream, *protocol, message).await?;
+    }
+}
+
+// 校验用户身份，未启用账号时只需要用户名
+// 外层的错误表示连接出错，内层的错误会发送给用户后重新提示
+async fn authenticate(
+    state: &Arc<State>,
+    stream: &mut Lines,
+    protocol: Protocol,
+    command: Command,
+) -> Result<Result<String>> {
+    let Some(accounts) = &state.accounts else {
+        return Ok(match command {
+            Command::Nick { username } | Command::Login { username, .. } => Ok(username),
+            Command::Register { .. } => Err(anyhow!("Registration is disabled")),
+            _ => Err(anyhow!("Please log in first")),
+        });
+    };
+
+    let ret = match command {
+        // 文本协议下输入用户名后再提示输入密码
+        Command::Nick { username } if protocol == Protocol::Text => {
+            send_prompt(stream, protocol, Message::system("Enter your password:")).await?;
+            let password = next_line(stream).await?;
+            accounts.verify(&username, &password).map(|_| username)
+        }
+        Command::Nick { .. } => Err(anyhow!("Password required, use the login command")),
+        Command::Login { username, password } => {
+            accounts.verify(&username, &password).map(|_| username)
+        }
+        Command::Register { username, password } if state.config.allow_register => {
+            accounts.register(&username, &password).map(|_| {
+                info!("Registered account {}", username);
+                username
+            })
+        }
+        Command::Register { .. } => Err(anyhow!("Registration is disabled")),
+        _ => Err(anyhow!("Please log in first")),
+    };
+    Ok(ret)
+}
+
+// IRC 客户端通过 NICK 和 USER 完成注册，启用账号时用 PASS 发送密码
+async fn irc_register(state: &Arc<State>, stream: &mut Lines, addr: SocketAddr) -> Result<String> {
+    let mut password = None;
+    let mut nick = None;
+    let mut user = false;
+    loop {
+        let line = next_line(stream).await?;
+        let Some(irc) = IrcLine::parse(&line) else {
+            continue;
+        };
+        match (irc.command.as_str(), irc.params.as_slice()) {
+            ("PASS", [pass, ..]) => password = Some(pass.to_string()),
+            ("NICK", [name, ..]) => match parse_username(name) {
+                Ok(name) => nick = Some(name),
+                Err(e) => {
+                    let reply = irc_numeric("432", "*", format!("{} :{}", name, e));
+                    stream.send(reply).await?;
+                }
+            },
+            ("USER", [_, ..]) => user = true,
+            ("PING", [token, ..]) => {
+                stream
+                    .send(Protocol::Irc.encode(&Message::pong(*token)))
+                    .await?
+            }
+            ("QUIT", _) => return Err(anyhow!("client quit during registration")),
+            // 不支持 IRCv3 的扩展能力协商，客户端会在收到 001 后继续
+            ("CAP", _) => {}
+            ("PASS" | "NICK" | "USER" | "PING", []) => {
+                let reply = irc_numeric(
+                    "461",
+                    "*",
+                    format!("{} :Not enough parameters", irc.command),
+                );
+                stream.send(reply).await?;
+            }
+            _ => {
+                stream
+                    .send(irc_numeric("451", "*", ":You have not registered"))
+                    .await?
+            }
+        }
+
+        // NICK 和 USER 都收到后才完成注册，注册失败需要重新发送 NICK
+        if !user {
+            continue;
+        }
+        let Some(username) = nick.take() else {
+            continue;
+        };
+        let command = match password.take() {
+            Some(password) => Command::Login { username, password },
+            None => Command::Nick { username },
+        };
+        let message = match authenticate(state, stream, Protocol::Irc, command).await? {
+            Ok(username) if state.is_banned(&username) => {
+                info!("Rejected login from banned user {}", username);
+                stream
+                    .send(irc_numeric(
+                        "465",
+                        &username,
+                        ":You are banned from this server",
+                    ))
+                    .await?;
+                return Err(anyhow!("user {} is banned", username));
+            }
+            Ok(username) if state.reserve_username(&username, addr) => {
+                let welcome = format!(
+                    ":Welcome to the chat server {}!{}@{}",
+                    username, username, IRC_SERVER_NAME
+                );
+                stream.send(irc_numeric("001", &username, welcome)).await?;
+                stream
+                    .send(irc_numeric("422", &username, ":MOTD File is missing"))
+                    .await?;
+                return Ok(username);
+            }
+            Ok(username) => irc_numeric(
+                "433",
+                "*",
+                format!("{} :Nickname is already in use", username),
+            ),
+            // IRC 客户端不会重试密码，直接断开
+            Err(e) if e.is::<InvalidCredentials>() => {
+                stream
+                    .send(irc_numeric("464", "*", ":Password incorrect"))
+                    .await?;
+                return Err(e);
+            }
+            Err(e) => Protocol::Irc.encode(&Message::error(e.to_string())),
+        };
+        stream.send(message).await?;
+    }
+}
+
+fn irc_numeric(code: &str, target: &str, params: impl fmt::Display) -> String {
+    format!(":{} {} {} {}", IRC_SERVER_NAME, code, target, params)
+}
+
+async fn next_line(stream: &mut Lines) -> Result<String> {
+    match stream.next().await {
+        Some(Ok(line)) => Ok(line),
+        Some(Err(e)) => Err(e),
+        None => Err(anyhow!("Connection closed during login")),
+    }
+}
+
+// 登录阶段直接写入连接，文本协议下保持不带前缀的提示
+async fn send_prompt(stream: &mut Lines, protocol: Protocol, message: Message) -> Result<()> {
+    let line = match (protocol, &message) {
+        (Protocol::Text, Message::System { content, .. })
+        | (Protocol::Text, Message::Error { content, .. }) => content.clone(),
+        _ => protocol.encode(&message),
+    };
+    stream.send(line).await?;
+    Ok(())
+}
+
+// 修改用户名，并通知所有在线用户
+async fn change_nick(state: &Arc<State>, peer: &mut Peer, addr: SocketAddr, new: String) {
+    if new == peer.username {
+        reply(state, addr, format!("You are already known as {}", new)).await;
+        return;
+    }
+    // 启用账号后用户名即身份，不允许改名
+    if state.accounts.is_some() {
+        reply_error(
+            state,
+            addr,
+            "Renaming is disabled when accounts are enabled",
+        )
+        .await;
+        return;
+    }
+    if !state.rename(&peer.username, &new, addr) {
+        reply_error(state, addr, format!("Username {} is already taken", new)).await;
+        return;
+    }
+
+    let old = std::mem::replace(&mut peer.username, new.clone());
+    let message = Arc::new(Message::user_renamed(old, new));
+    info!("{}", message);
+    state.persist(addr, &message);
+    state.broadcast_all(message).await;
+}
+
+// 加入房间，并切换为当前房间
+async fn join_room(state: &Arc<State>, peer: &mut Peer, addr: SocketAddr, room: String) {
+    let joined = state.join(&room, addr);
+    if joined {
+        broadcast_user_joined(state, &room, &peer.username, addr).await;
+    }
+    // IRC 客户端收到自己的 JOIN 和成员列表后才会打开频道
+    if peer.protocol == Protocol::Irc {
+        if joined {
+            let message = Arc::new(Message::user_joined(&room, &peer.username));
+            state.send_to(addr, message).await;
+            send_names(state, addr, room.clone()).await;
+        }
+    } else {
+        reply(state, addr, format!("You are now chatting in #{}", room)).await;
+    }
+    peer.room = Some(room);
+}
+
+// 离开房间，未指定房间时离开当前房间
+async fn leave_room(state: &Arc<State>, peer: &mut Peer, addr: SocketAddr, room: Option<String>) {
+    let Some(room) = room.or_else(|| peer.room.clone()) else {
+        reply_error(state, addr, "You are not in any room").await;
+        return;
+    };
+
+    if !state.leave(&room, addr) {
+        reply_error(state, addr, format!("You are not in #{}", room)).await;
+        return;
+    }
+    broadcast_user_left(state, &room, &peer.username, addr).await;
+
+    // 离开的是当前房间，则切换到仍然加入的其他房间
+    if peer.room.as_deref() == Some(room.as_str()) {
+        peer.room = state.rooms_of(addr).into_iter().next();
+    }
+    // IRC 客户端收到自己的 PART 后关闭频道
+    if peer.protocol == Protocol::Irc {
+        let message = Arc::new(Message::user_left(&room, &peer.username));
+        state.send_to(addr, message).await;
+        return;
+    }
+    let content = match &peer.room {
+        Some(current) => format!("You left #{}, now chatting in #{}", room, current),
+        None => format!("You left #{}", room),
+    };
+    reply(state, addr, content).await;
+}
+
+// 发送私聊消息，发送者也会收到一份作为回显
+async fn send_direct_message(
+    state: &Arc<State>,
+    peer: &Peer,
+    addr: SocketAddr,
+    to: String,
+    content: String,
+) {
+    let Some(target) = state.users.get(&to).map(|user| *user.value()) else {
+        reply_error(state, addr, format!("User {} is not online", to)).await;
+        return;
+    };
+
+    let message = Arc::new(Message::direct(&peer.username, &to, content));
+    state.persist(addr, &message);
+    if !state.send_to(target, message.clone()).await {
+        reply_error(
+            state,
+            addr,
+            format!("User {} is offline, message not delivered", to),
+        )
+        .await;
+        return;
+    }
+    if target != addr {
+        state.send_to(addr, message).await;
+    }
+}
+
+// 向在线用户发起文件传输，这里只需要文件名，接受后客户端再上传文件内容
+async fn offer_file(state: &Arc<State>, peer: &Peer, addr: SocketAddr, to: String, path: String) {
+    if to == peer.username {
+        reply_error(state, addr, "You cannot send a file to yourself").await;
+        return;
+    }
+    let Some(target) = state.users.get(&to).map(|user| *user.value()) else {
+        reply_error(state, addr, format!("User {} is not online", to)).await;
+        return;
+    };
+    let Some(name) = Path::new(&path).file_name() else {
+        reply_error(state, addr, format!("Invalid file path: {}", path)).await;
+        return;
+    };
+
+    let name = name.to_string_lossy().into_owned();
+    let transfer = state.transfers.offer(&peer.username, &to, name);
+    info!(
+        "{} offered {} to {} as transfer {}",
+        peer.username, transfer.name, to, transfer.id
+    );
+    let message = Arc::new(Message::transfer(&transfer, TransferStatus::Offered));
+    state.send_to(target, message.clone()).await;
+    state.send_to(addr, message).await;
+}
+
+// 接受文件传输，双方分别收到上传和下载使用的 token
+async fn accept_file(state: &Arc<State>, peer: &Peer, addr: SocketAddr, id: String) {
+    let (transfer, upload, download) = match state.transfers.accept(&id, &peer.username) {
+        Ok(accepted) => accepted,
+        Err(e) => return reply_error(state, addr, e.to_string()).await,
+    };
+    let config = &state.config;
+    let public_addr = config.transfer_public_addr.as_ref();
+    let public_addr = public_addr.unwrap_or(&config.transfer_addr);
+    let ready = |token| TransferStatus::Ready {
+        addr: public_addr.clone(),
+        token,
+    };
+    let message = Message::transfer(&transfer, ready(upload));
+    state
+        .send_to_user(&transfer.sender, Arc::new(message))
+        .await;
+    let message = Message::transfer(&transfer, ready(download));
+    state.send_to(addr, Arc::new(message)).await;
+    expire_transfer(state, transfer);
+}
+
+// 超时后仍有一端没有连接时中止传输，释放 token 和已经缓冲的数据
+fn expire_transfer(state: &Arc<State>, transfer: Arc<Transfer>) {
+    let state = state.clone();
+    tokio::spawn(async move {
+        let timeout = Duration::from_secs(state.config.transfer_timeout);
+        tokio::select! {
+            _ = time::sleep(timeout) => {}
+            _ = state.shutdown.cancelled() => return,
+        }
+        if state.transfers.unclaimed(&transfer.id) {
+            let status = TransferStatus::Failed {
+                reason: "Transfer was not started in time".to_string(),
+            };
+            state.finish_transfer(&transfer, status).await;
+        }
+    });
+}
+
+async fn reject_file(state: &Arc<State>, peer: &Peer, addr: SocketAddr, id: String) {
+    let transfer = match state.transfers.reject(&id, &peer.username) {
+        Ok(transfer) => transfer,
+        Err(e) => return reply_error(state, addr, e.to_string()).await,
+    };
+    let message = Arc::new(Message::transfer(&transfer, TransferStatus::Rejected));
+    state.send_to_user(&transfer.sender, message.clone()).await;
+    state.send_to(addr, message).await;
+}
+
+// 向用户回放指定房间最近的 n 条历史消息，未指定 n 时回放全部
+async fn replay_history(state: &Arc<State>, addr: SocketAddr, rooms: &[String], n: Option<usize>) {
+    for message in state.history(rooms, n) {
+        state
+            .send_to(addr, Arc::new(Message::History { message }))
+            .await;
+    }
+}
+
+// 向用户发送房间的成员列表
+async fn send_names(state: &Arc<State>, addr: SocketAddr, room: String) {
+    let users = state.names(&room);
+    state
+        .send_to(addr, Arc::new(Message::names(room, users)))
+        .await;
+}
+
+// 向用户自己发送提示信息
+async fn reply(state: &Arc<State>, addr: SocketAddr, content: impl Into<String>) {
+    let message = Arc::new(Message::system(content));
+    state.send_to(addr, message).await;
+}
+
+// 向用户自己发送错误信息
+async fn reply_error(state: &Arc<State>, addr: SocketAddr, content: impl Into<String>) {
+    let message = Arc::new(Message::error(content));
+    state.send_to(addr, message).await;
+}
+
+// 广播用户加入
+async fn broadcast_user_joined(state: &Arc<State>, room: &str, username: &str, addr: SocketAddr) {
+    let message = Arc::new(Message::user_joined(room, username));
+    info!("{}", message);
+    state.broadcast(room, addr, message).await;
+    let outcome = state.run_plugins(PluginHook::Join, room, username, "");
+    deliver_plugin_messages(state, room, addr, outcome).await;
+}
+
+// 广播用户离开
+async fn broadcast_user_left(state: &Arc<State>, room: &str, username: &str, addr: SocketAddr) {
+    let message = Arc::new(Message::user_left(room, username));
+    info!("{}", message);
+    state.broadcast(room, addr, message).await;
+    let outcome = state.run_plugins(PluginHook::Leave, room, username, "");
+    deliver_plugin_messages(state, room, addr, outcome).await;
+}
+
+// 经过插件处理后广播聊天消息
+// notify 为 true 时告知发送者消息的 ID，用于之后修改或删除
+async fn broadcast_chat_message(
+    state: &Arc<State>,
+    room: &str,
+    username: &str,
+    content: String,
+    addr: SocketAddr,
+    notify: bool,
+) {
+    let mut outcome = state.run_plugins(PluginHook::Chat, room, username, &content);
+    if !outcome.dropped {
+        let content = std::mem::take(&mut outcome.content);
+        let message = state.chat_message(room, username, content);
+        let id = message.id().map(str::to_string);
+        if let Some(id) = &id {
+            state.remember(room, id, username);
         }
+        state.broadcast(room, addr, Arc::new(message)).await;
+        if let Some(id) = id.filter(|_| notify) {
+            state.send_to(addr, Arc::new(Message::sent(id, room))).await;
+        }
+    }
+    deliver_plugin_messages(state, room, addr, outcome).await;
+}
+
+// 修改或删除自己最近发送的消息，content 为 None 时删除
+async fn correct_message(
+    state: &Arc<State>,
+    peer: &Peer,
+    addr: SocketAddr,
+    id: String,
+    content: Option<String>,
+) {
+    let id = state.qualify_id(id);
+    let Some(room) = state.find_recent(&id, &peer.username) else {
+        let content = format!("No recent message {} of yours to change", id);
+        return reply_error(state, addr, content).await;
+    };
+    if !state.in_room(&room, addr) {
+        return reply_error(state, addr, format!("You are not in #{}", room)).await;
+    }
+
+    let message = match content {
+        // 修改后的内容同样经过插件过滤，但不会触发机器人发言
+        Some(content) => {
+            let outcome = state.run_plugins(PluginHook::Chat, &room, &peer.username, &content);
+            if outcome.dropped {
+                for message in outcome.replies {
+                    state.send_to(addr, Arc::new(message)).await;
+                }
+                return;
+            }
+            Message::edited(id, &room, &peer.username, outcome.content)
+        }
+        None => {
+            state.forget_recent(&room, &id);
+            Message::deleted(id, &room, &peer.username)
+        }
+    };
+    let message = Arc::new(message);
+    state.broadcast(&room, addr, message.clone()).await;
+    state.send_to(addr, message).await;
+}
+
+// 发送插件产生的回复和房间消息
+async fn deliver_plugin_messages(
+    state: &Arc<State>,
+    room: &str,
+    addr: SocketAddr,
+    outcome: PluginOutcome,
+) {
+    for message in outcome.replies {
+        state.send_to(addr, Arc::new(message)).await;
+    }
+    for message in outcome.emits {
+        let message = state.number(message);
+        state.emit(room, Arc::new(message)).await;
+    }
+}
+
+// 用户离开
+async fn handle_user_exit(state: &Arc<State>, addr: SocketAddr, peer: Peer) {
+    state.peers.remove(&addr);
+    state.presence.remove(&addr);
+    state
+        .users
+        .remove_if(&peer.username, |_, user| *user == addr);
+    let rooms = state.leave_all(addr);
+    // 服务关闭时所有用户都会离开，不再逐个通知
+    if !state.closing.is_cancelled() {
+        for room in rooms {
+            broadcast_user_left(state, &room, &peer.username, addr).await;
+        }
+    }
+    state.transfers.forget(&peer.username);
+    // 发送完剩余消息后结束写任务
+    peer.outbox.finish();
+    drop(peer); // 确保资源释放
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self::parse_from(["chat"])
+    }
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self::new(Config::default())
+    }
+}
+
+impl State {
+    fn new(config: Config) -> Self {
+        Self {
+            history: Mutex::new(VecDeque::with_capacity(config.history_size)),
+            federation: Federation::new(config.server_id.clone()),
+            metrics: Arc::new(Metrics::default()),
+            plugins: config
+                .plugins
+                .iter()
+                .map(|kind| kind.build(&config))
+                .collect(),
+            config,
+            peers: DashMap::new(),
+            rooms: DashMap::new(),
+            users: DashMap::new(),
+            shutdown: CancellationToken::new(),
+            closing: CancellationToken::new(),
+            writers: TaskTracker::new(),
+            log: Mutex::new(None),
+            log_writer: Mutex::new(None),
+            accounts: None,
+            bans: Bans::default(),
+            mutes: DashMap::new(),
+            presence: DashMap::new(),
+            transfers: Transfers::default(),
+            message_id: AtomicU64::new(1),
+            recent: DashMap::new(),
+        }
+    }
+
+    async fn add(
+        &self,
+        addr: SocketAddr,
+        username: String,
+        protocol: Protocol,
+        stream: Lines,
+    ) -> Peer {
+        let outbox = Arc::new(Outbox::new(MAX_MESSAGES));
+
+        self.peers.insert(addr, outbox.clone());
+        let now = Utc::now();
+        let presence = Presence {
+            connected_at: now,
+            last_active: now,
+        };
+        self.presence.insert(addr, presence);
+
+        let (mut stream_sender, stream_receiver) = stream.split();
+        // receive messages from others, and send them to the client
+
+        let writer = outbox.clone();
+        let metrics = self.metrics.clone();
+        self.writers.spawn(async move {
+            while let Some(message) = writer.pop().await {
+                let line = protocol.encode(&message);
+                let len = line.len() as u64 + 1;
+                // 半开的连接可能一直阻塞在发送上，关闭时直接放弃
+                let ret = tokio::select! {
+                    ret = stream_sender.send(line) => ret,
+                    _ = writer.closed() => break,
+                };
+                if let Err(e) = ret {
+                    warn!("Failed to send message to {}: {}", addr, e);
+                    metrics.write_errors.fetch_add(1, Ordering::Relaxed);
+                    break;
+                }
+                metrics.bytes_sent.fetch_add(len, Ordering::Relaxed);
+            }
+            writer.close();
+        });
+
+        Peer {
+            username,
+            room: None,
+            protocol,
+            outbox,
+            stream: stream_receiver,
+        }
+    }
+
+    // 占用用户名，已被其他用户占用时返回 false
+    fn reserve_username(&self, username: &str, addr: SocketAddr) -> bool {
+        match self.users.entry(username.to_string()) {
+            Entry::Occupied(_) => false,
+            Entry::Vacant(entry) => {
+                entry.insert(addr);
+                true
+            }
+        }
+    }
+
+    // 先占用新用户名再释放旧用户名，保证改名过程中用户名不会被他人抢占
+    fn rename(&self, old: &str, new: &str, addr: SocketAddr) -> bool {
+        if !self.reserve_username(new, addr) {
+            return false;
+        }
+        self.users.remove_if(old, |_, user| *user == addr);
+        true
+    }
+
+    // 加入房间，房间不存在时创建；已在房间中返回 false
+    fn join(&self, room: &str, addr: SocketAddr) -> bool {
+        self.rooms.entry(room.to_string()).or_default().insert(addr)
+    }
+
+    // 离开房间，不在房间中返回 false
+    fn leave(&self, room: &str, addr: SocketAddr) -> bool {
+        let removed = match self.rooms.get_mut(room) {
+            Some(mut members) => members.remove(&addr),
+            None => false,
+        };
+        // 房间删除后其中的消息不能再修改
+        if self
+            .rooms
+            .remove_if(room, |_, members| members.is_empty())
+            .is_some()
+        {
+            self.recent.remove(room);
+        }
+        removed
+    }
+
+    // 离开所有房间，返回离开的房间列表
+    fn leave_all(&self, addr: SocketAddr) -> Vec<String> {
+        let rooms = self.rooms_of(addr);
+        rooms.iter().for_each(|room| {
+            self.leave(room, addr);
+        });
+        rooms
+    }
+
+    fn in_room(&self, room: &str, addr: SocketAddr) -> bool {
+        self.rooms
+            .get(room)
+            .is_some_and(|members| members.contains(&addr))
+    }
+
+    // 房间内的用户名，按名称排序
+    fn names(&self, room: &str) -> Vec<String> {
+        let Some(members) = self.rooms.get(room).map(|members| members.clone()) else {
+            return Vec::new();
+        };
+        let mut users = self
+            .users
+            .iter()
+            .filter(|user| members.contains(user.value()))
+            .map(|user| user.key().clone())
+            .collect::<Vec<_>>();
+        users.sort();
+        users
+    }
+
+    // 用户加入的所有房间，按名称排序
+    fn rooms_of(&self, addr: SocketAddr) -> Vec<String> {
+        let mut rooms = self
+            .rooms
+            .iter()
+            .filter(|room| room.value().contains(&addr))
+            .map(|room| room.key().clone())
+            .collect::<Vec<_>>();
+        rooms.sort();
+        rooms
+    }
+
+    // 所有房间及其成员数，按名称排序
+    fn rooms(&self) -> Vec<(String, usize)> {
+        let mut rooms = self
+            .rooms
+            .iter()
+            .map(|room| (room.key().clone(), room.value().len()))
+            .collect::<Vec<_>>();
+        rooms.sort();
+        rooms
+    }
+
+    // 向单个用户发送消息，用户不存在或已断开时返回 false
+    async fn send_to(&self, addr: SocketAddr, message: Arc<Message>) -> bool {
+        // 先克隆 outbox，避免跨 await 持有 DashMap 的锁
+        let Some(outbox) = self.peers.get(&addr).map(|peer| peer.value().clone()) else {
+            return false;
+        };
+        if outbox.is_closed() {
+            self.peers.remove(&addr);
+            return false;
+        }
+
+        let delivered = match self.config.slow_consumer_policy {
+            SlowConsumerPolicy::DropOldest => outbox.push_drop_oldest(message),
+            SlowConsumerPolicy::DropNew => outbox.try_push(message).is_ok(),
+            SlowConsumerPolicy::Disconnect => {
+                let timeout = Duration::from_secs(self.config.slow_consumer_timeout);
+                outbox.push_timeout(message, timeout).await
+            }
+        };
+        if delivered {
+            return true;
+        }
+
+        let dropped = outbox.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+        match self.config.slow_consumer_policy {
+            SlowConsumerPolicy::Disconnect => {
+                warn!("Disconnecting slow consumer {} ({} dropped)", addr, dropped);
+                self.metrics.disconnected.fetch_add(1, Ordering::Relaxed);
+                outbox.close();
+                self.peers.remove(&addr);
+                false
+            }
+            // 丢弃最早的消息时新消息仍然会被发送
+            SlowConsumerPolicy::DropOldest => {
+                self.metrics.dropped_oldest.fetch_add(1, Ordering::Relaxed);
+                warn!(
+                    "Slow consumer {}: dropped oldest message ({} dropped)",
+                    addr, dropped
+                );
+                true
+            }
+            SlowConsumerPolicy::DropNew => {
+                self.metrics.dropped_new.fetch_add(1, Ordering::Relaxed);
+                warn!(
+                    "Slow consumer {}: dropped new message ({} dropped)",
+                    addr, dropped
+                );
+                false
+            }
+        }
+    }
+
+    // 记录房间消息，超出容量时丢弃最早的消息
+    // 删除消息时只保留删除的记录，原消息和修改过的内容都不再回放
+    fn record(&self, message: Arc<Message>) {
+        let history_size = self.config.history_size;
+        if history_size == 0 {
+            return;
+        }
+        let mut history = self.history.lock().unwrap();
+        if let Message::Deleted { id, .. } = &*message {
+            history.retain(|recorded| recorded.id() != Some(id));
+        }
+        if history.len() == history_size {
+            history.pop_front();
+        }
+        history.push_back(message);
+    }
+
+    // 从聊天日志恢复历史消息，新消息的 ID 从恢复的最大 ID 之后开始
+    fn restore(&self, records: Vec<LogRecord>) {
+        for record in records {
+            if let Message::Chat { id, .. } = &*record.message {
+                if let Some(n) = self.local_id(id) {
+                    self.message_id.fetch_max(n + 1, Ordering::Relaxed);
+                }
+            }
+            if record.room.is_some() {
+                self.record(record.message);
+            }
+        }
+    }
+
+    fn chat_message(
+        &self,
+        room: impl Into<String>,
+        sender: impl Into<String>,
+        content: impl Into<String>,
+    ) -> Message {
+        self.number(Message::chat(room, sender, content))
+    }
+
+    // 为本服务器产生的聊天消息分配 ID，其他消息原样返回
+    fn number(&self, mut message: Message) -> Message {
+        if let Message::Chat { id, .. } = &mut message {
+            let n = self.message_id.fetch_add(1, Ordering::Relaxed);
+            *id = format!("{}:{}", self.config.server_id, n);
+        }
+        message
+    }
+
+    // 本服务器分配的 ID 中的序号
+    fn local_id(&self, id: &str) -> Option<u64> {
+        id.strip_prefix(self.config.server_id.as_str())?
+            .strip_prefix(':')?
+            .parse()
+            .ok()
+    }
+
+    // 用户可以省略本服务器的 ID，只输入序号
+    fn qualify_id(&self, id: String) -> String {
+        if id.contains(':') {
+            return id;
+        }
+        format!("{}:{}", self.config.server_id, id)
+    }
+
+    // 记录可以修改的消息，超过 edit_window 条时丢弃最早的
+    fn remember(&self, room: &str, id: &str, sender: &str) {
+        let window = self.config.edit_window;
+        if window == 0 {
+            return;
+        }
+        let mut recent = self.recent.entry(room.to_string()).or_default();
+        if recent.len() == window {
+            recent.pop_front();
+        }
+        recent.push_back((id.to_string(), sender.to_string()));
+    }
+
+    // 查找用户最近发送的消息所在的房间
+    fn find_recent(&self, id: &str, sender: &str) -> Option<String> {
+        self.recent
+            .iter()
+            .find(|recent| recent.iter().any(|(i, s)| i == id && s == sender))
+            .map(|recent| recent.key().clone())
+    }
+
+    fn forget_recent(&self, room: &str, id: &str) {
+        if let Some(mut recent) = self.recent.get_mut(room) {
+            recent.retain(|(i, _)| i != id);
+        }
+    }
+
+    // 写入聊天日志，队列已满时丢弃该记录，避免磁盘变慢拖慢广播
+    fn persist(&self, addr: SocketAddr, message: &Arc<Message>) {
+        let log = self.log.lock().unwrap();
+        let Some(log) = log.as_ref() else {
+            return;
+        };
+        let record = LogRecord {
+            timestamp: message.timestamp(),
+            sender: addr,
+            room: message.room().map(String::from),
+            message: message.clone(),
+        };
+        if let Err(e) = log.try_send(record) {
+            warn!("Failed to write chat log: {}", e);
+        }
+    }
+
+    // 指定房间最近的 n 条历史消息，按时间先后排序
+    fn history(&self, rooms: &[String], n: Option<usize>) -> Vec<Arc<Message>> {
+        let history = self.history.lock().unwrap();
+        let mut messages = history
+            .iter()
+            .rev()
+            .filter(|message| {
+                message
+                    .room()
+                    .is_some_and(|room| rooms.iter().any(|r| r == room))
+            })
+            .take(n.unwrap_or(self.config.history_size))
+            .cloned()
+            .collect::<Vec<_>>();
+        messages.reverse();
+        messages
+    }
+
+    // 用户的角色，命令行指定的角色优先于账号文件中的角色
+    fn role(&self, username: &str) -> Role {
+        if self.config.admins.iter().any(|admin| admin == username) {
+            return Role::Admin;
+        }
+        if self
+            .config
+            .moderators
+            .iter()
+            .any(|moderator| moderator == username)
+        {
+            return Role::Moderator;
+        }
+        self.accounts
+            .as_ref()
+            .map(|accounts| accounts.role(username))
+            .unwrap_or_default()
+    }
+
+    // 用户在线或者有注册的账号
+    fn user_exists(&self, username: &str) -> bool {
+        self.users.contains_key(username)
+            || self
+                .accounts
+                .as_ref()
+                .is_some_and(|accounts| accounts.exists(username))
+    }
+
+    // 记录用户最后活跃的时间
+    fn touch(&self, addr: SocketAddr, now: DateTime<Utc>) {
+        if let Some(mut presence) = self.presence.get_mut(&addr) {
+            presence.last_active = now;
+        }
+    }
+
+    // 在线用户及其连接信息，按用户名排序
+    fn who(&self) -> Vec<(String, Presence)> {
+        let mut users = self
+            .users
+            .iter()
+            .filter_map(|user| {
+                let presence = *self.presence.get(user.value())?;
+                Some((user.key().clone(), presence))
+            })
+            .collect::<Vec<_>>();
+        users.sort_by(|a, b| a.0.cmp(&b.0));
+        users
+    }
+
+    fn is_banned(&self, username: &str) -> bool {
+        let target = BanTarget::User(username.to_string());
+        self.bans.find(&target, Utc::now()).is_some()
+    }
+
+    // 禁言到期后自动解除
+    fn is_muted(&self, username: &str, now: DateTime<Utc>) -> bool {
+        self.mutes
+            .remove_if(username, |_, until| until.is_some_and(|until| until <= now));
+        self.mutes.contains_key(username)
+    }
+
+    // 按用户名发送消息，用户不在线时返回 false
+    async fn send_to_user(&self, username: &str, message: Arc<Message>) -> bool {
+        let Some(addr) = self.users.get(username).map(|user| *user.value()) else {
+            return false;
+        };
+        self.send_to(addr, message).await
+    }
+
+    // 通知传输双方最终结果，上传端和下载端都可能调用，只通知一次
+    async fn finish_transfer(&self, transfer: &Transfer, status: TransferStatus) {
+        if transfer.finished.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        self.transfers.finish(transfer);
+        let message = Arc::new(Message::transfer(transfer, status));
+        info!("{}", message);
+        self.send_to_user(&transfer.sender, message.clone()).await;
+        self.send_to_user(&transfer.recipient, message).await;
+    }
+
+    // 通知用户后断开连接，用户不在线时返回 false
+    async fn kick_user(&self, username: &str, reason: &str) -> bool {
+        let Some(addr) = self.users.get(username).map(|user| *user.value()) else {
+            return false;
+        };
+        self.kick(addr, reason).await
+    }
+
+    // 从该 IP 登录的在线用户
+    fn usernames_on(&self, ip: IpAddr) -> Vec<String> {
+        self.users
+            .iter()
+            .filter(|user| user.value().ip() == ip)
+            .map(|user| user.key().clone())
+            .collect()
+    }
+
+    // 断开来自该 IP 的所有连接
+    async fn kick_ip(&self, ip: IpAddr, reason: &str) -> bool {
+        let peers = self
+            .peers
+            .iter()
+            .map(|peer| *peer.key())
+            .filter(|addr| addr.ip() == ip)
+            .collect::<Vec<_>>();
+        let mut kicked = false;
+        for addr in peers {
+            kicked |= self.kick(addr, reason).await;
+        }
+        kicked
+    }
+
+    async fn kick(&self, addr: SocketAddr, reason: &str) -> bool {
+        let Some(outbox) = self.peers.get(&addr).map(|peer| peer.value().clone()) else {
+            return false;
+        };
+        self.send_to(addr, Arc::new(Message::error(reason))).await;
+        outbox.kick();
+        true
+    }
+
+    // 通知所有用户服务即将关闭，断开所有连接，并等待写任务发送完剩余消息
+    async fn close(&self) {
+        info!("Shutting down chat server");
+        let message = Arc::new(Message::system("Server is shutting down"));
+        self.broadcast_all(message).await;
+        self.closing.cancel();
+
+        self.writers.close();
+        let timeout = Duration::from_secs(self.config.shutdown_timeout);
+        if time::timeout(timeout, self.writers.wait()).await.is_err() {
+            warn!(
+                "Timed out waiting for {} connections to flush",
+                self.writers.len()
+            );
+        }
+
+        // 关闭写入队列，等待已经排队的日志写入文件
+        self.log.lock().unwrap().take();
+        let writer = self.log_writer.lock().unwrap().take();
+        if let Some(writer) = writer {
+            if let Err(e) = writer.await {
+                warn!("Chat log writer failed: {}", e);
+            }
+        }
+    }
+
+    // 向所有在线用户广播消息
+    async fn broadcast_all(&self, message: Arc<Message>) {
+        let start = Instant::now();
+        let peers = self
+            .peers
+            .iter()
+            .map(|peer| *peer.key())
+            .collect::<Vec<_>>();
+        let tasks = peers
+            .into_iter()
+            .map(|peer| self.send_to(peer, message.clone()))
+            .collect::<Vec<_>>();
+
+        futures::future::join_all(tasks).await;
+        self.metrics.observe_broadcast(start.elapsed());
+    }
+
+    // 只向房间内除发送者以外的成员广播消息，同时转发给互联的服务器
+    async fn broadcast(&self, room: &str, addr: SocketAddr, message: Arc<Message>) {
+        self.record(message.clone());
+        self.persist(addr, &message);
+        self.federation.publish(message.clone());
+        self.fan_out(room, Some(addr), message).await;
+    }
+
+    // 服务端产生的房间消息，发送给房间内所有成员
+    async fn emit(&self, room: &str, message: Arc<Message>) {
+        self.record(message.clone());
+        self.federation.publish(message.clone());
+        self.fan_out(room, None, message).await;
+    }
+
+    // 依次调用插件，消息被丢弃后不再调用之后的插件
+    fn run_plugins(
+        &self,
+        hook: PluginHook,
+        room: &str,
+        username: &str,
+        content: &str,
+    ) -> PluginOutcome {
+        let mut outcome = PluginOutcome {
+            content: content.to_string(),
+            ..Default::default()
+        };
+        for plugin in &self.plugins {
+            let actions = match hook {
+                PluginHook::Join => plugin.on_join(room, username),
+                PluginHook::Leave => plugin.on_leave(room, username),
+                PluginHook::Chat => plugin.on_chat(room, username, &outcome.content),
+            };
+            for action in actions {
+                match action {
+                    PluginAction::Drop => outcome.dropped = true,
+                    PluginAction::Rewrite(content) => outcome.content = content,
+                    PluginAction::Reply(message) => outcome.replies.push(message),
+                    PluginAction::Emit(message) => outcome.emits.push(message),
+                }
+            }
+            if outcome.dropped {
+                info!(
+                    "Plugin {} dropped a message from {}",
+                    plugin.name(),
+                    username
+                );
+                break;
+            }
+        }
+        outcome
+    }
+
+    // 处理其他服务器转发的事件，只接受房间内的消息
+    // 和本地消息一样写入聊天日志，日志中的发送地址为互联连接的地址
+    async fn receive_remote(&self, event: FederationEvent, from: &str, addr: SocketAddr) {
+        let Some(room) = event.message.room().map(str::to_string) else {
+            warn!("Dropping non-room event {} from {}", event.id, from);
+            return;
+        };
+        if !self.federation.receive(&event, from) {
+            return;
+        }
+        self.record(event.message.clone());
+        self.persist(addr, &event.message);
+        self.fan_out(&room, None, event.message).await;
+    }
+
+    async fn fan_out(&self, room: &str, except: Option<SocketAddr>, message: Arc<Message>) {
+        let start = Instant::now();
+        let members = match self.rooms.get(room) {
+            Some(members) => members
+                .iter()
+                .filter(|member| Some(**member) != except)
+                .copied()
+                .collect::<Vec<_>>(),
+            None => return,
+        };
+
+        let tasks = members
+            .into_iter()
+            .map(|member| self.send_to(member, message.clone()))
+            .collect::<Vec<_>>();
+
+        futures::future::join_all(tasks).await;
+        self.metrics.observe_broadcast(start.elapsed());
+    }
+}
+
+impl PluginKind {
+    fn build(&self, config: &Config) -> Box<dyn ChatPlugin> {
+        match self {
+            Self::Profanity => Box::new(ProfanityFilter::new(
+                &config.profanity_words,
+                config.profanity_drop,
+            )),
+            Self::Echo => Box::new(EchoBot),
+            Self::Time => Box::new(TimeBot),
+        }
+    }
+}
+
+impl ProfanityFilter {
+    fn new(words: &[String], drop: bool) -> Self {
+        Self {
+            words: words.iter().map(|word| word.to_lowercase()).collect(),
+            drop,
+        }
+    }
+
+    // 按单词匹配，忽略大小写和单词前后的标点
+    fn mask(&self, content: &str) -> String {
+        content
+            .split(' ')
+            .map(|token| {
+                let word = token.trim_matches(|c: char| !c.is_alphanumeric());
+                if word.is_empty() || !self.words.contains(&word.to_lowercase()) {
+                    return token.to_string();
+                }
+                token.replace(word, &"*".repeat(word.chars().count()))
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+impl ChatPlugin for ProfanityFilter {
+    fn name(&self) -> &'static str {
+        "profanity"
+    }
+
+    fn on_chat(&self, _room: &str, _username: &str, content: &str) -> Vec<PluginAction> {
+        let masked = self.mask(content);
+        if masked == content {
+            return Vec::new();
+        }
+        if self.drop {
+            let reply = Message::error("Your message was blocked by the profanity filter");
+            return vec![PluginAction::Drop, PluginAction::Reply(reply)];
+        }
+        vec![PluginAction::Rewrite(masked)]
+    }
+}
+
+impl ChatPlugin for EchoBot {
+    fn name(&self) -> &'static str {
+        "echo"
+    }
+
+    fn on_chat(&self, room: &str, _username: &str, content: &str) -> Vec<PluginAction> {
+        match content.trim().strip_prefix("!echo") {
+            Some(text) if text.starts_with(' ') && !text.trim().is_empty() => {
+                vec![PluginAction::Emit(Message::chat(
+                    room,
+                    BOT_NAME,
+                    text.trim(),
+                ))]
+            }
+            Some("") => vec![PluginAction::Reply(Message::error("Usage: !echo <text>"))],
+            _ => Vec::new(),
+        }
+    }
+}
+
+impl ChatPlugin for TimeBot {
+    fn name(&self) -> &'static str {
+        "time"
+    }
+
+    fn on_chat(&self, room: &str, _username: &str, content: &str) -> Vec<PluginAction> {
+        if content.trim() != "!time" {
+            return Vec::new();
+        }
+        let now = Utc::now().format("%Y-%m-%d %H:%M:%S UTC");
+        let message = Message::chat(room, BOT_NAME, format!("The time is {}", now));
+        vec![PluginAction::Emit(message)]
+    }
+}
+
+impl Metrics {
+    fn observe_broadcast(&self, elapsed: Duration) {
+        self.broadcasts.fetch_add(1, Ordering::Relaxed);
+        self.fan_out.observe(elapsed);
+    }
+
+    // 按 Prometheus 文本格式输出所有指标
+    fn render(&self, peers: usize) -> String {
+        let counter = |metric: &AtomicU64| metric.load(Ordering::Relaxed);
+        let mut out = String::new();
+        let mut write = |name: &str, kind: &str, help: &str, samples: &[(&str, u64)]| {
+            out.push_str(&format!(
+                "# HELP {} {}\n# TYPE {} {}\n",
+                name, help, name, kind
+            ));
+            for (labels, value) in samples {
+                out.push_str(&format!("{}{} {}\n", name, labels, value));
+            }
+        };
+        write(
+            "chat_connected_peers",
+            "gauge",
+            "Number of logged in peers.",
+            &[("", peers as u64)],
+        );
+        write(
+            "chat_connections_total",
+            "counter",
+            "Number of accepted client connections.",
+            &[("", counter(&self.connections))],
+        );
+        write(
+            "chat_messages_broadcast_total",
+            "counter",
+            "Number of messages broadcast to rooms or all peers.",
+            &[("", counter(&self.broadcasts))],
+        );
+        write(
+            "chat_bytes_sent_total",
+            "counter",
+            "Bytes written to client connections.",
+            &[("", counter(&self.bytes_sent))],
+        );
+        write(
+            "chat_send_failures_total",
+            "counter",
+            "Messages that could not be delivered, by reason.",
+            &[
+                ("{reason=\"write_error\"}", counter(&self.write_errors)),
+                ("{reason=\"dropped_oldest\"}", counter(&self.dropped_oldest)),
+                ("{reason=\"dropped_new\"}", counter(&self.dropped_new)),
+                ("{reason=\"disconnected\"}", counter(&self.disconnected)),
+            ],
+        );
+        self.fan_out.render(
+            &mut out,
+            "chat_broadcast_fan_out_seconds",
+            "Time taken to queue a broadcast for every recipient.",
+        );
+        out
+    }
+}
+
+impl Histogram {
+    fn observe(&self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        if let Some(i) = FAN_OUT_BUCKETS.iter().position(|bound| secs <= *bound) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum
+            .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        out.push_str(&format!(
+            "# HELP {} {}\n# TYPE {} histogram\n",
+            name, help, name
+        ));
+        let mut cumulative = 0;
+        for (bound, bucket) in FAN_OUT_BUCKETS.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            out.push_str(&format!(
+                "{}_bucket{{le=\"{}\"}} {}\n",
+                name, bound, cumulative
+            ));
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let sum = self.sum.load(Ordering::Relaxed) as f64 / 1e9;
+        out.push_str(&format!("{}_bucket{{le=\"+Inf\"}} {}\n", name, count));
+        out.push_str(&format!("{}_sum {}\n{}_count {}\n", name, sum, name, count));
+    }
+}
+
+impl Federation {
+    fn new(server_id: String) -> Self {
+        Self {
+            server_id,
+            links: DashMap::new(),
+            seen: Mutex::new(SeenEvents::default()),
+        }
+    }
+
+    // 为本地产生的消息分配 ID，发送给所有互联的服务器
+    fn publish(&self, message: Arc<Message>) {
+        let event = FederationEvent {
+            id: nanoid::nanoid!(),
+            origin: self.server_id.clone(),
+            message,
+        };
+        self.seen.lock().unwrap().insert(&event.id);
+        self.forward(&event, None);
+    }
+
+    // 新事件转发给来源以外的服务器，已处理过的事件返回 false
+    fn receive(&self, event: &FederationEvent, from: &str) -> bool {
+        if event.origin == self.server_id || !self.seen.lock().unwrap().insert(&event.id) {
+            return false;
+        }
+        self.forward(event, Some(from));
+        true
+    }
+
+    // 对端处理不过来时丢弃事件，避免阻塞本地广播
+    fn forward(&self, event: &FederationEvent, except: Option<&str>) {
+        for link in self.links.iter() {
+            if Some(link.key().as_str()) == except {
+                continue;
+            }
+            if link.value().try_send(event.clone()).is_err() {
+                warn!("Dropping event {} for peer server {}", event.id, link.key());
+            }
+        }
+    }
+}
+
+impl SeenEvents {
+    // 已经存在时返回 false
+    fn insert(&mut self, id: &str) -> bool {
+        if !self.ids.insert(id.to_string()) {
+            return false;
+        }
+        self.order.push_back(id.to_string());
+        if self.order.len() > MAX_SEEN_EVENTS {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+        true
+    }
+}
+
+impl Transfers {
+    fn offer(&self, sender: &str, recipient: &str, name: String) -> Arc<Transfer> {
+        let transfer = Arc::new(Transfer {
+            id: nanoid::nanoid!(8),
+            sender: sender.to_string(),
+            recipient: recipient.to_string(),
+            name,
+            finished: AtomicBool::new(false),
+            upload: Mutex::new(None),
+            download: Mutex::new(None),
+        });
+        self.offers.insert(transfer.id.clone(), transfer.clone());
+        transfer
+    }
+
+    // 只有接收者可以接受，返回上传和下载使用的 token
+    fn accept(&self, id: &str, recipient: &str) -> Result<(Arc<Transfer>, String, String)> {
+        let transfer = self.take(id, recipient)?;
+        let (sender, receiver) = mpsc::channel(TRANSFER_BUFFER);
+        *transfer.upload.lock().unwrap() = Some(sender);
+        *transfer.download.lock().unwrap() = Some(receiver);
+
+        let upload = nanoid::nanoid!();
+        let download = nanoid::nanoid!();
+        self.tokens
+            .insert(upload.clone(), (transfer.clone(), TransferRole::Upload));
+        self.tokens
+            .insert(download.clone(), (transfer.clone(), TransferRole::Download));
+        Ok((transfer, upload, download))
+    }
+
+    fn reject(&self, id: &str, recipient: &str) -> Result<Arc<Transfer>> {
+        self.take(id, recipient)
+    }
+
+    fn take(&self, id: &str, recipient: &str) -> Result<Arc<Transfer>> {
+        self.offers
+            .remove_if(id, |_, transfer| transfer.recipient == recipient)
+            .map(|(_, transfer)| transfer)
+            .ok_or_else(|| anyhow!("No pending file transfer {}", id))
+    }
+
+    // 每个 token 只能使用一次
+    fn claim(&self, token: &str) -> Option<(Arc<Transfer>, TransferRole)> {
+        self.tokens.remove(token).map(|(_, claimed)| claimed)
+    }
+
+    // 还有 token 没有被使用，说明有一端没有连接
+    fn unclaimed(&self, id: &str) -> bool {
+        self.tokens.iter().any(|entry| entry.value().0.id == id)
+    }
+
+    fn finish(&self, transfer: &Transfer) {
+        self.tokens.retain(|_, (other, _)| other.id != transfer.id);
+        transfer.close();
+    }
+
+    // 用户离开后取消和他有关的、还没有开始的传输
+    fn forget(&self, username: &str) {
+        let involves =
+            |transfer: &Transfer| transfer.sender == username || transfer.recipient == username;
+        self.offers.retain(|_, transfer| !involves(transfer));
+        self.tokens.retain(|_, (transfer, _)| {
+            if involves(transfer) {
+                transfer.close();
+                return false;
+            }
+            true
+        });
+    }
+}
+
+impl Transfer {
+    // 丢弃通道的两端，等待中的下载端会结束，缓冲的数据块也会被释放
+    fn close(&self) {
+        self.upload.lock().unwrap().take();
+        self.download.lock().unwrap().take();
+    }
+}
+
+impl TransferFrame {
+    const HELLO: u8 = 0;
+    const DATA: u8 = 1;
+    const END: u8 = 2;
+    const ERROR: u8 = 3;
+
+    fn encode(&self) -> Bytes {
+        let (kind, payload): (u8, &[u8]) = match self {
+            Self::Hello(token) => (Self::HELLO, token.as_bytes()),
+            Self::Data(chunk) => (Self::DATA, chunk),
+            Self::End(hash) => (Self::END, hash.as_bytes()),
+            Self::Error(reason) => (Self::ERROR, reason.as_bytes()),
+        };
+        let mut buf = BytesMut::with_capacity(payload.len() + 1);
+        buf.put_u8(kind);
+        buf.extend_from_slice(payload);
+        buf.freeze()
+    }
+
+    // 数据块直接复用读取的缓冲区，不需要复制
+    fn decode(mut frame: BytesMut) -> Result<Self> {
+        if frame.is_empty() {
+            return Err(anyhow!("empty transfer frame"));
+        }
+        match frame.get_u8() {
+            Self::HELLO => Ok(Self::Hello(String::from_utf8(frame.to_vec())?)),
+            Self::DATA => Ok(Self::Data(frame.freeze())),
+            Self::END => {
+                let hash: [u8; blake3::OUT_LEN] = frame[..]
+                    .try_into()
+                    .map_err(|_| anyhow!("invalid checksum length: {}", frame.len()))?;
+                Ok(Self::End(hash.into()))
+            }
+            Self::ERROR => Ok(Self::Error(String::from_utf8_lossy(&frame).into_owned())),
+            kind => Err(anyhow!("unknown transfer frame type: {}", kind)),
+        }
+    }
+}
+
+impl Outbox {
+    fn new(capacity: usize) -> Self {
+        Self {
+            queue: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+            readable: Notify::new(),
+            writable: Notify::new(),
+            closed: CancellationToken::new(),
+            finished: AtomicBool::new(false),
+            kicked: CancellationToken::new(),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    // 队列已满或已关闭时返回原消息
+    fn try_push(&self, message: Arc<Message>) -> Result<(), Arc<Message>> {
+        if self.is_closed() {
+            return Err(message);
+        }
+        let mut queue = self.queue.lock().unwrap();
+        if queue.len() >= self.capacity {
+            return Err(message);
+        }
+        queue.push_back(message);
+        self.readable.notify_one();
+        Ok(())
+    }
+
+    // 队列已满时丢弃最早的消息，丢弃了消息时返回 false
+    fn push_drop_oldest(&self, message: Arc<Message>) -> bool {
+        if self.is_closed() {
+            return false;
+        }
+        let mut queue = self.queue.lock().unwrap();
+        let full = queue.len() >= self.capacity;
+        if full {
+            queue.pop_front();
+        }
+        queue.push_back(message);
+        self.readable.notify_one();
+        !full
+    }
+
+    // 队列已满时等待空位，超时返回 false
+    async fn push_timeout(&self, message: Arc<Message>, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut message = message;
+        loop {
+            // 先注册通知再检查队列，避免错过写任务的唤醒
+            let writable = self.writable.notified();
+            tokio::pin!(writable);
+            writable.as_mut().enable();
+
+            match self.try_push(message) {
+                Ok(()) => return true,
+                Err(_) if self.is_closed() => return false,
+                Err(m) => message = m,
+            }
+            if time::timeout_at(deadline, writable).await.is_err() {
+                return false;
+            }
+        }
+    }
+
+    // 取出下一条消息，队列为空时等待；关闭后返回 None
+    async fn pop(&self) -> Option<Arc<Message>> {
+        loop {
+            let readable = self.readable.notified();
+            tokio::pin!(readable);
+            readable.as_mut().enable();
+
+            if self.is_closed() {
+                return None;
+            }
+            if let Some(message) = self.queue.lock().unwrap().pop_front() {
+                self.writable.notify_waiters();
+                return Some(message);
+            }
+            if self.finished.load(Ordering::Acquire) {
+                return None;
+            }
+            tokio::select! {
+                _ = readable => {}
+                _ = self.closed.cancelled() => return None,
+            }
+        }
+    }
+
+    fn finish(&self) {
+        self.finished.store(true, Ordering::Release);
+        self.readable.notify_one();
+    }
+
+    fn close(&self) {
+        self.closed.cancel();
+        self.writable.notify_waiters();
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed.is_cancelled()
+    }
+
+    fn kick(&self) {
+        self.kicked.cancel();
+    }
+
+    async fn kicked(&self) {
+        self.kicked.cancelled().await
+    }
+
+    async fn closed(&self) {
+        self.closed.cancelled().await
+    }
+}
+
+impl Decoder for BoundedLinesCodec {
+    type Item = Result<String, LinesCodecError>;
+    type Error = LinesCodecError;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        match self.0.decode(buf) {
+            Err(LinesCodecError::MaxLineLengthExceeded) => {
+                Ok(Some(Err(LinesCodecError::MaxLineLengthExceeded)))
+            }
+            ret => ret.map(|line| line.map(Ok)),
+        }
+    }
+
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        match self.0.decode_eof(buf) {
+            Err(LinesCodecError::MaxLineLengthExceeded) => {
+                Ok(Some(Err(LinesCodecError::MaxLineLengthExceeded)))
+            }
+            ret => ret.map(|line| line.map(Ok)),
+        }
+    }
+}
+
+impl<T: AsRef<str>> Encoder<T> for BoundedLinesCodec {
+    type Error = LinesCodecError;
+
+    fn encode(&mut self, line: T, buf: &mut BytesMut) -> Result<(), Self::Error> {
+        self.0.encode(line, buf)
+    }
+}
+
+impl Accounts {
+    // 加载账号文件，文件不存在时视为没有账号
+    fn load(path: &Path) -> Result<Self> {
+        let file = match fs::read_to_string(path) {
+            Ok(content) => toml::from_str::<AccountsFile>(&content)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => AccountsFile::default(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self {
+            path: path.to_path_buf(),
+            users: Mutex::new(file.users),
+        })
+    }
+
+    fn role(&self, username: &str) -> Role {
+        let users = self.users.lock().unwrap();
+        users
+            .get(username)
+            .map(|account| account.role)
+            .unwrap_or_default()
+    }
+
+    fn exists(&self, username: &str) -> bool {
+        self.users.lock().unwrap().contains_key(username)
+    }
+
+    fn verify(&self, username: &str, password: &str) -> Result<()> {
+        let users = self.users.lock().unwrap();
+        match users.get(username) {
+            Some(account) if account.verify(password) => Ok(()),
+            _ => Err(InvalidCredentials.into()),
+        }
+    }
+
+    // 注册新账号并写回账号文件
+    fn register(&self, username: &str, password: &str) -> Result<()> {
+        let mut users = self.users.lock().unwrap();
+        if users.contains_key(username) {
+            return Err(anyhow!("Account {} already exists", username));
+        }
+        users.insert(username.to_string(), Account::new(password));
+
+        let file = AccountsFile {
+            users: users.clone(),
+        };
+        if let Err(e) = self.save(&file) {
+            users.remove(username);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    // 先写入临时文件再重命名，避免写到一半时损坏账号文件
+    fn save(&self, file: &AccountsFile) -> Result<()> {
+        let content = toml::to_string_pretty(file)?;
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, content)?;
+        fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
+
+impl Account {
+    fn new(password: &str) -> Self {
+        let salt = nanoid::nanoid!(16);
+        let password_hash = Self::hash(&salt, password).to_hex().to_string();
+        Self {
+            salt,
+            password_hash,
+            role: Role::User,
+        }
+    }
+
+    fn hash(salt: &str, password: &str) -> blake3::Hash {
+        let mut hasher = blake3::Hasher::new_derive_key(PASSWORD_CONTEXT);
+        hasher.update(salt.as_bytes());
+        hasher.update(password.as_bytes());
+        hasher.finalize()
+    }
+
+    // blake3::Hash 的比较是常数时间的
+    fn verify(&self, password: &str) -> bool {
+        match blake3::Hash::from_hex(&self.password_hash) {
+            Ok(hash) => hash == Self::hash(&self.salt, password),
+            Err(_) => false,
+        }
+    }
+}
+
+impl Bans {
+    fn load(path: &Path) -> Result<Self> {
+        let file = match fs::read_to_string(path) {
+            Ok(content) => toml::from_str::<BansFile>(&content)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BansFile::default(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self {
+            path: Some(path.to_path_buf()),
+            bans: Mutex::new(file.bans),
+        })
+    }
+
+    // 查找仍然有效的封禁
+    fn find(&self, target: &BanTarget, now: DateTime<Utc>) -> Option<Ban> {
+        let bans = self.bans.lock().unwrap();
+        bans.iter()
+            .find(|ban| ban.target == *target && ban.is_active(now))
+            .cloned()
+    }
+
+    // 添加封禁，同一对象已有的封禁会被替换
+    fn ban(&self, ban: Ban, now: DateTime<Utc>) -> Result<()> {
+        let mut bans = self.bans.lock().unwrap();
+        bans.retain(|b| b.target != ban.target && b.is_active(now));
+        bans.push(ban);
+        self.save(&bans)
+    }
+
+    // 解除封禁，没有有效的封禁时返回 false
+    fn unban(&self, target: &BanTarget, now: DateTime<Utc>) -> Result<bool> {
+        let mut bans = self.bans.lock().unwrap();
+        let found = bans
+            .iter()
+            .any(|ban| ban.target == *target && ban.is_active(now));
+        // 没有匹配的封禁时不需要重写文件
+        if !found {
+            return Ok(false);
+        }
+        bans.retain(|ban| ban.target != *target && ban.is_active(now));
+        self.save(&bans)?;
+        Ok(true)
+    }
+
+    // 和账号文件一样先写临时文件再重命名
+    fn save(&self, bans: &[Ban]) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let file = BansFile {
+            bans: bans.to_vec(),
+        };
+        let content = toml::to_string_pretty(&file)?;
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, content)?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+impl Ban {
+    fn is_active(&self, now: DateTime<Utc>) -> bool {
+        self.until.is_none_or(|until| until > now)
+    }
+}
+
+impl fmt::Display for BanTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::User(username) => write!(f, "{}", username),
+            Self::Ip(ip) => write!(f, "{}", ip),
+        }
+    }
+}
+
+impl ChatLog {
+    // 打开日志目录，继续写入当天最新的文件
+    fn open(dir: &Path, max_size: u64, now: DateTime<Utc>) -> Result<Self> {
+        fs::create_dir_all(dir)?;
+        let date = now.date_naive();
+        let index = Self::latest_index(dir, date)?;
+        let (file, size) = Self::open_file(dir, date, index)?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            max_size,
+            date,
+            index,
+            file,
+            size,
+        })
+    }
+
+    // 在后台线程中写入日志，返回写入队列和写线程
+    // 队列的发送端全部关闭后，写线程写完剩余记录再退出
+    fn spawn(mut self) -> (mpsc::Sender<LogRecord>, JoinHandle<()>) {
+        let (tx, mut rx) = mpsc::channel(MAX_LOG_RECORDS);
+        let writer = tokio::task::spawn_blocking(move || {
+            while let Some(record) = rx.blocking_recv() {
+                if let Err(e) = self.append(&record) {
+                    warn!("Failed to write chat log: {}", e);
+                }
+            }
+        });
+        (tx, writer)
+    }
+
+    fn append(&mut self, record: &LogRecord) -> Result<()> {
+        let mut line = serde_json::to_string(record)?;
+        line.push('\n');
+
+        let date = record.timestamp.date_naive();
+        if date != self.date {
+            let index = Self::latest_index(&self.dir, date)?;
+            self.rotate(date, index)?;
+        }
+        if self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate(date, self.index + 1)?;
+        }
+
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self, date: NaiveDate, index: u32) -> Result<()> {
+        let (file, size) = Self::open_file(&self.dir, date, index)?;
+        self.date = date;
+        self.index = index;
+        self.file = file;
+        self.size = size;
+        Ok(())
+    }
+
+    // 最近的 n 条满足条件的记录，按时间先后排序
+    fn tail(dir: &Path, n: usize, filter: impl Fn(&LogRecord) -> bool) -> Result<Vec<LogRecord>> {
+        if !dir.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut records = VecDeque::with_capacity(n);
+        for (_, _, path) in Self::files(dir)?.into_iter().rev() {
+            if records.len() >= n {
+                break;
+            }
+            let content = fs::read_to_string(&path)?;
+            let mut file_records = content
+                .lines()
+                .filter_map(|line| match serde_json::from_str::<LogRecord>(line) {
+                    Ok(record) => Some(record),
+                    Err(e) => {
+                        warn!("Skipping invalid record in {}: {}", path.display(), e);
+                        None
+                    }
+                })
+                .filter(|record| filter(record))
+                .collect::<Vec<_>>();
+            while records.len() < n {
+                match file_records.pop() {
+                    Some(record) => records.push_front(record),
+                    None => break,
+                }
+            }
+        }
+        Ok(records.into())
+    }
+
+    // 目录下所有的日志文件，按日期和序号排序
+    fn files(dir: &Path) -> Result<Vec<(NaiveDate, u32, PathBuf)>> {
+        let mut files = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let Some(stem) = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_prefix("chat."))
+                .and_then(|name| name.strip_suffix(".jsonl"))
+            else {
+                continue;
+            };
+            let (date, index) = match stem.split_once('.') {
+                Some((date, index)) => (date, index.parse().ok()),
+                None => (stem, Some(0)),
+            };
+            if let (Ok(date), Some(index)) = (NaiveDate::parse_from_str(date, "%Y-%m-%d"), index) {
+                files.push((date, index, path));
+            }
+        }
+        files.sort();
+        Ok(files)
+    }
+
+    fn latest_index(dir: &Path, date: NaiveDate) -> Result<u32> {
+        let index = Self::files(dir)?
+            .into_iter()
+            .filter(|(d, _, _)| *d == date)
+            .map(|(_, index, _)| index)
+            .max()
+            .unwrap_or(0);
+        Ok(index)
+    }
+
+    fn path(dir: &Path, date: NaiveDate, index: u32) -> PathBuf {
+        let date = date.format("%Y-%m-%d");
+        let name = match index {
+            0 => format!("chat.{}.jsonl", date),
+            _ => format!("chat.{}.{}.jsonl", date, index),
+        };
+        dir.join(name)
+    }
+
+    fn open_file(dir: &Path, date: NaiveDate, index: u32) -> Result<(File, u64)> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(Self::path(dir, date, index))?;
+        let size = file.metadata()?.len();
+        Ok((file, size))
+    }
+}
+
+impl TokenBucket {
+    fn new(rate: f64, burst: u32, now: Instant) -> Self {
+        Self {
+            rate,
+            burst: burst as f64,
+            tokens: burst as f64,
+            last: now,
+        }
+    }
+
+    // 按流逝的时间补充令牌，令牌不足时返回 false
+    fn try_acquire(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+impl FloodGuard {
+    fn new(config: &Config) -> Self {
+        Self {
+            bucket: TokenBucket::new(config.rate_limit, config.rate_burst, Instant::now()),
+            mute_duration: Duration::from_secs(config.flood_mute_duration),
+            violations: 0,
+            muted_until: None,
+        }
+    }
+
+    fn is_muted(&self, now: Instant) -> bool {
+        self.muted_until.is_some_and(|until| now < until)
+    }
+
+    // 检查收到的一行是否超过限流
+    fn check(&mut self, now: Instant) -> FloodVerdict {
+        if self.is_muted(now) {
+            return FloodVerdict::Muted;
+        }
+        if self.bucket.try_acquire(now) {
+            return FloodVerdict::Allow;
+        }
+        self.violation(now)
+    }
+
+    // 记录一次违规，按违规次数逐步升级处罚
+    fn violation(&mut self, now: Instant) -> FloodVerdict {
+        if self.is_muted(now) {
+            return FloodVerdict::Muted;
+        }
+        self.violations += 1;
+        match self.violations {
+            1 => FloodVerdict::Warn,
+            2 => {
+                self.muted_until = Some(now + self.mute_duration);
+                FloodVerdict::Mute(self.mute_duration)
+            }
+            _ => FloodVerdict::Disconnect,
+        }
+    }
+}
+
+impl Message {
+    fn user_joined(room: impl Into<String>, username: impl Into<String>) -> Self {
+        Self::UserJoined {
+            room: room.into(),
+            username: username.into(),
+            timestamp: Utc::now(),
+        }
+    }
+
+    fn user_left(room: impl Into<String>, username: impl Into<String>) -> Self {
+        Self::UserLeft {
+            room: room.into(),
+            username: username.into(),
+            timestamp: Utc::now(),
+        }
+    }
+
+    // 还没有分配 ID 的聊天消息，广播前由 State::number 分配
+    fn chat(
+        room: impl Into<String>,
+        sender: impl Into<String>,
+        content: impl Into<String>,
+    ) -> Self {
+        Self::Chat {
+            id: String::new(),
+            room: room.into(),
+            sender: sender.into(),
+            content: content.into(),
+            timestamp: Utc::now(),
+        }
+    }
+
+    fn edited(
+        id: impl Into<String>,
+        room: impl Into<String>,
+        sender: impl Into<String>,
+        content: impl Into<String>,
+    ) -> Self {
+        Self::Edited {
+            id: id.into(),
+            room: room.into(),
+            sender: sender.into(),
+            content: content.into(),
+            timestamp: Utc::now(),
+        }
+    }
+
+    fn deleted(id: impl Into<String>, room: impl Into<String>, sender: impl Into<String>) -> Self {
+        Self::Deleted {
+            id: id.into(),
+            room: room.into(),
+            sender: sender.into(),
+            timestamp: Utc::now(),
+        }
+    }
+
+    fn user_renamed(old: impl Into<String>, new: impl Into<String>) -> Self {
+        Self::UserRenamed {
+            old: old.into(),
+            new: new.into(),
+            timestamp: Utc::now(),
+        }
+    }
+
+    fn direct(
+        sender: impl Into<String>,
+        recipient: impl Into<String>,
+        content: impl Into<String>,
+    ) -> Self {
+        Self::Direct {
+            sender: sender.into(),
+            recipient: recipient.into(),
+            content: content.into(),
+            timestamp: Utc::now(),
+        }
+    }
+
+    fn sent(id: impl Into<String>, room: impl Into<String>) -> Self {
+        Self::Sent {
+            id: id.into(),
+            room: room.into(),
+            timestamp: Utc::now(),
+        }
+    }
+
+    fn system(content: impl Into<String>) -> Self {
+        Self::System {
+            content: content.into(),
+            timestamp: Utc::now(),
+        }
+    }
+
+    fn error(content: impl Into<String>) -> Self {
+        Self::Error {
+            content: content.into(),
+            timestamp: Utc::now(),
+        }
+    }
+
+    fn ping() -> Self {
+        Self::Ping {
+            timestamp: Utc::now(),
+        }
+    }
+
+    fn pong(token: impl Into<String>) -> Self {
+        Self::Pong {
+            token: token.into(),
+            timestamp: Utc::now(),
+        }
+    }
+
+    fn names(room: impl Into<String>, users: Vec<String>) -> Self {
+        Self::Names {
+            room: room.into(),
+            users,
+            timestamp: Utc::now(),
+        }
+    }
+
+    fn transfer(transfer: &Transfer, status: TransferStatus) -> Self {
+        Self::Transfer {
+            id: transfer.id.clone(),
+            sender: transfer.sender.clone(),
+            recipient: transfer.recipient.clone(),
+            name: transfer.name.clone(),
+            status,
+            timestamp: Utc::now(),
+        }
+    }
+
+    fn timestamp(&self) -> DateTime<Utc> {
+        match self {
+            Self::UserJoined { timestamp, .. }
+            | Self::UserLeft { timestamp, .. }
+            | Self::Chat { timestamp, .. }
+            | Self::Edited { timestamp, .. }
+            | Self::Deleted { timestamp, .. }
+            | Self::UserRenamed { timestamp, .. }
+            | Self::Direct { timestamp, .. }
+            | Self::Sent { timestamp, .. }
+            | Self::System { timestamp, .. }
+            | Self::Error { timestamp, .. }
+            | Self::Ping { timestamp }
+            | Self::Pong { timestamp, .. }
+            | Self::Names { timestamp, .. }
+            | Self::Transfer { timestamp, .. } => *timestamp,
+            Self::History { message } => message.timestamp(),
+        }
+    }
+
+    // 聊天消息及其修改和删除对应的消息 ID
+    fn id(&self) -> Option<&str> {
+        match self {
+            Self::Chat { id, .. } | Self::Edited { id, .. } | Self::Deleted { id, .. } => Some(id),
+            _ => None,
+        }
+    }
+
+    // 房间消息所属的房间，私聊和系统消息返回 None
+    fn room(&self) -> Option<&str> {
+        match self {
+            Self::UserJoined { room, .. }
+            | Self::UserLeft { room, .. }
+            | Self::Chat { room, .. }
+            | Self::Edited { room, .. }
+            | Self::Deleted { room, .. } => Some(room),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::UserJoined { room, username, .. } => {
+                write!(f, "[{} has joined #{}]", username, room)
+            }
+            Self::UserLeft { room, username, .. } => {
+                write!(f, "[{} has left #{} :(]", username, room)
+            }
+            Self::Chat {
+                id,
+                room,
+                sender,
+                content,
+                timestamp,
+            } => write!(
+                f,
+                "#{} [{} {}] {}: {}",
+                room,
+                id,
+                timestamp.format("%H:%M:%S"),
+                sender,
+                content
+            ),
+            Self::Edited {
+                id,
+                room,
+                sender,
+                content,
+                timestamp,
+            } => write!(
+                f,
+                "#{} [{} {}] {} edited: {}",
+                room,
+                id,
+                timestamp.format("%H:%M:%S"),
+                sender,
+                content
+            ),
+            Self::Deleted {
+                id, room, sender, ..
+            } => write!(f, "#{} [{}] {} deleted the message", room, id, sender),
+            Self::UserRenamed { old, new, .. } => {
+                write!(f, "[{} is now known as {}]", old, new)
+            }
+            Self::Direct {
+                sender,
+                recipient,
+                content,
+                ..
+            } => write!(f, "[DM] {} -> {}: {}", sender, recipient, content),
+            Self::Sent { id, .. } => write!(f, "* Message #{} sent", id),
+            Self::System { content, .. } => write!(f, "* {}", content),
+            Self::Error { content, .. } => write!(f, "! {}", content),
+            Self::History { message } => write!(f, "[history] {}", message),
+            Self::Ping { .. } => write!(f, "PING"),
+            Self::Pong { token, .. } => write!(f, "PONG {}", token),
+            Self::Names { room, users, .. } => {
+                write!(f, "* Users in #{}: {}", room, users.join(", "))
+            }
+            Self::Transfer {
+                id,
+                sender,
+                recipient,
+                name,
+                status,
+                ..
+            } => match status {
+                TransferStatus::Offered => write!(
+                    f,
+                    "[file] {} offers {} to {}, use /accept {} or /reject {}",
+                    sender, name, recipient, id, id
+                ),
+                TransferStatus::Rejected => {
+                    write!(f, "[file] {} rejected {} from {}", recipient, name, sender)
+                }
+                TransferStatus::Ready { addr, token } => write!(
+                    f,
+                    "[file] transfer {} of {} is ready on {} with token {}",
+                    id, name, addr, token
+                ),
+                TransferStatus::Completed { size, checksum } => write!(
+                    f,
+                    "[file] {} sent {} to {}: {} bytes, blake3 {}",
+                    sender, name, recipient, size, checksum
+                ),
+                TransferStatus::Failed { reason } => {
+                    write!(f, "[file] transfer {} of {} failed: {}", id, name, reason)
+                }
+            },
+        }
+    }
+}
+
+impl Command {
+    // 封禁和解除封禁需要管理员，其他管理命令需要版主
+    fn required_role(&self) -> Role {
+        match self {
+            Self::Ban { .. } | Self::Unban { .. } => Role::Admin,
+            Self::Kick { .. } | Self::Mute { .. } | Self::Unmute { .. } => Role::Moderator,
+            _ => Role::User,
+        }
+    }
+
+    // 管理命令处理的用户或 IP，解除封禁时不检查对方的角色
+    fn moderation_target(&self) -> Option<&str> {
+        match self {
+            Self::Kick { username } | Self::Mute { username, .. } | Self::Unmute { username } => {
+                Some(username)
+            }
+            Self::Ban { target, .. } => Some(target),
+            _ => None,
+        }
+    }
+
+    fn parse(line: &str) -> Result<Self> {
+        let trimmed = line.trim();
+        match trimmed {
+            "exit!" => return Ok(Self::Exit),
+            "PONG" => return Ok(Self::Pong),
+            _ => {}
+        }
+        let Some(command) = trimmed.strip_prefix('/') else {
+            return Ok(Self::Chat {
+                room: None,
+                content: line.to_string(),
+            });
+        };
+
+        // 命令名之后的内容，/msg 需要保留消息中的空白
+        let (name, rest) = command
+            .split_once(char::is_whitespace)
+            .unwrap_or((command, ""));
+        let rest = rest.trim_start();
+        let arg = rest.split_whitespace().next();
+        match (name, arg) {
+            ("join", Some(room)) => Ok(Self::Join {
+                room: parse_room(room)?,
+            }),
+            ("join", None) => Err(anyhow!("Usage: /join <room>")),
+            ("leave", room) => Ok(Self::Leave {
+                room: room.map(parse_room).transpose()?,
+            }),
+            ("rooms", _) => Ok(Self::Rooms),
+            ("who", _) => Ok(Self::Who),
+            ("names", room) => Ok(Self::Names {
+                room: room.map(parse_room).transpose()?,
+            }),
+            ("msg", Some(to)) => match rest[to.len()..].trim() {
+                "" => Err(anyhow!("Usage: /msg <username> <text>")),
+                content => Ok(Self::Msg {
+                    to: to.to_string(),
+                    content: content.to_string(),
+                }),
+            },
+            ("msg", None) => Err(anyhow!("Usage: /msg <username> <text>")),
+            ("nick", Some(new)) => Ok(Self::Nick {
+                username: parse_username(new)?,
+            }),
+            ("nick", None) => Err(anyhow!("Usage: /nick <username>")),
+            ("login" | "register", Some(username)) => {
+                let username = parse_username(username)?;
+                let password = rest[username.len()..].trim().to_string();
+                match name {
+                    _ if password.is_empty() => {
+                        Err(anyhow!("Usage: /{} <username> <password>", name))
+                    }
+                    "login" => Ok(Self::Login { username, password }),
+                    _ => Ok(Self::Register { username, password }),
+                }
+            }
+            ("login" | "register", None) => Err(anyhow!("Usage: /{} <username> <password>", name)),
+            ("kick", Some(username)) => Ok(Self::Kick {
+                username: parse_username(username)?,
+            }),
+            ("ban" | "mute", Some(target)) => {
+                let duration = match rest[target.len()..].split_whitespace().next() {
+                    Some(duration) => Some(parse_duration(duration)?),
+                    None => None,
+                };
+                match name {
+                    "ban" => Ok(Self::Ban {
+                        target: parse_ban_target(target)?.to_string(),
+                        duration,
+                    }),
+                    _ => Ok(Self::Mute {
+                        username: parse_username(target)?,
+                        duration,
+                    }),
+                }
+            }
+            ("unban", Some(target)) => Ok(Self::Unban {
+                target: parse_ban_target(target)?.to_string(),
+            }),
+            ("unmute", Some(username)) => Ok(Self::Unmute {
+                username: parse_username(username)?,
+            }),
+            ("kick" | "mute" | "unmute", None) => Err(anyhow!("Usage: /{} <username>", name)),
+            ("ban", None) => Err(anyhow!("Usage: /ban <username|ip> [duration]")),
+            ("unban", None) => Err(anyhow!("Usage: /unban <username|ip>")),
+            ("send", Some(to)) => match rest[to.len()..].trim() {
+                "" => Err(anyhow!("Usage: /send <username> <path>")),
+                path => Ok(Self::Send {
+                    to: parse_username(to)?,
+                    path: path.to_string(),
+                }),
+            },
+            ("send", None) => Err(anyhow!("Usage: /send <username> <path>")),
+            ("accept", Some(id)) => Ok(Self::Accept { id: id.to_string() }),
+            ("reject", Some(id)) => Ok(Self::Reject { id: id.to_string() }),
+            ("accept" | "reject", None) => Err(anyhow!("Usage: /{} <id>", name)),
+            ("edit", Some(id)) => match rest[id.len()..].trim() {
+                "" => Err(anyhow!("Usage: /edit <id> <text>")),
+                content => Ok(Self::Edit {
+                    id: id.to_string(),
+                    content: content.to_string(),
+                }),
+            },
+            ("edit", None) => Err(anyhow!("Usage: /edit <id> <text>")),
+            ("delete", Some(id)) => Ok(Self::Delete { id: id.to_string() }),
+            ("delete", None) => Err(anyhow!("Usage: /delete <id>")),
+            ("history", n) => match n.map(str::parse::<usize>).transpose() {
+                Ok(n) => Ok(Self::History { n }),
+                Err(_) => Err(anyhow!("Usage: /history [n]")),
+            },
+            _ => Err(anyhow!("Unknown command: /{}", name)),
+        }
+    }
+
+    // JSON 协议下的命令同样需要校验房间名和用户名
+    fn validate(self) -> Result<Self> {
+        match self {
+            Self::Join { room } => Ok(Self::Join {
+                room: parse_room(&room)?,
+            }),
+            Self::Leave { room } => Ok(Self::Leave {
+                room: room.as_deref().map(parse_room).transpose()?,
+            }),
+            Self::Nick { username } => Ok(Self::Nick {
+                username: parse_username(&username)?,
+            }),
+            Self::Login { username, password } => Ok(Self::Login {
+                username: parse_username(&username)?,
+                password,
+            }),
+            Self::Register { username, password } if password.is_empty() => {
+                Err(anyhow!("Password cannot be empty: {}", username))
+            }
+            Self::Register { username, password } => Ok(Self::Register {
+                username: parse_username(&username)?,
+                password,
+            }),
+            Self::Kick { username } => Ok(Self::Kick {
+                username: parse_username(&username)?,
+            }),
+            Self::Ban { target, duration } => Ok(Self::Ban {
+                target: parse_ban_target(&target)?.to_string(),
+                duration,
+            }),
+            Self::Unban { target } => Ok(Self::Unban {
+                target: parse_ban_target(&target)?.to_string(),
+            }),
+            Self::Mute { username, duration } => Ok(Self::Mute {
+                username: parse_username(&username)?,
+                duration,
+            }),
+            Self::Unmute { username } => Ok(Self::Unmute {
+                username: parse_username(&username)?,
+            }),
+            Self::Names { room } => Ok(Self::Names {
+                room: room.as_deref().map(parse_room).transpose()?,
+            }),
+            Self::Send { to, path } => Ok(Self::Send {
+                to: parse_username(&to)?,
+                path,
+            }),
+            Self::Chat { room, content } => Ok(Self::Chat {
+                room: room.as_deref().map(parse_room).transpose()?,
+                content,
+            }),
+            command => Ok(command),
+        }
+    }
+
+    // 将注册完成后的 IRC 消息转换为命令，频道名和房间名一一对应
+    fn parse_irc(line: &str) -> Result<Self> {
+        let Some(irc) = IrcLine::parse(line) else {
+            return Err(anyhow!("Empty command"));
+        };
+        // JOIN 和 PART 可以带多个逗号分隔的频道，这里只处理第一个
+        let channel = |channels: &str| parse_room(channels.split(',').next().unwrap_or_default());
+        match (irc.command.as_str(), irc.params.as_slice()) {
+            ("NICK", [nick, ..]) => Ok(Self::Nick {
+                username: parse_username(nick)?,
+            }),
+            ("JOIN", [channels, ..]) => Ok(Self::Join {
+                room: channel(channels)?,
+            }),
+            ("PART", [channels, ..]) => Ok(Self::Leave {
+                room: Some(channel(channels)?),
+            }),
+            ("PRIVMSG", [target, content]) if target.starts_with('#') => Ok(Self::Chat {
+                room: Some(parse_room(target)?),
+                content: content.to_string(),
+            }),
+            ("PRIVMSG", [target, content]) => Ok(Self::Msg {
+                to: target.to_string(),
+                content: content.to_string(),
+            }),
+            ("NAMES", []) => Ok(Self::Names { room: None }),
+            ("NAMES", [channels, ..]) => Ok(Self::Names {
+                room: Some(channel(channels)?),
+            }),
+            ("WHO", _) => Ok(Self::Who),
+            ("PING", [token, ..]) => Ok(Self::Ping {
+                token: token.to_string(),
+            }),
+            ("PONG", _) => Ok(Self::Pong),
+            ("QUIT", _) => Ok(Self::Exit),
+            ("PASS" | "USER", _) => Err(anyhow!("You may not reregister")),
+            ("NICK" | "JOIN" | "PART" | "PRIVMSG" | "PING", _) => {
+                Err(anyhow!("Not enough parameters: {}", irc.command))
+            }
+            (command, _) => Err(anyhow!("Unknown command: {}", command)),
+        }
+    }
+}
+
+impl<'a> IrcLine<'a> {
+    // 忽略消息的前缀，命令名不区分大小写，空行返回 None
+    fn parse(line: &'a str) -> Option<Self> {
+        let mut line = line.trim_end_matches(['\r', '\n']);
+        if let Some(prefixed) = line.strip_prefix(':') {
+            line = prefixed.split_once(' ').map_or("", |(_, rest)| rest);
+        }
+        let (command, mut rest) = line
+            .trim_start()
+            .split_once(' ')
+            .unwrap_or((line.trim_start(), ""));
+        if command.is_empty() {
+            return None;
+        }
+
+        let mut params = Vec::new();
+        loop {
+            rest = rest.trim_start_matches(' ');
+            if rest.is_empty() {
+                break;
+            }
+            if let Some(trailing) = rest.strip_prefix(':') {
+                params.push(trailing);
+                break;
+            }
+            let (param, next) = rest.split_once(' ').unwrap_or((rest, ""));
+            params.push(param);
+            rest = next;
+        }
+        Some(Self {
+            command: command.to_ascii_uppercase(),
+            params,
+        })
+    }
+}
+
+impl Protocol {
+    // 将消息编码为一行输出
+    fn encode(&self, message: &Message) -> String {
+        match self {
+            Self::Text => message.to_string(),
+            Self::Json => serde_json::to_string(message).unwrap_or_else(|e| {
+                warn!("Failed to serialize message {:?}: {}", message, e);
+                message.to_string()
+            }),
+            Self::Irc => Self::encode_irc(message),
+        }
+    }
+
+    // 聊天消息和私聊都编码为 PRIVMSG，服务端的提示编码为 NOTICE
+    // 数字回复的目标用 * 代替，写任务并不知道用户当前的昵称
+    fn encode_irc(message: &Message) -> String {
+        let server = IRC_SERVER_NAME;
+        let user = |nick: &str| format!("{}!{}@{}", nick, nick, server);
+        match message {
+            Message::UserJoined { room, username, .. } => {
+                format!(":{} JOIN #{}", user(username), room)
+            }
+            Message::UserLeft { room, username, .. } => {
+                format!(":{} PART #{}", user(username), room)
+            }
+            Message::Chat {
+                room,
+                sender,
+                content,
+                ..
+            } => format!(":{} PRIVMSG #{} :{}", user(sender), room, content),
+            Message::Edited {
+                id,
+                room,
+                sender,
+                content,
+                ..
+            } => format!(
+                ":{} NOTICE #{} :edited {}: {}",
+                user(sender),
+                room,
+                id,
+                content
+            ),
+            Message::Deleted {
+                id, room, sender, ..
+            } => format!(":{} NOTICE #{} :deleted {}", user(sender), room, id),
+            Message::UserRenamed { old, new, .. } => format!(":{} NICK {}", user(old), new),
+            Message::Direct {
+                sender,
+                recipient,
+                content,
+                ..
+            } => format!(":{} PRIVMSG {} :{}", user(sender), recipient, content),
+            Message::Sent { id, room, .. } => {
+                format!(":{} NOTICE #{} :Message #{} sent", server, room, id)
+            }
+            Message::System { content, .. } => format!(":{} NOTICE * :{}", server, content),
+            Message::Error { content, .. } => format!(":{} NOTICE * :Error: {}", server, content),
+            Message::History { message } => {
+                let target = message.room().map(|room| format!("#{}", room));
+                let target = target.as_deref().unwrap_or("*");
+                format!(":{} NOTICE {} :[history] {}", server, target, message)
+            }
+            Message::Ping { .. } => format!("PING :{}", server),
+            Message::Pong { token, .. } => format!(":{} PONG {} :{}", server, server, token),
+            Message::Transfer { .. } => format!(":{} NOTICE * :{}", server, message),
+            Message::Names { room, users, .. } => {
+                let names = format!("= #{} :{}", room, users.join(" "));
+                let end = format!("#{} :End of /NAMES list", room);
+                format!(
+                    "{}\n{}",
+                    irc_numeric("353", "*", names),
+                    irc_numeric("366", "*", end)
+                )
+            }
+        }
+    }
+
+    // 将客户端输入的一行解析为命令
+    fn decode(&self, line: &str) -> Result<Command> {
+        match self {
+            Self::Text => Command::parse(line),
+            Self::Json => serde_json::from_str::<Command>(line)
+                .map_err(|e| anyhow!("Invalid command: {}", e))?
+                .validate(),
+            Self::Irc => Command::parse_irc(line),
+        }
+    }
+}
+
+// 房间名可以带 # 前缀，只允许字母、数字、- 和 _
+fn parse_room(room: &str) -> Result<String> {
+    let room = room.strip_prefix('#').unwrap_or(room);
+    if room.is_empty()
+        || !room
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(anyhow!("Invalid room name: {}", room));
+    }
+    Ok(room.to_string())
+}
+
+// 用户名不能为空，不能超过最大长度，只允许字母、数字、- 和 _
+fn parse_username(username: &str) -> Result<String> {
+    let username = username.trim();
+    if username.is_empty() {
+        return Err(anyhow!("Username cannot be empty"));
+    }
+    if username.chars().count() > MAX_USERNAME_LEN {
+        return Err(anyhow!(
+            "Username cannot be longer than {} characters",
+            MAX_USERNAME_LEN
+        ));
+    }
+    if !username
+        .chars()
+        .all(|c| c.is_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(anyhow!(
+            "Username can only contain letters, digits, - and _"
+        ));
+    }
+    Ok(username.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn add_outbox(state: &State, addr: SocketAddr) -> Arc<Outbox> {
+        let outbox = Arc::new(Outbox::new(2));
+        state.peers.insert(addr, outbox.clone());
+        outbox
+    }
+
+    // 聊天消息的显示中包含当前时间，比较时去掉
+    fn without_time(message: &Message) -> String {
+        match message {
+            Message::Chat {
+                id,
+                room,
+                sender,
+                content,
+                ..
+            } => format!("#{} [{}] {}: {}", room, id, sender, content),
+            message => message.to_string(),
+        }
+    }
+
+    fn state_with_policy(policy: SlowConsumerPolicy) -> State {
+        State::new(Config {
+            slow_consumer_policy: policy,
+            slow_consumer_timeout: 0,
+            ..Config::default()
+        })
+    }
+
+    #[test]
+    fn test_command_parse() {
+        assert_eq!(
+            Command::parse("hello").unwrap(),
+            Command::Chat {
+                room: None,
+                content: "hello".into()
+            }
+        );
+        assert_eq!(Command::parse(" exit! ").unwrap(), Command::Exit);
+        assert_eq!(
+            Command::parse("/join #rust").unwrap(),
+            Command::Join {
+                room: "rust".into()
+            }
+        );
+        assert_eq!(
+            Command::parse("/leave").unwrap(),
+            Command::Leave { room: None }
+        );
+        assert_eq!(
+            Command::parse("/leave rust").unwrap(),
+            Command::Leave {
+                room: Some("rust".into())
+            }
+        );
+        assert_eq!(Command::parse("/rooms").unwrap(), Command::Rooms);
+        assert_eq!(
+            Command::parse("/msg bob  hello  there").unwrap(),
+            Command::Msg {
+                to: "bob".into(),
+                content: "hello  there".into()
+            }
+        );
+        assert!(Command::parse("/msg bob").is_err());
+        assert_eq!(
+            Command::parse("/nick carol").unwrap(),
+            Command::Nick {
+                username: "carol".into()
+            }
+        );
+        assert!(Command::parse("/nick").is_err());
+        assert_eq!(
+            Command::parse("/register alice my secret").unwrap(),
+            Command::Register {
+                username: "alice".into(),
+                password: "my secret".into()
+            }
+        );
+        assert_eq!(
+            Command::parse("/login alice pw").unwrap(),
+            Command::Login {
+                username: "alice".into(),
+                password: "pw".into()
+            }
+        );
+        assert!(Command::parse("/login alice").is_err());
+        assert_eq!(
+            Command::parse("/history").unwrap(),
+            Command::History { n: None }
+        );
+        assert_eq!(
+            Command::parse("/history 5").unwrap(),
+            Command::History { n: Some(5) }
+        );
+        assert!(Command::parse("/history five").is_err());
+        assert!(Command::parse("/join").is_err());
+        assert!(Command::parse("/join a!b").is_err());
+        assert!(Command::parse("/unknown").is_err());
+    }
+
+    #[test]
+    fn test_state_rooms() {
+        let state = State::default();
+        assert!(state.join("lobby", addr(1)));
+        assert!(state.join("lobby", addr(2)));
+        assert!(state.join("rust", addr(1)));
+        assert!(!state.join("rust", addr(1)));
+        assert_eq!(
+            state.rooms(),
+            vec![("lobby".to_string(), 2), ("rust".to_string(), 1)]
+        );
+
+        assert!(state.leave("rust", addr(1)));
+        assert!(!state.leave("rust", addr(1)));
+        assert_eq!(state.rooms(), vec![("lobby".to_string(), 2)]);
+
+        assert_eq!(state.leave_all(addr(2)), vec!["lobby".to_string()]);
+        assert_eq!(state.rooms_of(addr(1)), vec!["lobby".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_only_reaches_room_members() {
+        let state = State::new(Config {
+            server_id: "a".into(),
+            ..Config::default()
+        });
+        let outboxes = (1..=3)
+            .map(|port| add_outbox(&state, addr(port)))
+            .collect::<Vec<_>>();
+        state.join("rust", addr(1));
+        state.join("rust", addr(2));
+        state.join("lobby", addr(3));
+
+        let message = Arc::new(state.chat_message("rust", "alice", "hi"));
+        state.broadcast("rust", addr(1), message).await;
+
+        assert!(outboxes[0].queue.lock().unwrap().is_empty());
+        assert_eq!(
+            without_time(&outboxes[1].pop().await.unwrap()),
+            "#rust [a:1] alice: hi"
+        );
+        assert!(outboxes[2].queue.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_message_display() {
+        assert_eq!(
+            Message::user_joined("lobby", "alice").to_string(),
+            "[alice has joined #lobby]"
+        );
+        assert_eq!(
+            Message::direct("alice", "bob", "psst").to_string(),
+            "[DM] alice -> bob: psst"
+        );
+        assert_eq!(Message::system("hello").to_string(), "* hello");
+    }
+
+    #[test]
+    fn test_parse_username() {
+        assert_eq!(parse_username(" alice ").unwrap(), "alice");
+        assert!(parse_username("").is_err());
+        assert!(parse_username("   ").is_err());
+        assert!(parse_username("al ice").is_err());
+        assert!(parse_username(&"a".repeat(MAX_USERNAME_LEN + 1)).is_err());
+    }
+
+    #[test]
+    fn test_state_usernames() {
+        let state = State::default();
+        assert!(state.reserve_username("alice", addr(1)));
+        assert!(!state.reserve_username("alice", addr(2)));
+        assert!(state.reserve_username("bob", addr(2)));
+
+        assert!(!state.rename("bob", "alice", addr(2)));
+        assert!(state.rename("bob", "carol", addr(2)));
+        assert!(state.reserve_username("bob", addr(3)));
+        assert_eq!(*state.users.get("carol").unwrap(), addr(2));
+    }
+
+    #[tokio::test]
+    async fn test_history_is_bounded_and_filtered_by_room() {
+        let state = State::new(Config {
+            history_size: 3,
+            server_id: "a".into(),
+            ..Config::default()
+        });
+        for i in 0..4 {
+            let message = Arc::new(state.chat_message("rust", "alice", i.to_string()));
+            state.broadcast("rust", addr(1), message).await;
+        }
+        let message = Arc::new(state.chat_message("lobby", "bob", "hi"));
+        state.broadcast("lobby", addr(2), message).await;
+
+        let rust = ["rust".to_string()];
+        let history = state
+            .history(&rust, None)
+            .iter()
+            .map(|message| without_time(message))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            history,
+            vec!["#rust [a:3] alice: 2", "#rust [a:4] alice: 3"]
+        );
+        assert_eq!(state.history(&rust, Some(1)).len(), 1);
+        assert!(state.history(&["go".to_string()], None).is_empty());
+        let replayed = Message::History {
+            message: Arc::new(state.chat_message("lobby", "bob", "hi")),
+        }
+        .to_string();
+        assert!(replayed.starts_with("[history] #lobby [a:6 ") && replayed.ends_with("] bob: hi"));
+    }
+
+    #[test]
+    fn test_json_protocol() {
+        let protocol = Protocol::Json;
+        assert_eq!(
+            protocol
+                .decode(r##"{"type":"join","room":"#rust"}"##)
+                .unwrap(),
+            Command::Join {
+                room: "rust".into()
+            }
+        );
+        assert_eq!(
+            protocol.decode(r#"{"type":"leave"}"#).unwrap(),
+            Command::Leave { room: None }
+        );
+        assert_eq!(
+            protocol
+                .decode(r#"{"type":"chat","content":"/not a command"}"#)
+                .unwrap(),
+            Command::Chat {
+                room: None,
+                content: "/not a command".into()
+            }
+        );
+        assert!(protocol.decode(r#"{"type":"nick","username":""}"#).is_err());
+        assert!(protocol.decode("hello").is_err());
+
+        let state = State::new(Config {
+            server_id: "a".into(),
+            ..Config::default()
+        });
+        let line = protocol.encode(&state.chat_message("rust", "alice", "hi"));
+        let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value["type"], "chat");
+        assert_eq!(value["room"], "rust");
+        assert_eq!(value["sender"], "alice");
+        assert!(value["timestamp"].is_string());
+
+        assert_eq!(value["id"], "a:1");
+
+        let message: Message = serde_json::from_str(&line).unwrap();
+        assert_eq!(without_time(&message), "#rust [a:1] alice: hi");
+
+        let line = protocol.encode(&Message::error("oops"));
+        assert!(line.contains(r#""type":"error""#));
+
+        // 发送者从 sent 消息中取得新消息的 ID
+        let value: serde_json::Value =
+            serde_json::from_str(&protocol.encode(&Message::sent("a:1", "rust"))).unwrap();
+        assert_eq!(value["type"], "sent");
+        assert_eq!(value["id"], "a:1");
+        assert_eq!(value["room"], "rust");
+        assert_eq!(
+            Message::sent("a:1", "rust").to_string(),
+            "* Message #a:1 sent"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_slow_consumer_drop_oldest() {
+        let state = state_with_policy(SlowConsumerPolicy::DropOldest);
+        let outbox = add_outbox(&state, addr(1));
+        for i in 0..3 {
+            let message = Arc::new(Message::system(i.to_string()));
+            assert!(state.send_to(addr(1), message).await);
+        }
+
+        assert_eq!(outbox.dropped.load(Ordering::Relaxed), 1);
+        assert_eq!(outbox.pop().await.unwrap().to_string(), "* 1");
+        assert_eq!(outbox.pop().await.unwrap().to_string(), "* 2");
+    }
+
+    #[tokio::test]
+    async fn test_slow_consumer_drop_new() {
+        let state = state_with_policy(SlowConsumerPolicy::DropNew);
+        let outbox = add_outbox(&state, addr(1));
+        for i in 0..3 {
+            let message = Arc::new(Message::system(i.to_string()));
+            assert_eq!(state.send_to(addr(1), message).await, i < 2);
+        }
+
+        assert_eq!(outbox.dropped.load(Ordering::Relaxed), 1);
+        assert_eq!(outbox.pop().await.unwrap().to_string(), "* 0");
+        assert_eq!(outbox.pop().await.unwrap().to_string(), "* 1");
+    }
+
+    #[tokio::test]
+    async fn test_slow_consumer_disconnect() {
+        let state = state_with_policy(SlowConsumerPolicy::Disconnect);
+        let outbox = add_outbox(&state, addr(1));
+        for i in 0..3 {
+            let message = Arc::new(Message::system(i.to_string()));
+            assert_eq!(state.send_to(addr(1), message).await, i < 2);
+        }
+
+        assert!(outbox.is_closed());
+        assert!(outbox.pop().await.is_none());
+        assert!(!state.peers.contains_key(&addr(1)));
+    }
+
+    #[tokio::test]
+    async fn test_outbox_push_waits_for_writer() {
+        let outbox = Arc::new(Outbox::new(1));
+        assert!(outbox.try_push(Arc::new(Message::system("a"))).is_ok());
+
+        let writer = outbox.clone();
+        let handle = tokio::spawn(async move { writer.pop().await });
+        let message = Arc::new(Message::system("b"));
+        assert!(outbox.push_timeout(message, Duration::from_secs(5)).await);
+        assert_eq!(handle.await.unwrap().unwrap().to_string(), "* a");
+    }
+
+    #[tokio::test]
+    async fn test_outbox_finish_drains_queue() {
+        let outbox = Outbox::new(2);
+        assert!(outbox.try_push(Arc::new(Message::system("a"))).is_ok());
+        outbox.finish();
+
+        assert_eq!(outbox.pop().await.unwrap().to_string(), "* a");
+        assert!(outbox.pop().await.is_none());
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(2.0, 2, now);
+        assert!(bucket.try_acquire(now));
+        assert!(bucket.try_acquire(now));
+        assert!(!bucket.try_acquire(now));
+
+        let later = now + Duration::from_millis(500);
+        assert!(bucket.try_acquire(later));
+        assert!(!bucket.try_acquire(later));
+
+        // 令牌不会超过桶的容量
+        let much_later = later + Duration::from_secs(60);
+        assert!(bucket.try_acquire(much_later));
+        assert!(bucket.try_acquire(much_later));
+        assert!(!bucket.try_acquire(much_later));
+    }
+
+    #[test]
+    fn test_flood_guard_escalates() {
+        let config = Config {
+            rate_limit: 1.0,
+            rate_burst: 1,
+            flood_mute_duration: 10,
+            ..Config::default()
+        };
+        let mut guard = FloodGuard::new(&config);
+        let now = Instant::now();
+
+        assert_eq!(guard.check(now), FloodVerdict::Allow);
+        assert_eq!(guard.check(now), FloodVerdict::Warn);
+        assert_eq!(
+            guard.check(now),
+            FloodVerdict::Mute(Duration::from_secs(10))
+        );
+        assert_eq!(guard.check(now), FloodVerdict::Muted);
+        assert_eq!(guard.violation(now), FloodVerdict::Muted);
+
+        let unmuted = now + Duration::from_secs(11);
+        assert_eq!(guard.check(unmuted), FloodVerdict::Allow);
+        assert_eq!(guard.check(unmuted), FloodVerdict::Disconnect);
+    }
+
+    #[test]
+    fn test_bounded_lines_codec_recovers_from_long_line() {
+        let mut codec = BoundedLinesCodec(LinesCodec::new_with_max_length(4));
+        let mut buf = BytesMut::from("toolong\nok\n");
+
+        assert!(matches!(
+            codec.decode(&mut buf),
+            Ok(Some(Err(LinesCodecError::MaxLineLengthExceeded)))
+        ));
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap().unwrap(), "ok");
+    }
+
+    #[tokio::test]
+    async fn test_graceful_shutdown() -> Result<()> {
+        let state = Arc::new(State::default());
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let server = tokio::spawn(serve(state.clone(), listener));
+
+        let stream = TcpStream::connect(addr).await?;
+        let mut client = Framed::new(stream, LinesCodec::new());
+        assert_eq!(client.next().await.unwrap()?, "Enter your username:");
+        client.send("alice").await?;
+        assert_eq!(
+            client.next().await.unwrap()?,
+            "* You are now chatting in #lobby"
+        );
+
+        state.shutdown.cancel();
+        assert_eq!(client.next().await.unwrap()?, "* Server is shutting down");
+        assert!(client.next().await.is_none());
+
+        time::timeout(Duration::from_secs(5), server).await???;
+        assert!(state.writers.is_empty());
+        Ok(())
+    }
+
+    fn temp_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("chat-log-{}", nanoid::nanoid!()))
+    }
+
+    fn log_record(room: &str, content: &str, timestamp: DateTime<Utc>) -> LogRecord {
+        let mut message = Message::chat(room, "alice", content);
+        if let Message::Chat {
+            id, timestamp: t, ..
+        } = &mut message
+        {
+            *id = format!("a:{}", content);
+            *t = timestamp;
+        }
+        LogRecord {
+            timestamp,
+            sender: addr(1),
+            room: Some(room.to_string()),
+            message: Arc::new(message),
+        }
+    }
+
+    #[test]
+    fn test_chat_log_rotates_by_size_and_day() -> Result<()> {
+        let dir = temp_dir();
+        let day1 = "2024-05-20T10:00:00Z".parse::<DateTime<Utc>>()?;
+        let day2 = "2024-05-21T10:00:00Z".parse::<DateTime<Utc>>()?;
+
+        let mut log = ChatLog::open(&dir, 200, day1)?;
+        for i in 0..3 {
+            log.append(&log_record("lobby", &i.to_string(), day1))?;
+        }
+        log.append(&log_record("lobby", "tomorrow", day2))?;
+
+        let names = ChatLog::files(&dir)?
+            .into_iter()
+            .map(|(_, _, path)| path.file_name().unwrap().to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            vec![
+                "chat.2024-05-20.jsonl",
+                "chat.2024-05-20.1.jsonl",
+                "chat.2024-05-20.2.jsonl",
+                "chat.2024-05-21.jsonl"
+            ]
+        );
+
+        // 重新打开时继续写入当天最新的文件
+        let log = ChatLog::open(&dir, 200, day1)?;
+        assert_eq!(log.index, 2);
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_chat_log_tail_restores_history() -> Result<()> {
+        let dir = temp_dir();
+        let now = "2024-05-20T10:00:00Z".parse::<DateTime<Utc>>()?;
+        let mut log = ChatLog::open(&dir, 300, now)?;
+        for i in 0..5 {
+            log.append(&log_record("lobby", &i.to_string(), now))?;
+        }
+        let mut record = log_record("lobby", "dm", now);
+        record.room = None;
+        log.append(&record)?;
+        // 删除的消息恢复后只剩删除记录
+        log.append(&LogRecord {
+            timestamp: now,
+            sender: addr(1),
+            room: Some("lobby".into()),
+            message: Arc::new(Message::deleted("a:3", "lobby", "alice")),
+        })?;
+
+        let records = ChatLog::tail(&dir, 4, |record| record.room.is_some())?;
+        let state = State::new(Config {
+            server_id: "a".into(),
+            ..Config::default()
+        });
+        state.restore(records);
+        let history = state
+            .history(&["lobby".to_string()], None)
+            .iter()
+            .map(|message| message.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            history,
+            vec![
+                "#lobby [a:2 10:00:00] alice: 2",
+                "#lobby [a:4 10:00:00] alice: 4",
+                "#lobby [a:3] alice deleted the message"
+            ]
+        );
+        // 新消息的 ID 接在恢复的消息之后
+        assert_eq!(state.chat_message("lobby", "bob", "hi").id(), Some("a:5"));
+
+        assert!(ChatLog::tail(&dir.join("missing"), 3, |_| true)?.is_empty());
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_close_flushes_chat_log() -> Result<()> {
+        let dir = temp_dir();
+        let state = State::default();
+        let (tx, writer) = ChatLog::open(&dir, 1 << 20, Utc::now())?.spawn();
+        *state.log.lock().unwrap() = Some(tx);
+        *state.log_writer.lock().unwrap() = Some(writer);
+        let addr = "127.0.0.1:10000".parse()?;
+        for i in 0..100 {
+            let message = state.chat_message("lobby", "alice", &i.to_string());
+            state.persist(addr, &Arc::new(message));
+        }
+        // 关闭服务时等待写线程写完队列中的记录
+        state.close().await;
+        assert_eq!(ChatLog::tail(&dir, 1000, |_| true)?.len(), 100);
+        assert!(state.log.lock().unwrap().is_none());
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_accounts_register_and_verify() -> Result<()> {
+        let dir = temp_dir();
+        fs::create_dir_all(&dir)?;
+        let path = dir.join("users.toml");
+
+        let accounts = Accounts::load(&path)?;
+        accounts.register("alice", "secret")?;
+        assert!(accounts.register("alice", "other").is_err());
+        assert!(accounts.verify("alice", "secret").is_ok());
+
+        // 重新加载后密码仍然有效，且文件中不保存明文密码
+        let accounts = Accounts::load(&path)?;
+        assert!(accounts.verify("alice", "secret").is_ok());
+        let err = accounts.verify("alice", "wrong").unwrap_err();
+        assert!(err.is::<InvalidCredentials>());
+        assert!(accounts.verify("bob", "secret").is_err());
+        assert!(!fs::read_to_string(&path)?.contains("secret"));
+
+        // 相同的密码使用不同的盐
+        let a = Account::new("secret");
+        let b = Account::new("secret");
+        assert_ne!(a.password_hash, b.password_hash);
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_moderation_commands() {
+        assert_eq!(
+            Command::parse("/ban bob 10m").unwrap(),
+            Command::Ban {
+                target: "bob".into(),
+                duration: Some(600)
+            }
+        );
+        assert_eq!(
+            Command::parse("/ban 10.0.0.1").unwrap(),
+            Command::Ban {
+                target: "10.0.0.1".into(),
+                duration: None
+            }
+        );
+        assert_eq!(
+            Command::parse("/mute bob 30").unwrap(),
+            Command::Mute {
+                username: "bob".into(),
+                duration: Some(30)
+            }
+        );
+        assert!(Command::parse("/mute bob 5x").is_err());
+        // JSON 协议中的字段名为 duration
+        assert_eq!(
+            serde_json::from_str::<Command>(r#"{"type":"mute","username":"bob","duration":30}"#)
+                .unwrap(),
+            Command::Mute {
+                username: "bob".into(),
+                duration: Some(30)
+            }
+        );
+        assert!(Command::parse("/kick").is_err());
+        assert_eq!(parse_duration("2h").unwrap(), 7200);
+        assert_eq!(for_duration(Some(86400)), " for 1d");
+        assert_eq!(for_duration(Some(90)), " for 90s");
+    }
+
+    #[test]
+    fn test_bans_expire_and_persist() -> Result<()> {
+        let dir = temp_dir();
+        fs::create_dir_all(&dir)?;
+        let path = dir.join("bans.toml");
+        let now = Utc::now();
+
+        let bans = Bans::load(&path)?;
+        let user = BanTarget::User("bob".into());
+        let ip = BanTarget::Ip("10.0.0.1".parse()?);
+        let ban = |target: &BanTarget, until| Ban {
+            target: target.clone(),
+            by: "alice".into(),
+            until,
+        };
+        bans.ban(ban(&user, None), now)?;
+        bans.ban(ban(&ip, Some(now + Duration::from_secs(60))), now)?;
+        assert!(bans.find(&user, now).is_some());
+        assert!(bans.find(&ip, now).is_some());
+        // 封禁到期后失效
+        assert!(bans.find(&ip, now + Duration::from_secs(61)).is_none());
+
+        // 重新加载后封禁仍然有效
+        let bans = Bans::load(&path)?;
+        assert!(bans.find(&user, now).is_some());
+        assert!(bans.unban(&user, now)?);
+        assert!(!bans.unban(&user, now)?);
+        assert!(Bans::load(&path)?.find(&user, now).is_none());
+
+        // 没有匹配的封禁时不会写文件
+        let missing = dir.join("missing.toml");
+        assert!(!Bans::load(&missing)?.unban(&user, now)?);
+        assert!(!missing.exists());
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_mute_and_kick() {
+        let state = State::default();
+        let now = Utc::now();
+        state
+            .mutes
+            .insert("bob".into(), Some(now + Duration::from_secs(10)));
+        assert!(state.is_muted("bob", now));
+        assert!(!state.is_muted("bob", now + Duration::from_secs(10)));
+
+        let bob = add_outbox(&state, addr(1));
+        assert!(state.reserve_username("bob", addr(1)));
+        assert!(state.kick_user("bob", "You have been kicked").await);
+        assert!(bob.kicked.is_cancelled());
+        assert!(!bob.is_closed());
+        assert!(!state.kick_user("carol", "You have been kicked").await);
+        // 只能禁言在线或者已注册的用户
+        assert!(state.user_exists("bob"));
+        assert!(!state.user_exists("carol"));
+    }
+
+    #[test]
+    fn test_moderation_targets() {
+        let kick = Command::Kick {
+            username: "bob".into(),
+        };
+        assert_eq!(kick.required_role(), Role::Moderator);
+        assert_eq!(kick.moderation_target(), Some("bob"));
+        let unban = Command::Unban {
+            target: "10.0.0.1".into(),
+        };
+        assert_eq!(unban.required_role(), Role::Admin);
+        assert_eq!(unban.moderation_target(), None);
+        assert_eq!(Command::Who.required_role(), Role::User);
+
+        let state = State::default();
+        assert!(state.reserve_username("alice", addr(1)));
+        assert!(state.reserve_username("bob", SocketAddr::from(([10, 0, 0, 2], 1))));
+        assert_eq!(state.usernames_on(addr(1).ip()), vec!["alice".to_string()]);
+        assert!(state.usernames_on("10.0.0.3".parse().unwrap()).is_empty());
+    }
+
+    #[test]
+    fn test_who_lists_presence() {
+        let state = State::default();
+        let now = Utc::now();
+        for (port, username) in [(1, "bob"), (2, "alice")] {
+            assert!(state.reserve_username(username, addr(port)));
+            let presence = Presence {
+                connected_at: now,
+                last_active: now,
+            };
+            state.presence.insert(addr(port), presence);
+        }
+        state.touch(addr(1), now + chrono::Duration::seconds(5));
+
+        let who = state.who();
+        assert_eq!(who.len(), 2);
+        assert_eq!(who[0].0, "alice");
+        assert_eq!(who[1].0, "bob");
+        assert_eq!(
+            (who[1].1.last_active - who[1].1.connected_at).num_seconds(),
+            5
+        );
+
+        assert_eq!(Command::parse("/who").unwrap(), Command::Who);
+        assert_eq!(Command::parse("PONG").unwrap(), Command::Pong);
+        assert_eq!(format_elapsed(75), "1m15s");
+        assert_eq!(format_elapsed(7260), "2h1m");
+    }
+
+    #[tokio::test]
+    async fn test_heartbeat_drops_silent_peer() -> Result<()> {
+        let state = Arc::new(State::new(Config {
+            heartbeat_interval: 1,
+            heartbeat_timeout: 1,
+            ..Config::default()
+        }));
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let server_addr = listener.local_addr()?;
+        tokio::spawn(serve(state.clone(), listener));
+
+        // 登录后不再发送任何数据，应在心跳超时后被断开
+        let client = TcpStream::connect(server_addr).await?;
+        let mut client = Framed::new(client, LinesCodec::new());
+        client.send("alice").await?;
+        let lines = time::timeout(Duration::from_secs(5), client.collect::<Vec<_>>()).await?;
+        let lines = lines.into_iter().collect::<Result<Vec<_>, _>>()?;
+        assert!(lines.iter().any(|line| line == "PING"));
+        assert!(state.users.is_empty());
+        assert!(state.presence.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_login_timeout_uses_protocol() -> Result<()> {
+        let state = Arc::new(State::new(Config {
+            login_timeout: 1,
+            ..Config::default()
+        }));
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let server_addr = listener.local_addr()?;
+        tokio::spawn(serve(state.clone(), listener));
+
+        // 切换到 JSON 协议后不再登录，超时的提示也是 JSON
+        let client = TcpStream::connect(server_addr).await?;
+        let mut client = Framed::new(client, LinesCodec::new());
+        client.send("/json").await?;
+        let lines = time::timeout(Duration::from_secs(5), client.collect::<Vec<_>>()).await?;
+        let lines = lines.into_iter().collect::<Result<Vec<_>, _>>()?;
+        let last = lines.last().unwrap();
+        let value: serde_json::Value = serde_json::from_str(last)?;
+        assert_eq!(value["type"], "error");
+        assert_eq!(value["content"], "Login timed out");
+        Ok(())
+    }
+
+    #[test]
+    fn test_irc_protocol() {
+        let irc = Protocol::Irc;
+        assert_eq!(
+            IrcLine::parse(":alice!a@host privmsg #rust :hello there\r").unwrap(),
+            IrcLine {
+                command: "PRIVMSG".into(),
+                params: vec!["#rust", "hello there"],
+            }
+        );
+        assert!(IrcLine::parse("  ").is_none());
+
+        assert_eq!(
+            irc.decode("PRIVMSG #rust :hi all").unwrap(),
+            Command::Chat {
+                room: Some("rust".into()),
+                content: "hi all".into()
+            }
+        );
+        assert_eq!(
+            irc.decode("PRIVMSG bob :psst").unwrap(),
+            Command::Msg {
+                to: "bob".into(),
+                content: "psst".into()
+            }
+        );
+        assert_eq!(
+            irc.decode("JOIN #rust,#go").unwrap(),
+            Command::Join {
+                room: "rust".into()
+            }
+        );
+        assert_eq!(
+            irc.decode("PART #rust :bye").unwrap(),
+            Command::Leave {
+                room: Some("rust".into())
+            }
+        );
+        assert_eq!(irc.decode("QUIT :gone").unwrap(), Command::Exit);
+        assert!(irc.decode("JOIN").is_err());
+        assert!(irc.decode("USER a 0 * :A").is_err());
+
+        assert_eq!(
+            irc.encode(&Message::chat("rust", "alice", "hi")),
+            ":alice!alice@chat PRIVMSG #rust :hi"
+        );
+        assert_eq!(
+            irc.encode(&Message::user_renamed("alice", "al")),
+            ":alice!alice@chat NICK al"
+        );
+        assert_eq!(irc.encode(&Message::pong("x")), ":chat PONG chat :x");
+        assert_eq!(
+            irc.encode(&Message::names("rust", vec!["alice".into(), "bob".into()])),
+            ":chat 353 * = #rust :alice bob\n:chat 366 * #rust :End of /NAMES list"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_irc_and_text_clients_share_rooms() -> Result<()> {
+        let state = Arc::new(State::default());
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let irc_listener = TcpListener::bind("127.0.0.1:0").await?;
+        let text_addr = listener.local_addr()?;
+        let irc_addr = irc_listener.local_addr()?;
+        tokio::spawn(serve(state.clone(), listener));
+        tokio::spawn(accept(state.clone(), irc_listener, Protocol::Irc));
+
+        let mut irc = Framed::new(TcpStream::connect(irc_addr).await?, LinesCodec::new());
+        irc.send("NICK alice\r").await?;
+        irc.send("USER alice 0 * :Alice\r").await?;
+        assert!(irc.next().await.unwrap()?.starts_with(":chat 001 alice"));
+        assert_eq!(
+            irc.next().await.unwrap()?,
+            ":chat 422 alice :MOTD File is missing"
+        );
+        assert_eq!(irc.next().await.unwrap()?, ":alice!alice@chat JOIN #lobby");
+        assert_eq!(irc.next().await.unwrap()?, ":chat 353 * = #lobby :alice");
+        assert_eq!(
+            irc.next().await.unwrap()?,
+            ":chat 366 * #lobby :End of /NAMES list"
+        );
+
+        let mut text = Framed::new(TcpStream::connect(text_addr).await?, LinesCodec::new());
+        assert_eq!(text.next().await.unwrap()?, "Enter your username:");
+        text.send("bob").await?;
+        assert_eq!(irc.next().await.unwrap()?, ":bob!bob@chat JOIN #lobby");
+
+        text.send("hello irc").await?;
+        assert_eq!(
+            irc.next().await.unwrap()?,
+            ":bob!bob@chat PRIVMSG #lobby :hello irc"
+        );
+        // 没有等待心跳回复时 PONG 是普通的聊天消息
+        text.send("PONG").await?;
+        assert_eq!(
+            irc.next().await.unwrap()?,
+            ":bob!bob@chat PRIVMSG #lobby :PONG"
+        );
+        irc.send("PRIVMSG #lobby :hello text\r").await?;
+        loop {
+            let line = text.next().await.unwrap()?;
+            if line.ends_with("] alice: hello text") {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn remote_event(id: &str, origin: &str) -> FederationEvent {
+        FederationEvent {
+            id: id.into(),
+            origin: origin.into(),
+            message: Arc::new(Message::chat("lobby", "alice", "hi")),
+        }
+    }
+
+    #[test]
+    fn test_federation_dedups_and_never_loops() {
+        let federation = Federation::new("a".into());
+        let (b, mut b_events) = mpsc::channel(8);
+        let (c, mut c_events) = mpsc::channel(8);
+        federation.links.insert("b".into(), b);
+        federation.links.insert("c".into(), c);
+
+        // 从 b 收到的事件只转发给 c
+        assert!(federation.receive(&remote_event("1", "b"), "b"));
+        assert_eq!(c_events.try_recv().unwrap().id, "1");
+        assert!(b_events.try_recv().is_err());
+
+        // 重复的事件和自己产生的事件都不再处理
+        assert!(!federation.receive(&remote_event("1", "b"), "c"));
+        assert!(!federation.receive(&remote_event("2", "a"), "b"));
+        assert!(c_events.try_recv().is_err());
+
+        federation.publish(Arc::new(Message::chat("lobby", "bob", "yo")));
+        let event = b_events.try_recv().unwrap();
+        assert_eq!(event.origin, "a");
+        assert_eq!(c_events.try_recv().unwrap().id, event.id);
+        assert!(!federation.receive(&event, "c"));
+
+        let mut seen = SeenEvents::default();
+        for i in 0..=MAX_SEEN_EVENTS {
+            assert!(seen.insert(&i.to_string()));
+        }
+        // 最早的 ID 被淘汰后可以再次插入
+        assert!(seen.insert("0"));
+        assert!(!seen.insert("2"));
+    }
+
+    #[tokio::test]
+    async fn test_federated_servers_share_rooms() -> Result<()> {
+        let new_server = |server_id: &str| {
+            Arc::new(State::new(Config {
+                server_id: server_id.into(),
+                ..Config::default()
+            }))
+        };
+        let a = new_server("a");
+        let b = new_server("b");
+        let dir = temp_dir();
+        let (tx, writer) = ChatLog::open(&dir, 1 << 20, Utc::now())?.spawn();
+        *a.log.lock().unwrap() = Some(tx);
+        *a.log_writer.lock().unwrap() = Some(writer);
+        let a_listener = TcpListener::bind("127.0.0.1:0").await?;
+        let b_listener = TcpListener::bind("127.0.0.1:0").await?;
+        let federation_listener = TcpListener::bind("127.0.0.1:0").await?;
+        let (a_addr, b_addr) = (a_listener.local_addr()?, b_listener.local_addr()?);
+        let federation_addr = federation_listener.local_addr()?;
+        tokio::spawn(serve(a.clone(), a_listener));
+        tokio::spawn(serve(b.clone(), b_listener));
+        tokio::spawn(accept_peers(a.clone(), federation_listener));
+        tokio::spawn(dial_peer(b.clone(), federation_addr.to_string()));
+        while a.federation.links.is_empty() || b.federation.links.is_empty() {
+            time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let mut alice = Framed::new(TcpStream::connect(a_addr).await?, LinesCodec::new());
+        alice.send("alice").await?;
+        assert_eq!(alice.next().await.unwrap()?, "Enter your username:");
+        assert_eq!(
+            alice.next().await.unwrap()?,
+            "* You are now chatting in #lobby"
+        );
+
+        let mut bob = Framed::new(TcpStream::connect(b_addr).await?, LinesCodec::new());
+        bob.send("bob").await?;
+        assert_eq!(alice.next().await.unwrap()?, "[bob has joined #lobby]");
+        bob.send("hello from b").await?;
+        let line = alice.next().await.unwrap()?;
+        assert!(line.starts_with("#lobby [b:1 ") && line.ends_with("] bob: hello from b"));
+
+        // 两台服务器分配的消息 ID 不会重复
+        alice.send("hello from a").await?;
+        loop {
+            let line = bob.next().await.unwrap()?;
+            if line.ends_with("] alice: hello from a") {
+                assert!(line.starts_with("#lobby [a:1 "));
+                break;
+            }
+        }
+
+        // 其他服务器的消息也写入聊天日志，重启后可以恢复
+        a.close().await;
+        let records = ChatLog::tail(&dir, 100, |_| true)?;
+        assert!(records
+            .iter()
+            .any(|record| record.message.id() == Some("b:1")));
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_metrics() {
+        let state = state_with_policy(SlowConsumerPolicy::DropNew);
+        add_outbox(&state, addr(1));
+        state.join("lobby", addr(1));
+        for content in ["a", "b", "c"] {
+            let message = Arc::new(Message::chat("lobby", "bob", content));
+            state.broadcast("lobby", addr(2), message).await;
+        }
+        state.metrics.fan_out.observe(Duration::from_millis(20));
+
+        let text = state.metrics.render(state.peers.len());
+        assert!(text.contains("# TYPE chat_connected_peers gauge\nchat_connected_peers 1\n"));
+        assert!(text.contains("chat_messages_broadcast_total 3\n"));
+        assert!(text.contains("chat_send_failures_total{reason=\"dropped_new\"} 1\n"));
+        assert!(text.contains("chat_broadcast_fan_out_seconds_bucket{le=\"0.01\"} 3\n"));
+        assert!(text.contains("chat_broadcast_fan_out_seconds_bucket{le=\"0.05\"} 4\n"));
+        assert!(text.contains("chat_broadcast_fan_out_seconds_bucket{le=\"+Inf\"} 4\n"));
+        assert!(text.contains("chat_broadcast_fan_out_seconds_count 4\n"));
+    }
+
+    #[test]
+    fn test_builtin_plugins() {
+        let filter = ProfanityFilter::new(&["darn".to_string()], false);
+        assert_eq!(
+            filter.mask("Darn, it is darned DARN"),
+            "****, it is darned ****"
+        );
+        assert!(filter.on_chat("lobby", "alice", "all good").is_empty());
+        let filter = ProfanityFilter::new(&["darn".to_string()], true);
+        assert!(matches!(
+            &filter.on_chat("lobby", "alice", "darn")[..],
+            [PluginAction::Drop, PluginAction::Reply(_)]
+        ));
+
+        let actions = EchoBot.on_chat("lobby", "alice", "!echo  hello there ");
+        match &actions[..] {
+            [PluginAction::Emit(message)] => {
+                assert_eq!(without_time(message), "#lobby [] bot: hello there")
+            }
+            _ => panic!("unexpected actions: {:?}", actions),
+        }
+        assert!(EchoBot.on_chat("lobby", "alice", "!echoes").is_empty());
+        assert!(matches!(
+            &EchoBot.on_chat("lobby", "alice", "!echo")[..],
+            [PluginAction::Reply(_)]
+        ));
+
+        let actions = TimeBot.on_chat("lobby", "alice", "!time");
+        match &actions[..] {
+            [PluginAction::Emit(message)] => {
+                assert!(without_time(message).starts_with("#lobby [] bot: The time is "))
+            }
+            _ => panic!("unexpected actions: {:?}", actions),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_plugins_rewrite_emit_and_drop() {
+        let mut state = State::new(Config {
+            plugins: vec![PluginKind::Profanity, PluginKind::Echo],
+            profanity_words: vec!["darn".into()],
+            server_id: "a".into(),
+            ..Config::default()
+        });
+        let alice = add_outbox(&state, addr(1));
+        let bob = add_outbox(&state, addr(2));
+        state.join("lobby", addr(1));
+        state.join("lobby", addr(2));
+
+        // 插件按配置顺序调用，echo 收到的是过滤后的内容
+        let state = Arc::new(state);
+        broadcast_chat_message(&state, "lobby", "alice", "!echo darn".into(), addr(1), true).await;
+        assert_eq!(
+            without_time(&bob.pop().await.unwrap()),
+            "#lobby [a:1] alice: !echo ****"
+        );
+        assert_eq!(
+            without_time(&bob.pop().await.unwrap()),
+            "#lobby [a:2] bot: ****"
+        );
+        // 发送者只收到消息的 ID，不会再收到自己的消息
+        assert_eq!(
+            alice.pop().await.unwrap().to_string(),
+            "* Message #a:1 sent"
+        );
+        assert_eq!(
+            without_time(&alice.pop().await.unwrap()),
+            "#lobby [a:2] bot: ****"
+        );
+
+        // 消息被拦截后之后的插件不再被调用
+        let mut state = Arc::into_inner(state).unwrap();
+        state.plugins[0] = Box::new(ProfanityFilter::new(&["darn".into()], true));
+        let state = Arc::new(state);
+        broadcast_chat_message(&state, "lobby", "alice", "!echo darn".into(), addr(1), true).await;
+        assert_eq!(
+            alice.pop().await.unwrap().to_string(),
+            "! Your message was blocked by the profanity filter"
+        );
+        assert!(alice.queue.lock().unwrap().is_empty());
+        assert!(bob.queue.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_transfer_commands_and_frames() {
+        assert_eq!(
+            Command::parse("/send bob ~/My Files/report.pdf").unwrap(),
+            Command::Send {
+                to: "bob".into(),
+                path: "~/My Files/report.pdf".into()
+            }
+        );
+        assert!(Command::parse("/send bob").is_err());
+        assert_eq!(
+            Command::parse("/accept abc").unwrap(),
+            Command::Accept { id: "abc".into() }
+        );
+
+        let frames = [
+            TransferFrame::Hello("token".into()),
+            TransferFrame::Data(Bytes::from_static(b"\x00\x01binary")),
+            TransferFrame::End(blake3::hash(b"hello")),
+            TransferFrame::Error("too large".into()),
+        ];
+        for frame in frames {
+            let encoded = BytesMut::from(&frame.encode()[..]);
+            assert_eq!(TransferFrame::decode(encoded).unwrap(), frame);
+        }
+        assert!(TransferFrame::decode(BytesMut::from(&b"\x02short"[..])).is_err());
+        assert!(TransferFrame::decode(BytesMut::new()).is_err());
+
+        let message = Message::Transfer {
+            id: "abc".into(),
+            sender: "alice".into(),
+            recipient: "bob".into(),
+            name: "a.txt".into(),
+            status: TransferStatus::Ready {
+                addr: "127.0.0.1:3093".into(),
+                token: "t".into(),
+            },
+            timestamp: Utc::now(),
+        };
+        let json = serde_json::to_value(&message).unwrap();
+        assert_eq!(json["type"], "transfer");
+        assert_eq!(json["status"], "ready");
+        assert_eq!(json["token"], "t");
+    }
+
+    #[tokio::test]
+    async fn test_file_transfer() -> Result<()> {
+        async fn connect(addr: SocketAddr, token: &str) -> Result<TransferStream> {
+            let mut framed =
+                Framed::new(TcpStream::connect(addr).await?, LengthDelimitedCodec::new());
+            framed
+                .send(TransferFrame::Hello(token.to_string()).encode())
+                .await?;
+            Ok(framed)
+        }
+
+        let state = Arc::new(State::new(Config {
+            max_transfer_size: 1000,
+            ..Config::default()
+        }));
+        let alice = add_outbox(&state, addr(1));
+        let bob = add_outbox(&state, addr(2));
+        state.users.insert("alice".into(), addr(1));
+        state.users.insert("bob".into(), addr(2));
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let transfer_addr = listener.local_addr()?;
+        tokio::spawn(accept_transfers(state.clone(), listener));
+
+        // 只有接收者可以接受
+        let transfer = state.transfers.offer("alice", "bob", "a.bin".into());
+        assert!(state.transfers.accept(&transfer.id, "carol").is_err());
+        let (_, upload, download) = state.transfers.accept(&transfer.id, "bob")?;
+        assert!(state.transfers.accept(&transfer.id, "bob").is_err());
+
+        // 上传端先发送全部内容，下载端之后连接也能收到
+        let data = (0..=255u8).cycle().take(600).collect::<Vec<_>>();
+        let mut uploader = connect(transfer_addr, &upload).await?;
+        for chunk in data.chunks(256) {
+            let frame = TransferFrame::Data(Bytes::copy_from_slice(chunk));
+            uploader.send(frame.encode()).await?;
+        }
+        let hash = blake3::hash(&data);
+        uploader.send(TransferFrame::End(hash).encode()).await?;
+        let ack = TransferFrame::decode(uploader.next().await.unwrap()?)?;
+        assert_eq!(ack, TransferFrame::End(hash));
+
+        let mut downloader = connect(transfer_addr, &download).await?;
+        let mut received = Vec::new();
+        loop {
+            match TransferFrame::decode(downloader.next().await.unwrap()?)? {
+                TransferFrame::Data(chunk) => received.extend_from_slice(&chunk),
+                frame => {
+                    assert_eq!(frame, TransferFrame::End(hash));
+                    break;
+                }
+            }
+        }
+        assert_eq!(received, data);
+        let completed = format!("[file] alice sent a.bin to bob: 600 bytes, blake3 {}", hash);
+        assert_eq!(alice.pop().await.unwrap().to_string(), completed);
+        assert_eq!(bob.pop().await.unwrap().to_string(), completed);
+
+        // token 只能使用一次
+        let mut reused = connect(transfer_addr, &download).await?;
+        let frame = TransferFrame::decode(reused.next().await.unwrap()?)?;
+        assert_eq!(frame, TransferFrame::Error("Invalid transfer token".into()));
+
+        // 超过大小限制时中止传输
+        let transfer = state.transfers.offer("alice", "bob", "big.bin".into());
+        let (_, upload, _) = state.transfers.accept(&transfer.id, "bob")?;
+        let mut uploader = connect(transfer_addr, &upload).await?;
+        for _ in 0..5 {
+            let frame = TransferFrame::Data(Bytes::from(vec![0; 256]));
+            uploader.send(frame.encode()).await?;
+        }
+        let frame = TransferFrame::decode(uploader.next().await.unwrap()?)?;
+        assert_eq!(
+            frame,
+            TransferFrame::Error("File is larger than 1000 bytes".into())
+        );
+        assert!(alice
+            .pop()
+            .await
+            .unwrap()
+            .to_string()
+            .ends_with("failed: File is larger than 1000 bytes"));
+        assert!(state.transfers.tokens.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_transfer_expires() -> Result<()> {
+        let state = Arc::new(State::new(Config {
+            transfer_timeout: 1,
+            ..Config::default()
+        }));
+        let alice = add_outbox(&state, addr(1));
+        state.users.insert("alice".into(), addr(1));
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let transfer_addr = listener.local_addr()?;
+        tokio::spawn(accept_transfers(state.clone(), listener));
+
+        // 下载端一直没有连接，已经上传的数据在超时后释放
+        let transfer = state.transfers.offer("alice", "bob", "a.txt".into());
+        let (transfer, upload, _) = state.transfers.accept(&transfer.id, "bob")?;
+        expire_transfer(&state, transfer.clone());
+        let mut uploader = Framed::new(
+            TcpStream::connect(transfer_addr).await?,
+            LengthDelimitedCodec::new(),
+        );
+        uploader.send(TransferFrame::Hello(upload).encode()).await?;
+        let hash = blake3::hash(b"hello");
+        uploader
+            .send(TransferFrame::Data(Bytes::from_static(b"hello")).encode())
+            .await?;
+        uploader.send(TransferFrame::End(hash).encode()).await?;
+        let ack = TransferFrame::decode(uploader.next().await.unwrap()?)?;
+        assert_eq!(ack, TransferFrame::End(hash));
+        assert!(alice
+            .pop()
+            .await
+            .unwrap()
+            .to_string()
+            .ends_with("failed: Transfer was not started in time"));
+        assert!(state.transfers.tokens.is_empty());
+        assert!(transfer.download.lock().unwrap().is_none());
+
+        // 上传端一直没有连接，下载端等待超时后收到错误帧
+        let transfer = state.transfers.offer("alice", "bob", "b.txt".into());
+        let (_, _, download) = state.transfers.accept(&transfer.id, "bob")?;
+        let mut downloader = Framed::new(
+            TcpStream::connect(transfer_addr).await?,
+            LengthDelimitedCodec::new(),
+        );
+        downloader
+            .send(TransferFrame::Hello(download).encode())
+            .await?;
+        let frame = time::timeout(Duration::from_secs(5), downloader.next()).await?;
+        let frame = TransferFrame::decode(frame.unwrap()?)?;
+        assert_eq!(frame, TransferFrame::Error("Upload stalled".into()));
+
+        // 用户离开时释放还没有开始的传输
+        let transfer = state.transfers.offer("alice", "bob", "c.txt".into());
+        let (transfer, _, _) = state.transfers.accept(&transfer.id, "bob")?;
+        state.transfers.forget("bob");
+        assert!(!state.transfers.unclaimed(&transfer.id));
+        assert!(transfer.upload.lock().unwrap().is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_edit_and_delete_messages() -> Result<()> {
+        assert_eq!(
+            Command::parse("/edit 3 fixed  typo").unwrap(),
+            Command::Edit {
+                id: "3".into(),
+                content: "fixed  typo".into()
+            }
+        );
+        assert!(Command::parse("/edit 3").is_err());
+        assert!(Command::parse("/delete").is_err());
+
+        let state = Arc::new(State::new(Config {
+            edit_window: 2,
+            server_id: "a".into(),
+            ..Config::default()
+        }));
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(serve(state.clone(), listener));
+
+        let mut alice = Framed::new(TcpStream::connect(addr).await?, LinesCodec::new());
+        alice.send("alice").await?;
+        let mut bob = Framed::new(TcpStream::connect(addr).await?, LinesCodec::new());
+        bob.send("bob").await?;
+        loop {
+            if alice.next().await.unwrap()? == "[bob has joined #lobby]" {
+                break;
+            }
+        }
+        while bob.next().await.unwrap()? != "* You are now chatting in #lobby" {}
+
+        // 发送者只收到消息的 ID，不会重复显示自己的消息
+        for content in ["one", "two", "three"] {
+            alice.send(content).await?;
+        }
+        for (id, content) in ["one", "two", "three"].iter().enumerate() {
+            assert_eq!(
+                alice.next().await.unwrap()?,
+                format!("* Message #a:{} sent", id + 1)
+            );
+            assert!(bob
+                .next()
+                .await
+                .unwrap()?
+                .ends_with(&format!("] alice: {}", content)));
+        }
+
+        // 只保留每个房间最近的 edit_window 条消息，也不能修改别人的消息
+        alice.send("/edit 1 uno").await?;
+        assert_eq!(
+            alice.next().await.unwrap()?,
+            "! No recent message a:1 of yours to change"
+        );
+        bob.send("/delete 2").await?;
+        assert_eq!(
+            bob.next().await.unwrap()?,
+            "! No recent message a:2 of yours to change"
+        );
+
+        alice.send("/edit 2 dos").await?;
+        assert!(bob.next().await.unwrap()?.ends_with("] alice edited: dos"));
+        assert!(alice
+            .next()
+            .await
+            .unwrap()?
+            .ends_with("] alice edited: dos"));
+        // 可以省略本服务器的 ID，也可以输入完整的 ID
+        alice.send("/delete a:3").await?;
+        assert_eq!(
+            bob.next().await.unwrap()?,
+            "#lobby [a:3] alice deleted the message"
+        );
+        assert_eq!(
+            alice.next().await.unwrap()?,
+            "#lobby [a:3] alice deleted the message"
+        );
+        alice.send("/delete 3").await?;
+        assert_eq!(
+            alice.next().await.unwrap()?,
+            "! No recent message a:3 of yours to change"
+        );
+
+        // 删除的消息不会再出现在历史消息中
+        let history = state
+            .history(&["lobby".to_string()], None)
+            .iter()
+            .map(|message| without_time(message))
+            .collect::<Vec<_>>();
+        assert!(history.contains(&"#lobby [a:2] alice: two".to_string()));
+        assert!(!history.iter().any(|line| line.contains("three")));
+        assert_eq!(
+            history.last().unwrap(),
+            "#lobby [a:3] alice deleted the message"
+        );
+        Ok(())
     }
 }
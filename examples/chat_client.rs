@@ -0,0 +1,1229 @@
+use anyhow::{anyhow, Result};
+use bytes::{BufMut, Bytes, BytesMut};
+use clap::Parser;
+use crossterm::{
+    cursor::MoveTo,
+    event::{Event, EventStream, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
+    execute, queue,
+    style::{Attribute, Color, Print, ResetColor, SetAttribute, SetForegroundColor},
+    terminal::{self, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen},
+};
+use futures::{SinkExt, StreamExt};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    env,
+    fs::{self, OpenOptions},
+    io::{self, BufRead, IsTerminal, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    sync::mpsc,
+    time,
+};
+use tokio_util::codec::{Framed, LengthDelimitedCodec, LinesCodec};
+
+// 通知服务端退出的命令
+const EXIT: &str = "exit!";
+// 第一次重连前等待的时间，之后每次翻倍
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+// 界面上保留的最大行数
+const MAX_LINES: usize = 1000;
+const USERNAME_PROMPT: &str = "Enter your username:";
+const PASSWORD_PROMPT: &str = "Enter your password:";
+// 登录完成后服务端发送的消息都带有前缀，登录阶段的提示没有
+const SYSTEM_PREFIX: &str = "* ";
+// 密码只从环境变量读取，避免出现在进程列表和 shell 历史中
+const PASSWORD_ENV: &str = "CHAT_PASSWORD";
+// 上传时每个数据帧的大小，不能超过服务端的限制
+const TRANSFER_CHUNK_SIZE: usize = 64 * 1024;
+// 传输端口上的帧类型，第一个字节为类型，和服务端一致
+const FRAME_HELLO: u8 = 0;
+const FRAME_DATA: u8 = 1;
+const FRAME_END: u8 = 2;
+const FRAME_ERROR: u8 = 3;
+
+// 服务端支持的命令及用法，用于 /help、补全和发送前的检查
+const COMMANDS: &[(&str, &str)] = &[
+    ("join", "/join <room>"),
+    ("leave", "/leave [room]"),
+    ("rooms", "/rooms"),
+    ("who", "/who"),
+    ("names", "/names [room]"),
+    ("msg", "/msg <username> <text>"),
+    ("nick", "/nick <username>"),
+    ("login", "/login <username> <password>"),
+    ("register", "/register <username> <password>"),
+    ("history", "/history [n]"),
+    ("edit", "/edit <id> <text>"),
+    ("delete", "/delete <id>"),
+    ("kick", "/kick <username>"),
+    ("ban", "/ban <username|ip> [duration]"),
+    ("unban", "/unban <username|ip>"),
+    ("mute", "/mute <username> [duration]"),
+    ("unmute", "/unmute <username>"),
+    ("send", "/send <username> <path>"),
+    ("accept", "/accept <id>"),
+    ("reject", "/reject <id>"),
+];
+
+// 只在客户端处理的命令
+const LOCAL_COMMANDS: &[(&str, &str)] =
+    &[("help", "/help"), ("clear", "/clear"), ("quit", "/quit")];
+
+#[derive(Debug, Parser)]
+#[command(name = "chat_client", version, author, about, long_about = None)]
+struct Config {
+    // 聊天服务的地址
+    #[arg(
+        long,
+        default_value = "127.0.0.1:3090",
+        help = "address of the chat server"
+    )]
+    addr: String,
+
+    // 指定后连接时自动登录，否则按服务端提示输入
+    // 密码设置在 CHAT_PASSWORD 环境变量中时自动回复，否则按提示输入
+    #[arg(long, help = "username to log in with")]
+    username: Option<String>,
+
+    // 接受的文件保存的目录
+    #[arg(
+        long,
+        default_value = ".",
+        help = "directory accepted files are saved to"
+    )]
+    download_dir: PathBuf,
+
+    // 命令历史文件，默认保存在用户目录下
+    #[arg(long, help = "file the input history is kept in")]
+    history_file: Option<PathBuf>,
+
+    #[arg(
+        long,
+        default_value_t = 1000,
+        help = "number of input lines kept in history"
+    )]
+    history_size: usize,
+
+    // 重连的最大间隔
+    #[arg(
+        long,
+        default_value_t = 30,
+        help = "maximum seconds to wait between reconnect attempts"
+    )]
+    max_backoff: u64,
+
+    // 标准输入或输出不是终端时自动进入脚本模式
+    #[arg(long, help = "read lines from stdin and print messages to stdout")]
+    script: bool,
+
+    // 脚本模式下每行之间的间隔，避免触发服务端的限流
+    #[arg(
+        long,
+        default_value_t = 0,
+        help = "milliseconds to wait between lines in script mode"
+    )]
+    interval: u64,
+}
+
+// 连接任务发送给界面的事件
+#[derive(Debug)]
+enum ClientEvent {
+    Connected,
+    Line(String),
+    Disconnected(String),
+    // 用户退出或不再重连，界面随之退出
+    Closed,
+}
+
+// 服务端消息的类型，根据文本协议的前缀区分，用于选择颜色
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum LineKind {
+    Chat,
+    Direct,
+    Event,
+    System,
+    Error,
+    History,
+    File,
+    // 客户端自己的提示
+    Local,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Prompt {
+    None,
+    Username,
+    Password,
+}
+
+// 记录用户名和加入的房间，重连后自动恢复，密码不会被保存
+#[derive(Debug)]
+struct Session {
+    username: Option<String>,
+    rooms: Vec<String>,
+    prompt: Prompt,
+    // 重连时已经发送了登录信息，忽略服务端的第一次用户名提示
+    replayed: bool,
+    // 登录完成前发送的命令会被当作密码，重连后等登录完成再重新加入房间
+    rejoin: bool,
+    // 发起传输的文件，按文件名记录本地路径，服务端准备好后上传
+    uploads: HashMap<String, PathBuf>,
+    // 已经接受的传输 ID，服务端准备好后下载
+    downloads: HashSet<String>,
+}
+
+// 服务端准备好传输后，客户端连接传输端口上传或下载文件
+#[derive(Debug, PartialEq)]
+struct TransferJob {
+    addr: String,
+    token: String,
+    name: String,
+    direction: Direction,
+}
+
+#[derive(Debug, PartialEq)]
+enum Direction {
+    Upload(PathBuf),
+    Download(PathBuf),
+}
+
+// 重连的等待时间按指数增长，连接成功后重置
+#[derive(Debug)]
+struct Backoff {
+    current: Duration,
+    max: Duration,
+}
+
+// 输入历史，按上下键浏览，登录命令和密码不会被记录
+#[derive(Debug)]
+struct History {
+    path: Option<PathBuf>,
+    lines: Vec<String>,
+    max: usize,
+    // 正在浏览的历史位置，等于 lines.len() 时表示正在编辑新的一行
+    cursor: usize,
+}
+
+// 上方显示消息，倒数第二行为状态栏，最后一行为输入框
+#[derive(Debug)]
+struct Screen {
+    lines: VecDeque<(LineKind, String)>,
+    input: Vec<char>,
+    // 光标在输入中的位置，按字符计算
+    cursor: usize,
+    // 向上翻看的行数
+    scroll: usize,
+    status: String,
+    // 输入密码时不显示内容，也不记录到历史
+    secret: bool,
+    history: History,
+}
+
+// 退出时恢复终端，发生 panic 时同样会执行
+struct TerminalGuard;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let config = Arc::new(Config::parse());
+    let interactive = !config.script && io::stdin().is_terminal() && io::stdout().is_terminal();
+    if interactive {
+        run_interactive(config).await
+    } else {
+        run_script(config).await
+    }
+}
+
+// 标准输入的每一行发送给服务端，收到的消息原样输出，输入结束后发送 exit! 并等待服务端关闭连接
+async fn run_script(config: Arc<Config>) -> Result<()> {
+    let (input, receiver) = mpsc::channel(16);
+    let (sender, mut events) = mpsc::channel(128);
+    tokio::spawn(connection(config.clone(), receiver, sender, false));
+
+    let interval = Duration::from_millis(config.interval);
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            if input.blocking_send(line).is_err() {
+                return;
+            }
+            thread::sleep(interval);
+        }
+        let _ = input.blocking_send(EXIT.to_string());
+    });
+
+    let mut stdout = io::stdout().lock();
+    let mut ret = Ok(());
+    while let Some(event) = events.recv().await {
+        match event {
+            ClientEvent::Connected => {}
+            ClientEvent::Line(line) => writeln!(stdout, "{}", line)?,
+            ClientEvent::Disconnected(reason) => ret = Err(anyhow!(reason)),
+            ClientEvent::Closed => break,
+        }
+    }
+    ret
+}
+
+async fn run_interactive(config: Arc<Config>) -> Result<()> {
+    let path = config.history_file.clone().or_else(|| {
+        std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".chat_client_history"))
+    });
+    let history = History::load(path, config.history_size);
+    let mut screen = Screen::new(history, format!("connecting to {}", config.addr));
+
+    let (input, receiver) = mpsc::channel(16);
+    let (sender, mut events) = mpsc::channel(128);
+    tokio::spawn(connection(config.clone(), receiver, sender, true));
+
+    let _guard = TerminalGuard::enter()?;
+    let mut stdout = io::stdout();
+    let mut keys = EventStream::new();
+    screen.draw(&mut stdout)?;
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Some(ClientEvent::Connected) => {
+                    screen.status = format!("connected to {}", config.addr);
+                }
+                Some(ClientEvent::Line(line)) => screen.receive(line),
+                Some(ClientEvent::Disconnected(reason)) => {
+                    screen.status = format!("disconnected: {}", reason);
+                }
+                Some(ClientEvent::Closed) | None => break,
+            },
+            key = keys.next() => match key {
+                Some(Ok(Event::Key(key))) if key.kind != KeyEventKind::Release => {
+                    match screen.handle_key(key) {
+                        KeyAction::None => {}
+                        KeyAction::Send(line) => {
+                            if input.send(line).await.is_err() {
+                                break;
+                            }
+                        }
+                        KeyAction::Quit => break,
+                    }
+                }
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Err(e.into()),
+                None => break,
+            },
+        }
+        screen.draw(&mut stdout)?;
+    }
+    Ok(())
+}
+
+// 连接服务端，断开后按指数退避重连，直到用户退出
+async fn connection(
+    config: Arc<Config>,
+    mut input: mpsc::Receiver<String>,
+    events: mpsc::Sender<ClientEvent>,
+    reconnect: bool,
+) {
+    let mut session = Session::new(config.username.as_deref());
+    let mut backoff = Backoff::new(Duration::from_secs(config.max_backoff));
+    loop {
+        let reason = match TcpStream::connect(&config.addr).await {
+            Ok(stream) => {
+                backoff.reset();
+                let _ = events.send(ClientEvent::Connected).await;
+                match run_session(stream, &config, &mut session, &mut input, &events).await {
+                    Ok(()) => break,
+                    Err(e) => e.to_string(),
+                }
+            }
+            Err(e) => e.to_string(),
+        };
+        if !reconnect {
+            let _ = events.send(ClientEvent::Disconnected(reason)).await;
+            break;
+        }
+        let delay = backoff.next();
+        let reason = format!("{}, reconnecting in {}s", reason, delay.as_secs());
+        let _ = events.send(ClientEvent::Disconnected(reason)).await;
+        time::sleep(delay).await;
+    }
+    let _ = events.send(ClientEvent::Closed).await;
+}
+
+// 转发输入和服务端消息，自动回复心跳，用户退出时返回 Ok
+async fn run_session(
+    stream: TcpStream,
+    config: &Config,
+    session: &mut Session,
+    input: &mut mpsc::Receiver<String>,
+    events: &mpsc::Sender<ClientEvent>,
+) -> Result<()> {
+    let mut framed = Framed::new(stream, LinesCodec::new());
+    if let Some(username) = session.replay() {
+        framed.send(username).await?;
+    }
+
+    let mut exiting = false;
+    loop {
+        tokio::select! {
+            line = input.recv(), if !exiting => {
+                let line = line.unwrap_or_else(|| EXIT.to_string());
+                exiting = line.trim() == EXIT;
+                session.record(&line);
+                framed.send(line).await?;
+            }
+            line = framed.next() => match line {
+                Some(Ok(line)) if line == "PING" => framed.send("PONG").await?,
+                Some(Ok(line)) => {
+                    session.observe(&line);
+                    for join in session.rejoin(&line) {
+                        framed.send(join).await?;
+                    }
+                    // 文件内容通过单独的连接传输，不阻塞聊天
+                    if let Some(job) = session.transfer(&line, config) {
+                        tokio::spawn(run_transfer(job, events.clone()));
+                    }
+                    // 配置了密码环境变量时自动回复，每次都重新读取，不保存在内存中
+                    match env::var(PASSWORD_ENV) {
+                        Ok(password) if line == PASSWORD_PROMPT => {
+                            session.record(&password);
+                            framed.send(password).await?;
+                        }
+                        _ => events.send(ClientEvent::Line(line)).await?,
+                    }
+                }
+                Some(Err(e)) => return Err(e.into()),
+                None if exiting => return Ok(()),
+                None => return Err(anyhow!("connection closed by server")),
+            },
+        }
+    }
+}
+
+// 传输结束后把结果作为一行 [file] 消息显示
+async fn run_transfer(job: TransferJob, events: mpsc::Sender<ClientEvent>) {
+    let ret = match &job.direction {
+        Direction::Upload(path) => upload(&job.addr, &job.token, path).await,
+        Direction::Download(path) => download(&job.addr, &job.token, path).await,
+    };
+    let line = match (&job.direction, ret) {
+        (Direction::Upload(_), Ok(size)) => {
+            format!("[file] uploaded {}: {} bytes", job.name, size)
+        }
+        (Direction::Download(path), Ok(size)) => {
+            format!(
+                "[file] saved {} to {}: {} bytes",
+                job.name,
+                path.display(),
+                size
+            )
+        }
+        (_, Err(e)) => format!("[file] transfer of {} failed: {}", job.name, e),
+    };
+    let _ = events.send(ClientEvent::Line(line)).await;
+}
+
+async fn connect_transfer(
+    addr: &str,
+    token: &str,
+) -> Result<Framed<TcpStream, LengthDelimitedCodec>> {
+    let stream = TcpStream::connect(addr).await?;
+    let mut framed = Framed::new(stream, LengthDelimitedCodec::new());
+    framed.send(frame(FRAME_HELLO, token.as_bytes())).await?;
+    Ok(framed)
+}
+
+// 分块上传文件，最后发送校验和，服务端回复结束帧表示收到完整的文件
+async fn upload(addr: &str, token: &str, path: &Path) -> Result<u64> {
+    let mut file = File::open(path).await?;
+    let mut framed = connect_transfer(addr, token).await?;
+    let mut hasher = blake3::Hasher::new();
+    let mut buf = vec![0; TRANSFER_CHUNK_SIZE];
+    let mut size = 0;
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        size += n as u64;
+        framed.send(frame(FRAME_DATA, &buf[..n])).await?;
+    }
+    framed
+        .send(frame(FRAME_END, hasher.finalize().as_bytes()))
+        .await?;
+    match framed.next().await {
+        Some(reply) => match split_frame(reply?)? {
+            (FRAME_END, _) => Ok(size),
+            (FRAME_ERROR, reason) => Err(anyhow!(String::from_utf8_lossy(&reason).into_owned())),
+            (kind, _) => Err(anyhow!("unexpected transfer frame type: {}", kind)),
+        },
+        None => Err(anyhow!("transfer connection closed")),
+    }
+}
+
+// 下载的内容先写入临时文件，校验通过后再改名，失败时删除
+async fn download(addr: &str, token: &str, path: &Path) -> Result<u64> {
+    if path.exists() {
+        return Err(anyhow!("{} already exists", path.display()));
+    }
+    let mut framed = connect_transfer(addr, token).await?;
+    let part = path.with_extension("part");
+    let mut file = File::create(&part).await?;
+    let ret = async {
+        let mut hasher = blake3::Hasher::new();
+        let mut size = 0;
+        while let Some(frame) = framed.next().await {
+            match split_frame(frame?)? {
+                (FRAME_DATA, chunk) => {
+                    hasher.update(&chunk);
+                    size += chunk.len() as u64;
+                    file.write_all(&chunk).await?;
+                }
+                (FRAME_END, hash) if hash[..] == hasher.finalize().as_bytes()[..] => {
+                    file.flush().await?;
+                    return Ok(size);
+                }
+                (FRAME_END, _) => return Err(anyhow!("checksum mismatch")),
+                (FRAME_ERROR, reason) => {
+                    return Err(anyhow!(String::from_utf8_lossy(&reason).into_owned()))
+                }
+                (kind, _) => return Err(anyhow!("unexpected transfer frame type: {}", kind)),
+            }
+        }
+        Err(anyhow!("transfer connection closed"))
+    }
+    .await;
+    match ret {
+        Ok(size) => {
+            fs::rename(&part, path)?;
+            Ok(size)
+        }
+        Err(e) => {
+            let _ = fs::remove_file(&part);
+            Err(e)
+        }
+    }
+}
+
+fn frame(kind: u8, payload: &[u8]) -> Bytes {
+    let mut buf = BytesMut::with_capacity(payload.len() + 1);
+    buf.put_u8(kind);
+    buf.extend_from_slice(payload);
+    buf.freeze()
+}
+
+fn split_frame(mut frame: BytesMut) -> Result<(u8, BytesMut)> {
+    if frame.is_empty() {
+        return Err(anyhow!("empty transfer frame"));
+    }
+    let payload = frame.split_off(1);
+    Ok((frame[0], payload))
+}
+
+// 服务端监听所有地址时，使用聊天服务的主机名连接传输端口
+fn transfer_addr(server: &str, addr: &str) -> String {
+    match addr.parse::<SocketAddr>() {
+        Ok(parsed) if parsed.ip().is_unspecified() => {
+            let host = server.rsplit_once(':').map_or(server, |(host, _)| host);
+            format!("{}:{}", host, parsed.port())
+        }
+        _ => addr.to_string(),
+    }
+}
+
+// 解析服务端的传输就绪消息，返回传输 ID、文件名、地址和 token
+fn parse_ready(line: &str) -> Option<(&str, &str, &str, &str)> {
+    let rest = line.strip_prefix("[file] transfer ")?;
+    let (id, rest) = rest.split_once(" of ")?;
+    let (rest, token) = rest.rsplit_once(" with token ")?;
+    let (name, addr) = rest.rsplit_once(" is ready on ")?;
+    Some((id, name, addr, token))
+}
+
+#[derive(Debug, PartialEq)]
+enum KeyAction {
+    None,
+    Send(String),
+    Quit,
+}
+
+// 检查服务端命令的名称和参数个数，避免把明显错误的命令发给服务端
+fn check_command(line: &str) -> Result<(), String> {
+    let Some(command) = line.trim().strip_prefix('/') else {
+        return Ok(());
+    };
+    let mut args = command.split_whitespace();
+    let name = args.next().unwrap_or_default();
+    let Some((_, usage)) = COMMANDS.iter().find(|(command, _)| *command == name) else {
+        return Err(format!("Unknown command /{}, try /help", name));
+    };
+    let required = usage.matches('<').count();
+    let args = args.collect::<Vec<_>>();
+    if args.len() < required {
+        return Err(format!("Usage: {}", usage));
+    }
+    // 接受后才会上传，提前检查文件是否存在
+    if name == "send" {
+        let path = command["send".len()..].trim_start()[args[0].len()..].trim();
+        if !Path::new(path).is_file() {
+            return Err(format!("No such file: {}", path));
+        }
+    }
+    Ok(())
+}
+
+// 补全命令名，有多个候选时补全到共同前缀
+fn complete(input: &str) -> Option<String> {
+    let prefix = input.strip_prefix('/')?;
+    if prefix.contains(char::is_whitespace) {
+        return None;
+    }
+    let candidates = COMMANDS
+        .iter()
+        .chain(LOCAL_COMMANDS)
+        .map(|(name, _)| *name)
+        .filter(|name| name.starts_with(prefix))
+        .collect::<Vec<_>>();
+    match candidates[..] {
+        [] => None,
+        [name] => Some(format!("/{} ", name)),
+        [first, ..] => {
+            let common = candidates.iter().fold(first.len(), |len, name| {
+                first
+                    .chars()
+                    .zip(name.chars())
+                    .take(len)
+                    .take_while(|(a, b)| a == b)
+                    .count()
+            });
+            Some(format!("/{}", &first[..common]))
+        }
+    }
+}
+
+impl Session {
+    fn new(username: Option<&str>) -> Self {
+        Self {
+            username: username.map(str::to_string),
+            rooms: Vec::new(),
+            prompt: Prompt::None,
+            replayed: false,
+            rejoin: false,
+            uploads: HashMap::new(),
+            downloads: HashSet::new(),
+        }
+    }
+
+    // 服务端准备好传输时，根据之前的 /send 或 /accept 决定上传还是下载
+    fn transfer(&mut self, line: &str, config: &Config) -> Option<TransferJob> {
+        let (id, name, addr, token) = parse_ready(line)?;
+        let direction = match self.downloads.remove(id) {
+            // 文件名来自对方，只取最后一部分，避免写到下载目录之外
+            true => Direction::Download(config.download_dir.join(Path::new(name).file_name()?)),
+            false => Direction::Upload(self.uploads.remove(name)?),
+        };
+        Some(TransferJob {
+            addr: transfer_addr(&config.addr, addr),
+            token: token.to_string(),
+            name: name.to_string(),
+            direction,
+        })
+    }
+
+    // 连接后先发送的内容，未登录过时由用户按提示输入
+    // 只发送用户名，服务端需要密码时会再次提示
+    fn replay(&mut self) -> Option<String> {
+        let username = self.username.clone()?;
+        self.replayed = true;
+        self.rejoin = !self.rooms.is_empty();
+        Some(username)
+    }
+
+    // 收到登录后的第一条系统消息时，重新加入之前的房间
+    fn rejoin(&mut self, line: &str) -> Vec<String> {
+        if !self.rejoin || !line.starts_with(SYSTEM_PREFIX) {
+            return Vec::new();
+        }
+        self.rejoin = false;
+        self.rooms
+            .iter()
+            .map(|room| format!("/join {}", room))
+            .collect()
+    }
+
+    // 根据服务端的提示判断下一行输入是用户名还是密码
+    fn observe(&mut self, line: &str) {
+        self.prompt = match line {
+            USERNAME_PROMPT if self.replayed => {
+                self.replayed = false;
+                Prompt::None
+            }
+            USERNAME_PROMPT => Prompt::Username,
+            PASSWORD_PROMPT => Prompt::Password,
+            _ => return,
+        };
+    }
+
+    fn record(&mut self, line: &str) {
+        let line = line.trim();
+        let mut words = line.split_whitespace();
+        match (self.prompt, words.next(), words.next()) {
+            (Prompt::Password, _, _) => {}
+            (Prompt::Username, Some("/login" | "/register"), Some(username)) => {
+                self.username = Some(username.to_string());
+            }
+            (Prompt::Username, Some(username), None) if !username.starts_with('/') => {
+                self.username = Some(username.to_string());
+            }
+            (Prompt::Username, _, _) => return,
+            (Prompt::None, Some("/join"), Some(room)) => {
+                let room = room.trim_start_matches('#').to_string();
+                if !self.rooms.contains(&room) {
+                    self.rooms.push(room);
+                }
+            }
+            (Prompt::None, Some("/leave"), room) => {
+                // 未指定房间时离开当前房间，这里按最后加入的房间处理
+                match room.map(|room| room.trim_start_matches('#')) {
+                    Some(room) => self.rooms.retain(|joined| joined != room),
+                    None => {
+                        self.rooms.pop();
+                    }
+                }
+            }
+            (Prompt::None, Some("/nick"), Some(username)) => {
+                self.username = Some(username.to_string());
+            }
+            // 路径中可以有空格，服务端只使用文件名
+            (Prompt::None, Some("/send"), Some(to)) => {
+                let path = line["/send".len()..].trim_start()[to.len()..].trim();
+                if let Some(name) = Path::new(path).file_name() {
+                    let name = name.to_string_lossy().into_owned();
+                    self.uploads.insert(name, PathBuf::from(path));
+                }
+            }
+            (Prompt::None, Some("/accept"), Some(id)) => {
+                self.downloads.insert(id.to_string());
+            }
+            _ => {}
+        }
+        self.prompt = Prompt::None;
+    }
+}
+
+impl Backoff {
+    fn new(max: Duration) -> Self {
+        Self {
+            current: INITIAL_BACKOFF,
+            max,
+        }
+    }
+
+    fn next(&mut self) -> Duration {
+        let delay = self.current;
+        self.current = (self.current * 2).min(self.max);
+        delay
+    }
+
+    fn reset(&mut self) {
+        self.current = INITIAL_BACKOFF;
+    }
+}
+
+impl History {
+    // 历史文件不存在或无法读取时从空历史开始
+    fn load(path: Option<PathBuf>, max: usize) -> Self {
+        let mut lines = path
+            .as_ref()
+            .and_then(|path| fs::read_to_string(path).ok())
+            .map(|content| content.lines().map(str::to_string).collect::<Vec<_>>())
+            .unwrap_or_default();
+        if lines.len() > max {
+            lines.drain(..lines.len() - max);
+            if let Some(path) = &path {
+                let _ = fs::write(path, lines.join("\n") + "\n");
+            }
+        }
+        Self {
+            path,
+            cursor: lines.len(),
+            lines,
+            max,
+        }
+    }
+
+    fn push(&mut self, line: &str) {
+        let skip = line.trim().is_empty()
+            || line.starts_with("/login ")
+            || line.starts_with("/register ")
+            || self.lines.last().is_some_and(|last| last == line);
+        if !skip {
+            self.lines.push(line.to_string());
+            if self.lines.len() > self.max {
+                self.lines.remove(0);
+            }
+            self.append(line);
+        }
+        self.cursor = self.lines.len();
+    }
+
+    fn append(&self, line: &str) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let file = OpenOptions::new().create(true).append(true).open(path);
+        if let Ok(mut file) = file {
+            let _ = writeln!(file, "{}", line);
+        }
+    }
+
+    fn prev(&mut self) -> Option<&str> {
+        self.cursor = self.cursor.checked_sub(1)?;
+        self.lines.get(self.cursor).map(String::as_str)
+    }
+
+    // 越过最新的一条后回到空的输入
+    fn next(&mut self) -> Option<&str> {
+        if self.cursor < self.lines.len() {
+            self.cursor += 1;
+        }
+        Some(self.lines.get(self.cursor).map_or("", String::as_str))
+    }
+}
+
+impl Screen {
+    fn new(history: History, status: String) -> Self {
+        Self {
+            lines: VecDeque::with_capacity(MAX_LINES),
+            input: Vec::new(),
+            cursor: 0,
+            scroll: 0,
+            status,
+            secret: false,
+            history,
+        }
+    }
+
+    // 进入密码输入后直到提交才恢复，期间收到其他消息也不会显示密码
+    fn receive(&mut self, line: String) {
+        if line == PASSWORD_PROMPT {
+            self.secret = true;
+        }
+        self.push(LineKind::classify(&line), line);
+    }
+
+    fn push(&mut self, kind: LineKind, line: String) {
+        if self.lines.len() == MAX_LINES {
+            self.lines.pop_front();
+        }
+        self.lines.push_back((kind, line));
+    }
+
+    fn handle_key(&mut self, key: KeyEvent) -> KeyAction {
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        match key.code {
+            KeyCode::Char('c' | 'd') if ctrl => return KeyAction::Quit,
+            KeyCode::Char('u') if ctrl => self.set_input(""),
+            KeyCode::Char(c) => {
+                self.input.insert(self.cursor, c);
+                self.cursor += 1;
+            }
+            KeyCode::Backspace if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.input.remove(self.cursor);
+            }
+            KeyCode::Delete if self.cursor < self.input.len() => {
+                self.input.remove(self.cursor);
+            }
+            KeyCode::Left => self.cursor = self.cursor.saturating_sub(1),
+            KeyCode::Right => self.cursor = (self.cursor + 1).min(self.input.len()),
+            KeyCode::Home => self.cursor = 0,
+            KeyCode::End => self.cursor = self.input.len(),
+            KeyCode::Up if !self.secret => {
+                if let Some(line) = self.history.prev().map(str::to_string) {
+                    self.set_input(&line);
+                }
+            }
+            KeyCode::Down if !self.secret => {
+                if let Some(line) = self.history.next().map(str::to_string) {
+                    self.set_input(&line);
+                }
+            }
+            KeyCode::PageUp => self.scroll = (self.scroll + 10).min(self.lines.len()),
+            KeyCode::PageDown => self.scroll = self.scroll.saturating_sub(10),
+            KeyCode::Tab => {
+                let input = self.input.iter().collect::<String>();
+                if let Some(completed) = complete(&input) {
+                    self.set_input(&completed);
+                }
+            }
+            KeyCode::Enter => return self.submit(),
+            _ => {}
+        }
+        KeyAction::None
+    }
+
+    // 本地命令直接处理，其余内容检查后发送给服务端
+    fn submit(&mut self) -> KeyAction {
+        let line = self.input.iter().collect::<String>();
+        self.set_input("");
+        self.scroll = 0;
+        if self.secret {
+            self.secret = false;
+            return KeyAction::Send(line);
+        }
+        self.history.push(&line);
+        match line.trim() {
+            "/quit" | EXIT => return KeyAction::Send(EXIT.to_string()),
+            "/clear" => self.lines.clear(),
+            "/help" => {
+                for (_, usage) in COMMANDS.iter().chain(LOCAL_COMMANDS) {
+                    self.push(LineKind::Local, usage.to_string());
+                }
+            }
+            _ => match check_command(&line) {
+                Ok(()) => return KeyAction::Send(line),
+                Err(e) => self.push(LineKind::Local, e),
+            },
+        }
+        KeyAction::None
+    }
+
+    fn set_input(&mut self, line: &str) {
+        self.input = line.chars().collect();
+        self.cursor = self.input.len();
+    }
+
+    // 每次都重绘整个屏幕，长行按终端宽度折行
+    fn draw(&self, out: &mut impl Write) -> io::Result<()> {
+        let (width, height) = terminal::size()?;
+        let width = width.max(1) as usize;
+        let rows = height.saturating_sub(2) as usize;
+
+        let mut wrapped = Vec::new();
+        for (kind, line) in &self.lines {
+            let chars = line.chars().collect::<Vec<_>>();
+            if chars.is_empty() {
+                wrapped.push((*kind, String::new()));
+            }
+            for chunk in chars.chunks(width) {
+                wrapped.push((*kind, chunk.iter().collect::<String>()));
+            }
+        }
+        let end = wrapped.len().saturating_sub(self.scroll);
+        let start = end.saturating_sub(rows);
+
+        queue!(out, Clear(ClearType::All))?;
+        for (row, (kind, line)) in wrapped[start..end].iter().enumerate() {
+            queue!(
+                out,
+                MoveTo(0, row as u16),
+                SetForegroundColor(kind.color()),
+                Print(line),
+                ResetColor
+            )?;
+        }
+
+        let status = format!(" {} ", self.status);
+        queue!(
+            out,
+            MoveTo(0, height.saturating_sub(2)),
+            SetAttribute(Attribute::Reverse),
+            Print(format!("{:<width$}", status, width = width)),
+            SetAttribute(Attribute::Reset)
+        )?;
+
+        // 输入超过一行时只显示光标附近的内容
+        let visible = width.saturating_sub(3);
+        let offset = self.cursor.saturating_sub(visible);
+        let input = match self.secret {
+            true => "*".repeat(self.input.len().min(visible)),
+            false => self.input.iter().skip(offset).take(visible).collect(),
+        };
+        queue!(
+            out,
+            MoveTo(0, height.saturating_sub(1)),
+            Print("> "),
+            Print(input),
+            MoveTo((self.cursor - offset + 2) as u16, height.saturating_sub(1))
+        )?;
+        out.flush()
+    }
+}
+
+impl LineKind {
+    fn classify(line: &str) -> Self {
+        match line {
+            _ if line.starts_with("* ") => Self::System,
+            _ if line.starts_with("! ") => Self::Error,
+            _ if line.starts_with("[DM]") => Self::Direct,
+            _ if line.starts_with("[history]") => Self::History,
+            _ if line.starts_with("[file]") => Self::File,
+            _ if line.starts_with('[') => Self::Event,
+            _ => Self::Chat,
+        }
+    }
+
+    fn color(&self) -> Color {
+        match self {
+            Self::Chat => Color::Reset,
+            Self::Direct => Color::Magenta,
+            Self::Event => Color::Green,
+            Self::System => Color::Cyan,
+            Self::Error => Color::Red,
+            Self::History => Color::DarkGrey,
+            Self::File => Color::Yellow,
+            Self::Local => Color::Blue,
+        }
+    }
+}
+
+impl TerminalGuard {
+    fn enter() -> Result<Self> {
+        terminal::enable_raw_mode()?;
+        execute!(io::stdout(), EnterAlternateScreen)?;
+        Ok(Self)
+    }
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        let _ = execute!(io::stdout(), LeaveAlternateScreen);
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(code: KeyCode) -> KeyEvent {
+        KeyEvent::new(code, KeyModifiers::NONE)
+    }
+
+    #[test]
+    fn test_classify() {
+        assert_eq!(LineKind::classify("* hello"), LineKind::System);
+        assert_eq!(LineKind::classify("! nope"), LineKind::Error);
+        assert_eq!(LineKind::classify("[DM] a -> b: hi"), LineKind::Direct);
+        assert_eq!(
+            LineKind::classify("[history] #lobby a: hi"),
+            LineKind::History
+        );
+        assert_eq!(LineKind::classify("[file] a offers x"), LineKind::File);
+        assert_eq!(
+            LineKind::classify("[alice has joined #lobby]"),
+            LineKind::Event
+        );
+        assert_eq!(LineKind::classify("#lobby alice: [hi]"), LineKind::Chat);
+    }
+
+    #[test]
+    fn test_check_command_and_complete() {
+        assert!(check_command("hello /join").is_ok());
+        assert!(check_command("/join lobby").is_ok());
+        assert!(check_command("/leave").is_ok());
+        assert_eq!(check_command("/join").unwrap_err(), "Usage: /join <room>");
+        assert_eq!(
+            check_command("/msg bob").unwrap_err(),
+            "Usage: /msg <username> <text>"
+        );
+        assert!(check_command("/dance").is_err());
+
+        assert_eq!(complete("/jo").as_deref(), Some("/join "));
+        assert_eq!(complete("/un").as_deref(), Some("/un"));
+        assert_eq!(complete("/unb").as_deref(), Some("/unban "));
+        assert_eq!(complete("/join lo"), None);
+        assert_eq!(complete("/xyz"), None);
+    }
+
+    #[test]
+    fn test_session_replay() {
+        let mut session = Session::new(None);
+        assert_eq!(session.replay(), None);
+
+        // 按提示输入用户名和密码后，重连时只发送用户名，不保存密码
+        session.observe(USERNAME_PROMPT);
+        session.record("alice");
+        session.observe(PASSWORD_PROMPT);
+        session.record("secret word");
+        session.record("/join rust");
+        session.record("/join #go");
+        session.record("/leave go");
+        assert_eq!(session.replay().as_deref(), Some("alice"));
+        assert!(!format!("{:?}", session).contains("secret"));
+
+        // 重连后服务端的用户名提示不会被当作新的登录
+        session.observe(USERNAME_PROMPT);
+        session.record("/join go");
+        assert_eq!(session.rooms, vec!["rust", "go"]);
+
+        // 登录完成前不发送加入房间的命令，避免被当作密码
+        session.replay();
+        session.observe(USERNAME_PROMPT);
+        session.observe(PASSWORD_PROMPT);
+        assert!(session.rejoin(PASSWORD_PROMPT).is_empty());
+        assert!(session.rejoin("Invalid username or password").is_empty());
+        assert_eq!(
+            session.rejoin("* You are now chatting in #lobby"),
+            vec!["/join rust", "/join go"]
+        );
+        assert!(session.rejoin("* You are now chatting in #go").is_empty());
+
+        let mut session = Session::new(Some("bob"));
+        session.record("/nick carol");
+        assert_eq!(session.replay().as_deref(), Some("carol"));
+        assert!(session
+            .rejoin("* You are now chatting in #lobby")
+            .is_empty());
+
+        let mut session = Session::new(None);
+        session.observe(USERNAME_PROMPT);
+        session.record("/login dave hunter2");
+        assert_eq!(session.replay().as_deref(), Some("dave"));
+    }
+
+    #[test]
+    fn test_session_transfers() {
+        let config = Config::parse_from(["chat_client", "--addr", "chat.example.com:3090"]);
+        let mut session = Session::new(Some("alice"));
+        session.record("/send bob ~/My Files/report.pdf");
+        session.record("/accept x1");
+        assert_eq!(
+            parse_ready("[file] transfer x1 of a b.txt is ready on 1.2.3.4:3093 with token t"),
+            Some(("x1", "a b.txt", "1.2.3.4:3093", "t"))
+        );
+        assert_eq!(parse_ready("[file] alice offers a.txt to bob"), None);
+
+        // 发起的传输上传本地文件，监听所有地址时改用聊天服务的主机
+        let job = session.transfer(
+            "[file] transfer y2 of report.pdf is ready on 0.0.0.0:3093 with token up",
+            &config,
+        );
+        assert_eq!(
+            job,
+            Some(TransferJob {
+                addr: "chat.example.com:3093".into(),
+                token: "up".into(),
+                name: "report.pdf".into(),
+                direction: Direction::Upload("~/My Files/report.pdf".into()),
+            })
+        );
+        // 接受的传输下载到下载目录，文件名中的路径被去掉
+        let job = session
+            .transfer(
+                "[file] transfer x1 of ../x.txt is ready on 10.0.0.1:3093 with token down",
+                &config,
+            )
+            .unwrap();
+        assert_eq!(job.addr, "10.0.0.1:3093");
+        assert_eq!(job.direction, Direction::Download("./x.txt".into()));
+        // 每个传输只处理一次
+        assert!(session
+            .transfer(
+                "[file] transfer x1 of x.txt is ready on 10.0.0.1:3093 with token down",
+                &config,
+            )
+            .is_none());
+        assert!(check_command("/send bob /no/such/file").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_upload_and_download() -> Result<()> {
+        // 模拟服务端，把上传的帧原样转发给下载端
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?.to_string();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await?;
+            let mut uploader = Framed::new(stream, LengthDelimitedCodec::new());
+            let mut frames = Vec::new();
+            while let Some(frame) = uploader.next().await {
+                let frame = frame?;
+                let end = frame[0] == FRAME_END;
+                frames.push(frame.freeze());
+                if end {
+                    uploader.send(frames.last().unwrap().clone()).await?;
+                    break;
+                }
+            }
+            let (stream, _) = listener.accept().await?;
+            let mut downloader = Framed::new(stream, LengthDelimitedCodec::new());
+            downloader.next().await;
+            for frame in frames.into_iter().skip(1) {
+                downloader.send(frame).await?;
+            }
+            Ok::<_, anyhow::Error>(())
+        });
+
+        let dir = env::temp_dir().join(format!("chat-client-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        let source = dir.join("source.bin");
+        let data = (0..=255u8).cycle().take(200_000).collect::<Vec<_>>();
+        fs::write(&source, &data)?;
+        assert_eq!(upload(&addr, "up", &source).await?, 200_000);
+        let target = dir.join("target.bin");
+        assert_eq!(download(&addr, "down", &target).await?, 200_000);
+        assert_eq!(fs::read(&target)?, data);
+        // 不会覆盖已有的文件
+        assert!(download(&addr, "down", &target).await.is_err());
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_backoff() {
+        let mut backoff = Backoff::new(Duration::from_secs(5));
+        let delays = (0..5).map(|_| backoff.next().as_secs()).collect::<Vec<_>>();
+        assert_eq!(delays, vec![1, 2, 4, 5, 5]);
+        backoff.reset();
+        assert_eq!(backoff.next(), INITIAL_BACKOFF);
+    }
+
+    #[test]
+    fn test_screen_input_and_history() {
+        let mut screen = Screen::new(History::load(None, 2), String::new());
+        for c in "/jo".chars() {
+            screen.handle_key(key(KeyCode::Char(c)));
+        }
+        screen.handle_key(key(KeyCode::Tab));
+        for c in "lobby".chars() {
+            screen.handle_key(key(KeyCode::Char(c)));
+        }
+        assert_eq!(
+            screen.handle_key(key(KeyCode::Enter)),
+            KeyAction::Send("/join lobby".into())
+        );
+
+        // 密码不会显示在历史中
+        screen.receive(PASSWORD_PROMPT.into());
+        screen.handle_key(key(KeyCode::Char('x')));
+        // 输入密码时收到其他消息，仍然保持隐藏
+        screen.receive("#lobby [3 10:00:00] bob: hi".into());
+        assert!(screen.secret);
+        assert_eq!(
+            screen.handle_key(key(KeyCode::Enter)),
+            KeyAction::Send("x".into())
+        );
+        screen.handle_key(key(KeyCode::Char('/')));
+        screen.handle_key(key(KeyCode::Enter));
+        assert_eq!(screen.lines.back().unwrap().0, LineKind::Local);
+
+        screen.handle_key(key(KeyCode::Up));
+        screen.handle_key(key(KeyCode::Up));
+        assert_eq!(screen.input.iter().collect::<String>(), "/join lobby");
+        screen.handle_key(key(KeyCode::Down));
+        screen.handle_key(key(KeyCode::Down));
+        assert!(screen.input.is_empty());
+        assert_eq!(
+            screen.handle_key(KeyEvent::new(KeyCode::Char('c'), KeyModifiers::CONTROL)),
+            KeyAction::Quit
+        );
+    }
+}
@@ -1,25 +1,52 @@
-use std::fmt;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt,
+    fs::{self, File, OpenOptions},
+    future::Future,
+    io::{self, BufRead, BufReader, Write},
+    net::{IpAddr, SocketAddr},
+    path::{Path as FsPath, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
 
 use anyhow::Result;
 use axum::{
-    extract::{Path, State},
+    extract::{ConnectInfo, Path, State},
     response::{IntoResponse, Response},
     routing::{get, post},
     Json, Router,
 };
-use clap::Parser;
-use http::{header::LOCATION, HeaderMap, StatusCode};
+use chrono::{DateTime, NaiveDate, Utc};
+use clap::{Parser, ValueEnum};
+use dashmap::{mapref::entry::Entry, DashMap};
+use http::{
+    header::{AUTHORIZATION, LOCATION, REFERER, USER_AGENT},
+    HeaderMap, StatusCode,
+};
 use nanoid::nanoid;
 use serde::{Deserialize, Serialize};
 use sqlx::{postgres::PgPoolOptions, FromRow, PgPool};
 use thiserror::Error;
-use tokio::net::TcpListener;
+use tokio::{fs as async_fs, net::TcpListener, sync::mpsc, task};
 use tracing::{info, level_filters::LevelFilter, warn};
 use tracing_subscriber::{fmt::Layer, layer::SubscriberExt, util::SubscriberInitExt, Layer as _};
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
 struct ShortenReq {
     url: String,
+    // 自定义的短链接 ID，不指定时随机生成
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    alias: Option<String>,
+    // 过期时间，之后访问返回 410
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at: Option<DateTime<Utc>>,
+    // 最多可以访问的次数，用完后访问返回 410
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_clicks: Option<i64>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -27,34 +54,268 @@ struct ShortenRes {
     short_url: String,
 }
 
-#[derive(Debug, Clone)]
-struct AppState {
-    // Pool is an Arc<PoolInner<DB>> , 因此可以使用 Clone
-    // pub struct Pool<DB: Database>(pub(crate) Arc<PoolInner<DB>>);
-    db: PgPool,
+#[derive(Debug)]
+struct AppState<S> {
+    // 存储后端由命令行参数选择，多个请求共享同一个实例
+    store: Arc<S>,
+    // 访问记录交给后台任务写入，不增加跳转的延迟
+    clicks: mpsc::Sender<ClickEvent>,
+    // 客户端 IP 和盐一起哈希后保存
+    ip_salt: Arc<str>,
+    // 列出和删除链接需要的 token，没有设置时这两个接口不可用
+    admin_token: Option<Arc<str>>,
+}
+
+// 一次跳转的访问记录
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct ClickEvent {
+    id: String,
+    timestamp: DateTime<Utc>,
+    referrer: Option<String>,
+    user_agent: Option<String>,
+    ip_hash: String,
+}
+
+// 一个链接的访问统计，只保存计数，不随访问次数增长
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+struct ClickStats {
+    id: String,
+    total: u64,
+    per_day: BTreeMap<NaiveDate, u64>,
+    referrers: HashMap<String, u64>,
+    user_agents: HashMap<String, u64>,
+    // 独立访客的 IP 哈希
+    visitors: HashSet<String>,
+}
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+struct LinkStats {
+    id: String,
+    total_clicks: u64,
+    unique_visitors: u64,
+    // 按 UTC 日期统计
+    clicks_per_day: BTreeMap<NaiveDate, u64>,
+    top_referrers: Vec<TopEntry>,
+    top_user_agents: Vec<TopEntry>,
 }
 
-#[derive(Debug, FromRow)]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct TopEntry {
+    name: String,
+    clicks: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, FromRow, Serialize, Deserialize)]
 struct UrlRecord {
     #[sqlx(default)]
     id: String,
     #[sqlx(default)]
     url: String,
+    // 是否为自定义的 ID，随机 ID 才会被相同的 URL 复用
+    #[sqlx(default)]
+    #[serde(default)]
+    alias: bool,
+    #[sqlx(default)]
+    #[serde(default)]
+    expires_at: Option<DateTime<Utc>>,
+    #[sqlx(default)]
+    #[serde(default)]
+    max_clicks: Option<i64>,
+    // 已经访问的次数，没有 max_clicks 的链接以访问记录的数量为准
+    #[sqlx(default)]
+    #[serde(default)]
+    clicks: i64,
 }
 
 #[derive(Error, Debug)]
 pub enum ShortenerError {
     #[error("database error")]
     DatabaseError(#[from] sqlx::Error),
+    #[error("storage error")]
+    StorageError(#[from] io::Error),
     #[error("URL not found")]
     NotFound,
     #[error("Invalid URL: {0}")]
     InvalidUrl(String),
+    #[error("Invalid alias: {0}")]
+    InvalidAlias(String),
+    #[error("Alias already taken: {0}")]
+    AliasTaken(String),
+    #[error("Invalid limit: {0}")]
+    InvalidLimit(String),
+    #[error("URL has expired")]
+    Gone,
+    #[error("admin token required")]
+    Unauthorized,
+}
+
+// 自定义 ID 的长度范围和允许的字符
+const ALIAS_MIN_LEN: usize = 3;
+const ALIAS_MAX_LEN: usize = 32;
+// 保留给服务自身路由使用的 ID
+const RESERVED_ALIASES: [&str; 3] = ["api", "admin", "health"];
+
+// 等待写入的访问记录数量，超过后丢弃新的记录
+const CLICK_BUFFER: usize = 1024;
+// 每次最多批量写入的访问记录数量
+const CLICK_BATCH: usize = 100;
+// 统计中返回的 referrer 和 user agent 数量
+const TOP_STATS: usize = 10;
+// 数据文件追加的行数少于这个值时不重写
+const COMPACT_MIN_LINES: usize = 1000;
+// 随机生成的盐的长度
+const SALT_LEN: usize = 32;
+
+// 短链接的存储后端，不带 alias 和限制的请求中，同一个 URL 重复提交返回已有的随机 ID
+// alias 已被其他 URL 使用时返回 AliasTaken
+// 方法返回 Send 的 Future，axum 的 handler 才能在多线程运行时中使用
+trait UrlStore: Send + Sync + 'static {
+    fn shorten(
+        &self,
+        req: &ShortenReq,
+    ) -> impl Future<Output = Result<String, ShortenerError>> + Send;
+
+    fn lookup(&self, id: &str) -> impl Future<Output = Result<UrlRecord, ShortenerError>> + Send;
+
+    // 记录一次访问，过期或点击次数用完时返回 Gone
+    fn visit(&self, id: &str) -> impl Future<Output = Result<UrlRecord, ShortenerError>> + Send;
+
+    fn delete(&self, id: &str) -> impl Future<Output = Result<(), ShortenerError>> + Send;
+
+    fn list(&self) -> impl Future<Output = Result<Vec<UrlRecord>, ShortenerError>> + Send;
+
+    // 删除在 before 之前过期的链接，返回删除的数量
+    // 点击次数用完的链接不会被删除，访问时继续返回 Gone；访问记录也会保留
+    fn purge(
+        &self,
+        before: DateTime<Utc>,
+    ) -> impl Future<Output = Result<u64, ShortenerError>> + Send;
+
+    // 批量保存访问记录，已经删除的链接的记录会被忽略
+    fn record_clicks(
+        &self,
+        clicks: &[ClickEvent],
+    ) -> impl Future<Output = Result<(), ShortenerError>> + Send;
+
+    // 已经清理的链接仍然可以查询访问统计
+    fn stats(&self, id: &str) -> impl Future<Output = Result<LinkStats, ShortenerError>> + Send;
+
+    // 哈希客户端 IP 使用的盐，第一次使用时随机生成并和数据保存在一起
+    // 盐和访问记录一样需要长期不变，否则同一个访客会被统计成多个
+    fn ip_salt(&self) -> impl Future<Output = Result<String, ShortenerError>> + Send;
+}
+
+// 保存在 Postgres 中
+#[derive(Debug, Clone)]
+struct PgStore {
+    // Pool is an Arc<PoolInner<DB>> , 因此可以使用 Clone
+    // pub struct Pool<DB: Database>(pub(crate) Arc<PoolInner<DB>>);
+    db: PgPool,
+}
+
+// 只保存在内存中，重启后丢失
+#[derive(Debug, Default)]
+struct MemoryStore {
+    // ID -> 记录
+    urls: DashMap<String, UrlRecord>,
+    // URL -> 随机 ID，用于相同的 URL 返回相同的 ID，只包含没有限制的链接
+    ids: DashMap<String, String>,
+    // ID -> 访问统计，链接被清理后保留，ID 也不会再被使用
+    stats: DashMap<String, ClickStats>,
+}
+
+// 数据保存在内存中，每次修改都追加一行 JSON 到文件
+// 启动时重放文件恢复数据，然后只保留现有的记录重写文件，运行中在清理时重写
+#[derive(Debug)]
+struct FileStore {
+    memory: Arc<MemoryStore>,
+    path: PathBuf,
+    // 修改内存和追加文件在同一个锁内完成，文件中的顺序和内存一致
+    // 锁只在阻塞线程中持有，写文件不会占用异步运行时的线程
+    file: Arc<Mutex<DataFile>>,
+}
+
+#[derive(Debug)]
+struct DataFile {
+    file: File,
+    path: PathBuf,
+    // 上次重写时写入的行数，之后追加的行数超过它时再次重写
+    written: usize,
+    appended: usize,
+}
+
+// 数据文件中的一行
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum FileOp {
+    Put(UrlRecord),
+    Click { id: String },
+    Event(ClickEvent),
+    // 重写文件时用统计代替每一条访问记录
+    Stats(ClickStats),
+    Delete { id: String },
+    // 清理过期的链接，和 Delete 不同，访问记录会保留
+    Purge { id: String },
+}
+
+// 在存储前面加一层 LRU 缓存，ID 不存在的结果也会被缓存
+// 只缓存没有限制的链接，有限制的链接每次访问都需要在存储中计数
+// 命中缓存的访问不经过存储，这些链接的 clicks 由访问记录统计
+#[derive(Debug)]
+struct CachedStore<S> {
+    inner: S,
+    cache: Mutex<LruCache>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Debug)]
+struct LruCache {
+    capacity: usize,
+    ttl: Duration,
+    entries: HashMap<String, CacheEntry>,
+    // 使用序号 -> ID，第一项是最久未使用的
+    order: BTreeMap<u64, String>,
+    next: u64,
+    // 每次失效加一，回填前检查期间是否有失效
+    version: u64,
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+    // None 表示 ID 不存在
+    record: Option<UrlRecord>,
+    expires_at: Instant,
+    used: u64,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct CacheStats {
+    hits: u64,
+    misses: u64,
+    entries: usize,
+    capacity: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+enum StoreKind {
+    Postgres,
+    Memory,
+    File,
 }
 
 #[derive(Clone, Debug, Parser)]
 #[command(name="url", version, author, about, long_about = None)]
 struct Config {
+    // 存储后端
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = StoreKind::Postgres,
+        help = "where short URLs are stored"
+    )]
+    store: StoreKind,
+
     // 数据库连接
     #[arg(
         long,
@@ -63,9 +324,52 @@ struct Config {
     )]
     database_url: String,
 
+    // file 存储使用的数据文件
+    #[arg(
+        long,
+        default_value = "shortener.jsonl",
+        help = "data file of the file store"
+    )]
+    data_file: PathBuf,
+
     // 监听地址
     #[arg(long, default_value = "0.0.0.0:9876", help = "listen address")]
     listen_addr: String,
+
+    // 访问统计中客户端 IP 哈希使用的盐，不指定时使用存储中保存的随机盐
+    #[arg(
+        long,
+        help = "salt for hashing client IPs, a random one is stored with the data by default"
+    )]
+    ip_salt: Option<String>,
+
+    // 列出和删除链接的接口需要在 Authorization 中带上这个 token
+    #[arg(long, help = "bearer token for listing and deleting URLs")]
+    admin_token: Option<String>,
+
+    // 清理过期链接的间隔
+    #[arg(
+        long,
+        default_value_t = 60,
+        help = "seconds between purges of expired URLs"
+    )]
+    purge_interval: u64,
+
+    // 过期的链接在这段时间内仍然返回 410，之后才会被清理
+    #[arg(
+        long,
+        default_value_t = 86400,
+        help = "seconds an expired URL keeps answering 410 before it is purged"
+    )]
+    purge_grace: u64,
+
+    // 缓存的链接数量，0 表示不使用缓存
+    #[arg(long, default_value_t = 10000, help = "max URLs kept in the cache")]
+    cache_capacity: usize,
+
+    // 缓存的有效时间
+    #[arg(long, default_value_t = 60, help = "seconds a cached URL stays valid")]
+    cache_ttl: u64,
 }
 
 #[tokio::main]
@@ -75,49 +379,280 @@ async fn main() -> Result<()> {
 
     let config = Config::parse();
 
-    let database_url = config.database_url;
-    let state = AppState::try_new(&database_url).await?;
-    info!("Connected to database: {database_url}");
+    let listener = TcpListener::bind(&config.listen_addr).await?;
+    info!("Listening on: {}", config.listen_addr);
+
+    match config.store {
+        StoreKind::Postgres => {
+            let store = PgStore::try_new(&config.database_url).await?;
+            info!("Connected to database: {}", config.database_url);
+            serve(listener, store, config).await
+        }
+        StoreKind::Memory => serve(listener, MemoryStore::default(), config).await,
+        StoreKind::File => {
+            let path = config.data_file.clone();
+            let store = task::spawn_blocking(move || FileStore::open(&path)).await??;
+            info!(
+                "Loaded {} URLs from {}",
+                store.memory.urls.len(),
+                config.data_file.display()
+            );
+            serve(listener, store, config).await
+        }
+    }
+}
 
-    let listen_addr = config.listen_addr;
-    let listener = TcpListener::bind(&listen_addr).await?;
-    info!("Listening on: {}", listen_addr);
+async fn serve<S: UrlStore>(listener: TcpListener, store: S, config: Config) -> Result<()> {
+    let cache_ttl = Duration::from_secs(config.cache_ttl);
+    let store = CachedStore::new(store, config.cache_capacity, cache_ttl);
+    let ip_salt = match config.ip_salt {
+        Some(salt) => salt,
+        None => store.ip_salt().await?,
+    };
+    let (state, clicks) = AppState::new(store, &ip_salt, config.admin_token.as_deref());
+    tokio::spawn(record_clicks(state.store.clone(), clicks));
+    let purge_interval = Duration::from_secs(config.purge_interval);
+    let purge_grace = chrono::Duration::seconds(config.purge_grace as i64);
+    tokio::spawn(purge_expired(
+        state.store.clone(),
+        purge_interval,
+        purge_grace,
+    ));
 
     let app = Router::new()
-        .route("/", post(shorten))
-        .route("/:id", get(redirect))
-        .with_state((state, listen_addr)); // 将配置传递给应用状态
+        .route(
+            "/",
+            post(shorten::<CachedStore<S>>).get(list::<CachedStore<S>>),
+        )
+        .route("/api/stats", get(cache_stats::<S>))
+        .route(
+            "/:id",
+            get(redirect::<CachedStore<S>>).delete(delete::<CachedStore<S>>),
+        )
+        .route("/:id/stats", get(stats::<CachedStore<S>>))
+        .with_state((state, config.listen_addr)); // 将配置传递给应用状态
 
-    axum::serve(listener, app.into_make_service()).await?;
+    // 访问统计需要客户端地址
+    let service = app.into_make_service_with_connect_info::<SocketAddr>();
+    axum::serve(listener, service).await?;
 
     Ok(())
 }
 
-async fn shorten(
-    State((state, listen_addr)): State<(AppState, String)>,
-    Json(data): Json<ShortenReq>,
+// 定期清理过期超过 grace 的链接
+async fn purge_expired<S: UrlStore>(store: Arc<S>, period: Duration, grace: chrono::Duration) {
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        match store.purge(Utc::now() - grace).await {
+            Ok(0) => {}
+            Ok(n) => info!("Purged {n} expired URLs"),
+            Err(e) => warn!("Failed to purge expired URLs: {e}"),
+        }
+    }
+}
+
+// 批量写入访问记录，写入失败只影响统计，不影响跳转
+async fn record_clicks<S: UrlStore>(store: Arc<S>, mut clicks: mpsc::Receiver<ClickEvent>) {
+    let mut batch = Vec::with_capacity(CLICK_BATCH);
+    while clicks.recv_many(&mut batch, CLICK_BATCH).await > 0 {
+        if let Err(e) = store.record_clicks(&batch).await {
+            warn!("Failed to record {} clicks: {e}", batch.len());
+        }
+        batch.clear();
+    }
+}
+
+async fn shorten<S: UrlStore>(
+    State((state, listen_addr)): State<(AppState<S>, String)>,
+    Json(mut data): Json<ShortenReq>,
 ) -> Result<impl IntoResponse, ShortenerError> {
-    let id = state.shorten(&data.url).await.map_err(|e| {
+    // 保存规范化后的 URL，跳转时一定能作为 Location 头返回
+    data.url = validate_url(&data.url)?;
+    if let Some(alias) = &data.alias {
+        validate_alias(alias)?;
+    }
+    validate_limits(&data)?;
+    let id = state.store.shorten(&data).await.map_err(|e| {
         warn!("Failed to shorten URL: {e}");
-        ShortenerError::InvalidUrl(data.url)
+        e
     })?;
     let body = Json(ShortenRes {
-        short_url: format!("http://{}/{}", listen_addr, id.short_url),
+        short_url: format!("http://{}/{}", listen_addr, id),
     });
     Ok((StatusCode::CREATED, body))
 }
 
-async fn redirect(
+async fn redirect<S: UrlStore>(
     Path(id): Path<String>,
-    State((state, _)): State<(AppState, String)>,
+    State((state, _)): State<(AppState<S>, String)>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    req_headers: HeaderMap,
 ) -> Result<impl IntoResponse, ShortenerError> {
-    let url = state.get_url(&id).await?;
+    let url = state.store.visit(&id).await?;
+    let header = |name| {
+        req_headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string)
+    };
+    let click = ClickEvent {
+        id,
+        timestamp: Utc::now(),
+        referrer: header(REFERER),
+        user_agent: header(USER_AGENT),
+        ip_hash: hash_ip(&state.ip_salt, addr.ip()),
+    };
+    if let Err(e) = state.clicks.try_send(click) {
+        warn!("Dropping click: {e}");
+    }
+
     let mut headers = HeaderMap::new();
-    headers.insert(LOCATION, url.url.parse().unwrap());
-    Ok((StatusCode::PERMANENT_REDIRECT, headers))
+    // 旧版本保存的 URL 没有规范化，无法作为 Location 头时返回 500
+    let location = url.url.parse().map_err(|e| {
+        warn!("Invalid URL stored for {}: {e}", url.id);
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    })?;
+    headers.insert(LOCATION, location);
+    // 有限制的链接不能让浏览器缓存跳转
+    let status = match url.is_limited() {
+        true => StatusCode::TEMPORARY_REDIRECT,
+        false => StatusCode::PERMANENT_REDIRECT,
+    };
+    Ok((status, headers))
+}
+
+async fn delete<S: UrlStore>(
+    Path(id): Path<String>,
+    State((state, _)): State<(AppState<S>, String)>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, ShortenerError> {
+    state.authorize(&headers)?;
+    state.store.delete(&id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn stats<S: UrlStore>(
+    Path(id): Path<String>,
+    State((state, _)): State<(AppState<S>, String)>,
+) -> Result<impl IntoResponse, ShortenerError> {
+    Ok(Json(state.store.stats(&id).await?))
+}
+
+async fn cache_stats<S: UrlStore>(
+    State((state, _)): State<(AppState<CachedStore<S>>, String)>,
+) -> impl IntoResponse {
+    Json(state.store.cache_stats())
+}
+
+async fn list<S: UrlStore>(
+    State((state, _)): State<(AppState<S>, String)>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, ShortenerError> {
+    state.authorize(&headers)?;
+    Ok(Json(state.store.list().await?))
+}
+
+impl<S> AppState<S> {
+    fn new(
+        store: S,
+        ip_salt: &str,
+        admin_token: Option<&str>,
+    ) -> (Self, mpsc::Receiver<ClickEvent>) {
+        let (tx, rx) = mpsc::channel(CLICK_BUFFER);
+        let state = Self {
+            store: Arc::new(store),
+            clicks: tx,
+            ip_salt: ip_salt.into(),
+            admin_token: admin_token.map(Into::into),
+        };
+        (state, rx)
+    }
+
+    // 检查 Authorization: Bearer <token>，比较哈希使耗时与 token 内容无关
+    fn authorize(&self, headers: &HeaderMap) -> Result<(), ShortenerError> {
+        let token = headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        match (&self.admin_token, token) {
+            (Some(expected), Some(token))
+                if blake3::hash(expected.as_bytes()) == blake3::hash(token.as_bytes()) =>
+            {
+                Ok(())
+            }
+            _ => Err(ShortenerError::Unauthorized),
+        }
+    }
+}
+
+impl<S> Clone for AppState<S> {
+    fn clone(&self) -> Self {
+        Self {
+            store: self.store.clone(),
+            clicks: self.clicks.clone(),
+            ip_salt: self.ip_salt.clone(),
+            admin_token: self.admin_token.clone(),
+        }
+    }
+}
+
+impl UrlRecord {
+    fn is_limited(&self) -> bool {
+        self.expires_at.is_some() || self.max_clicks.is_some()
+    }
+
+    // 只有随机 ID 并且没有限制的链接才会被相同的 URL 复用
+    fn is_plain(&self) -> bool {
+        !self.alias && !self.is_limited()
+    }
+
+    fn is_gone(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+            || self
+                .max_clicks
+                .is_some_and(|max_clicks| self.clicks >= max_clicks)
+    }
+}
+
+impl ClickStats {
+    fn new(id: &str) -> Self {
+        Self {
+            id: id.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn add(&mut self, event: &ClickEvent) {
+        self.total += 1;
+        *self
+            .per_day
+            .entry(event.timestamp.date_naive())
+            .or_default() += 1;
+        if let Some(referrer) = &event.referrer {
+            *self.referrers.entry(referrer.clone()).or_default() += 1;
+        }
+        if let Some(user_agent) = &event.user_agent {
+            *self.user_agents.entry(user_agent.clone()).or_default() += 1;
+        }
+        self.visitors.insert(event.ip_hash.clone());
+    }
+}
+
+impl From<&ClickStats> for LinkStats {
+    fn from(stats: &ClickStats) -> Self {
+        Self {
+            id: stats.id.clone(),
+            total_clicks: stats.total,
+            unique_visitors: stats.visitors.len() as u64,
+            clicks_per_day: stats.per_day.clone(),
+            top_referrers: top_entries(&stats.referrers),
+            top_user_agents: top_entries(&stats.user_agents),
+        }
+    }
 }
 
-impl AppState {
+impl PgStore {
     async fn try_new(database_url: &str) -> Result<Self> {
         let db = PgPoolOptions::new()
             .max_connections(5)
@@ -128,25 +663,107 @@ impl AppState {
         sqlx::query(
             r#"
             CREATE TABLE IF NOT EXISTS urls (
-                id CHAR(6) PRIMARY KEY,
-                url TEXT NOT NULL UNIQUE
+                id VARCHAR(32) PRIMARY KEY,
+                url TEXT NOT NULL,
+                alias BOOLEAN NOT NULL DEFAULT FALSE,
+                expires_at TIMESTAMPTZ,
+                max_clicks BIGINT,
+                clicks BIGINT NOT NULL DEFAULT 0
             )
             "#,
         )
         .execute(&db)
         .await?;
 
+        // 迁移旧的表结构：ID 改为变长，URL 只在没有限制的随机 ID 中唯一
+        for sql in [
+            "ALTER TABLE urls ALTER COLUMN id TYPE VARCHAR(32)",
+            "ALTER TABLE urls ADD COLUMN IF NOT EXISTS alias BOOLEAN NOT NULL DEFAULT FALSE",
+            "ALTER TABLE urls ADD COLUMN IF NOT EXISTS expires_at TIMESTAMPTZ",
+            "ALTER TABLE urls ADD COLUMN IF NOT EXISTS max_clicks BIGINT",
+            "ALTER TABLE urls ADD COLUMN IF NOT EXISTS clicks BIGINT NOT NULL DEFAULT 0",
+            "ALTER TABLE urls DROP CONSTRAINT IF EXISTS urls_url_key",
+            "CREATE UNIQUE INDEX IF NOT EXISTS urls_url_plain_idx ON urls (url) WHERE NOT alias AND expires_at IS NULL AND max_clicks IS NULL",
+        ] {
+            sqlx::query(sql).execute(&db).await?;
+        }
+
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS clicks (
+                id BIGSERIAL PRIMARY KEY,
+                url_id VARCHAR(32) NOT NULL,
+                clicked_at TIMESTAMPTZ NOT NULL,
+                referrer TEXT,
+                user_agent TEXT,
+                ip_hash TEXT NOT NULL
+            )
+            "#,
+        )
+        .execute(&db)
+        .await?;
+        // 访问记录在清理链接后保留，不引用 urls 表
+        for sql in [
+            "CREATE INDEX IF NOT EXISTS clicks_url_id_idx ON clicks (url_id, clicked_at)",
+            "CREATE TABLE IF NOT EXISTS settings (name TEXT PRIMARY KEY, value TEXT NOT NULL)",
+        ] {
+            sqlx::query(sql).execute(&db).await?;
+        }
+
         Ok(Self { db })
     }
 
-    async fn shorten(&self, url: &str) -> Result<ShortenRes, ShortenerError> {
-        validate_url(url)?;
+    // 统计某一列中出现最多的值
+    async fn top_entries(&self, id: &str, column: &str) -> Result<Vec<TopEntry>, ShortenerError> {
+        let sql = format!(
+            "SELECT {column}, COUNT(*) FROM clicks WHERE url_id = $1 AND {column} IS NOT NULL GROUP BY {column} ORDER BY COUNT(*) DESC, {column} LIMIT $2"
+        );
+        let rows: Vec<(String, i64)> = sqlx::query_as(&sql)
+            .bind(id)
+            .bind(TOP_STATS as i64)
+            .fetch_all(&self.db)
+            .await?;
+        Ok(rows
+            .into_iter()
+            .map(|(name, clicks)| TopEntry {
+                name,
+                clicks: clicks as u64,
+            })
+            .collect())
+    }
+}
+
+impl UrlStore for PgStore {
+    async fn shorten(&self, req: &ShortenReq) -> Result<String, ShortenerError> {
+        if let Some(alias) = &req.alias {
+            let ret: Option<UrlRecord> = sqlx::query_as(
+                "INSERT INTO urls (id, url, alias, expires_at, max_clicks) SELECT $1, $2, TRUE, $3, $4 WHERE NOT EXISTS (SELECT 1 FROM clicks WHERE url_id = $1) ON CONFLICT(id) DO NOTHING RETURNING id",
+            )
+            .bind(alias)
+            .bind(&req.url)
+            .bind(req.expires_at)
+            .bind(req.max_clicks)
+            .fetch_optional(&self.db)
+            .await?;
+            return match ret {
+                Some(ret) => Ok(ret.id),
+                // 重复提交同一个 alias 和 URL 时返回已有的记录
+                None => match self.lookup(alias).await {
+                    Ok(record) if record.url == req.url => Ok(record.id),
+                    _ => Err(ShortenerError::AliasTaken(alias.to_string())),
+                },
+            };
+        }
+
         let mut id = nanoid!(6);
         loop {
-            let ret: Option<UrlRecord> = sqlx::query_as("SELECT id FROM urls WHERE id = $1")
-                .bind(&id)
-                .fetch_optional(&self.db)
-                .await?;
+            // 已经清理的链接保留了访问记录，它们的 ID 也不能再使用
+            let ret: Option<UrlRecord> = sqlx::query_as(
+                "SELECT id FROM urls WHERE id = $1 UNION ALL SELECT url_id FROM clicks WHERE url_id = $1 LIMIT 1",
+            )
+            .bind(&id)
+            .fetch_optional(&self.db)
+            .await?;
             match ret {
                 Some(_) => {
                     warn!("Collision on ID: {id}");
@@ -156,69 +773,1586 @@ impl AppState {
             }
         }
 
+        // 带有限制的链接不在唯一索引中，总是插入新的记录
         let ret:UrlRecord = sqlx::query_as(
-                    "INSERT INTO urls (id, url) VALUES ($1, $2) ON CONFLICT(url) DO UPDATE SET url=EXCLUDED.url RETURNING id",
+                    "INSERT INTO urls (id, url, expires_at, max_clicks) VALUES ($1, $2, $3, $4) ON CONFLICT(url) WHERE NOT alias AND expires_at IS NULL AND max_clicks IS NULL DO UPDATE SET url=EXCLUDED.url RETURNING id",
                 )
                 .bind(&id)
-                .bind(url)
+                .bind(&req.url)
+                .bind(req.expires_at)
+                .bind(req.max_clicks)
                 .fetch_one(&self.db)
                 .await?;
 
-        Ok(ShortenRes { short_url: ret.id })
+        Ok(ret.id)
     }
 
-    async fn get_url(&self, id: &str) -> Result<UrlRecord, ShortenerError> {
-        let ret = sqlx::query_as::<_, UrlRecord>("SELECT url FROM urls WHERE id = $1")
-            .bind(id)
-            .fetch_optional(&self.db)
-            .await?;
+    // 没有限制的链接不在 urls 中计数，点击次数来自 clicks 表
+    async fn lookup(&self, id: &str) -> Result<UrlRecord, ShortenerError> {
+        let ret = sqlx::query_as::<_, UrlRecord>(
+            "SELECT id, url, alias, expires_at, max_clicks, CASE WHEN max_clicks IS NULL THEN (SELECT COUNT(*) FROM clicks WHERE url_id = urls.id) ELSE clicks END AS clicks FROM urls WHERE id = $1",
+        )
+        .bind(id)
+        .fetch_optional(&self.db)
+        .await?;
 
         match ret {
             Some(url_record) => Ok(url_record),
             None => Err(ShortenerError::NotFound),
         }
     }
-}
 
-fn validate_url(url: &str) -> Result<(), ShortenerError> {
-    let parsed_url =
-        url::Url::parse(url).map_err(|_| ShortenerError::InvalidUrl(url.to_string()))?;
-    if !["http", "https"].contains(&parsed_url.scheme()) {
-        return Err(ShortenerError::InvalidUrl(url.to_string()));
+    async fn visit(&self, id: &str) -> Result<UrlRecord, ShortenerError> {
+        // 限制了点击次数的链接在同一条语句中检查和计数，并发访问也不会超过 max_clicks
+        let ret = sqlx::query_as::<_, UrlRecord>(
+            "UPDATE urls SET clicks = clicks + 1 WHERE id = $1 AND max_clicks IS NOT NULL AND (expires_at IS NULL OR expires_at > NOW()) AND clicks < max_clicks RETURNING id, url, alias, expires_at, max_clicks, clicks",
+        )
+        .bind(id)
+        .fetch_optional(&self.db)
+        .await?;
+        if let Some(url_record) = ret {
+            return Ok(url_record);
+        }
+
+        // 其他链接只需要检查是否过期，不写入 urls 表
+        let ret = sqlx::query_as::<_, UrlRecord>(
+            "SELECT id, url, alias, expires_at, max_clicks, clicks FROM urls WHERE id = $1 AND max_clicks IS NULL AND (expires_at IS NULL OR expires_at > NOW())",
+        )
+        .bind(id)
+        .fetch_optional(&self.db)
+        .await?;
+
+        match ret {
+            Some(url_record) => Ok(url_record),
+            None => {
+                self.lookup(id).await?;
+                Err(ShortenerError::Gone)
+            }
+        }
+    }
+
+    // 访问记录没有级联删除，删除链接时在同一个事务中删除
+    async fn delete(&self, id: &str) -> Result<(), ShortenerError> {
+        let mut tx = self.db.begin().await?;
+        let urls = sqlx::query("DELETE FROM urls WHERE id = $1")
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        let clicks = sqlx::query("DELETE FROM clicks WHERE url_id = $1")
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        match urls.rows_affected() + clicks.rows_affected() {
+            0 => Err(ShortenerError::NotFound),
+            _ => Ok(()),
+        }
+    }
+
+    async fn list(&self) -> Result<Vec<UrlRecord>, ShortenerError> {
+        let ret = sqlx::query_as(
+            "SELECT id, url, alias, expires_at, max_clicks, CASE WHEN max_clicks IS NULL THEN (SELECT COUNT(*) FROM clicks WHERE url_id = urls.id) ELSE clicks END AS clicks FROM urls ORDER BY id",
+        )
+        .fetch_all(&self.db)
+        .await?;
+        Ok(ret)
+    }
+
+    async fn purge(&self, before: DateTime<Utc>) -> Result<u64, ShortenerError> {
+        let ret = sqlx::query("DELETE FROM urls WHERE expires_at <= $1")
+            .bind(before)
+            .execute(&self.db)
+            .await?;
+        Ok(ret.rows_affected())
+    }
+
+    async fn record_clicks(&self, clicks: &[ClickEvent]) -> Result<(), ShortenerError> {
+        let ids = clicks.iter().map(|c| c.id.clone()).collect::<Vec<_>>();
+        let timestamps = clicks.iter().map(|c| c.timestamp).collect::<Vec<_>>();
+        let referrers = clicks
+            .iter()
+            .map(|c| c.referrer.clone())
+            .collect::<Vec<_>>();
+        let user_agents = clicks
+            .iter()
+            .map(|c| c.user_agent.clone())
+            .collect::<Vec<_>>();
+        let ip_hashes = clicks.iter().map(|c| c.ip_hash.clone()).collect::<Vec<_>>();
+        // 一条语句写入整批记录，跳过写入前已经被删除的链接
+        sqlx::query(
+            r#"
+            INSERT INTO clicks (url_id, clicked_at, referrer, user_agent, ip_hash)
+            SELECT c.url_id, c.clicked_at, c.referrer, c.user_agent, c.ip_hash
+            FROM UNNEST($1::VARCHAR[], $2::TIMESTAMPTZ[], $3::TEXT[], $4::TEXT[], $5::TEXT[])
+                AS c (url_id, clicked_at, referrer, user_agent, ip_hash)
+            WHERE EXISTS (SELECT 1 FROM urls WHERE urls.id = c.url_id)
+            "#,
+        )
+        .bind(ids)
+        .bind(timestamps)
+        .bind(referrers)
+        .bind(user_agents)
+        .bind(ip_hashes)
+        .execute(&self.db)
+        .await?;
+        Ok(())
+    }
+
+    async fn stats(&self, id: &str) -> Result<LinkStats, ShortenerError> {
+        let (total_clicks, unique_visitors): (i64, i64) = sqlx::query_as(
+            "SELECT COUNT(*), COUNT(DISTINCT ip_hash) FROM clicks WHERE url_id = $1",
+        )
+        .bind(id)
+        .fetch_one(&self.db)
+        .await?;
+        // 没有访问记录时再确认链接是否存在
+        if total_clicks == 0 {
+            self.lookup(id).await?;
+        }
+        let days: Vec<(NaiveDate, i64)> = sqlx::query_as(
+            "SELECT (clicked_at AT TIME ZONE 'UTC')::DATE AS day, COUNT(*) FROM clicks WHERE url_id = $1 GROUP BY day",
+        )
+        .bind(id)
+        .fetch_all(&self.db)
+        .await?;
+
+        Ok(LinkStats {
+            id: id.to_string(),
+            total_clicks: total_clicks as u64,
+            unique_visitors: unique_visitors as u64,
+            clicks_per_day: days
+                .into_iter()
+                .map(|(day, clicks)| (day, clicks as u64))
+                .collect(),
+            top_referrers: self.top_entries(id, "referrer").await?,
+            top_user_agents: self.top_entries(id, "user_agent").await?,
+        })
+    }
+
+    // 多个实例同时启动时只有第一个生成的盐会被保存
+    async fn ip_salt(&self) -> Result<String, ShortenerError> {
+        sqlx::query("INSERT INTO settings (name, value) VALUES ('ip_salt', $1) ON CONFLICT(name) DO NOTHING")
+            .bind(nanoid!(SALT_LEN))
+            .execute(&self.db)
+            .await?;
+        let (salt,): (String,) =
+            sqlx::query_as("SELECT value FROM settings WHERE name = 'ip_salt'")
+                .fetch_one(&self.db)
+                .await?;
+        Ok(salt)
     }
-    Ok(())
 }
 
-// Implement `IntoResponse` for `ShortenerError`.
-impl IntoResponse for ShortenerError {
-    fn into_response(self) -> Response {
-        let (status, error_message) = match &self {
-            ShortenerError::DatabaseError(_) => (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Database error occurred.",
-            ),
-            ShortenerError::NotFound => (StatusCode::NOT_FOUND, "URL parsing error occurred."),
-            ShortenerError::InvalidUrl(_) => {
-                (StatusCode::UNPROCESSABLE_ENTITY, "Failed to shorten URL.")
-            }
+impl MemoryStore {
+    // 返回请求对应的记录，以及是否为新插入的记录
+    fn insert(&self, req: &ShortenReq) -> Result<(UrlRecord, bool), ShortenerError> {
+        let record = UrlRecord {
+            id: String::new(),
+            url: req.url.clone(),
+            alias: req.alias.is_some(),
+            expires_at: req.expires_at,
+            max_clicks: req.max_clicks,
+            clicks: 0,
         };
-        let body = Json({
-            let mut map = std::collections::HashMap::new();
-            map.insert("error", error_message);
-            map
-        });
-        (status, body).into_response()
+        let Some(alias) = &req.alias else {
+            return match record.is_plain() {
+                true => Ok(self.insert_plain(record)),
+                false => Ok((self.insert_random(record), true)),
+            };
+        };
+        match self.urls.entry(alias.clone()) {
+            Entry::Occupied(entry) if entry.get().url == req.url => {
+                Ok((entry.get().clone(), false))
+            }
+            Entry::Occupied(_) => Err(ShortenerError::AliasTaken(alias.clone())),
+            Entry::Vacant(_) if self.stats.contains_key(alias) => {
+                Err(ShortenerError::AliasTaken(alias.clone()))
+            }
+            Entry::Vacant(entry) => {
+                let record = UrlRecord {
+                    id: alias.clone(),
+                    ..record
+                };
+                Ok((entry.insert(record).clone(), true))
+            }
+        }
     }
-}
 
-impl fmt::Display for ShortenRes {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.short_url)
+    // 相同的 URL 复用已有的随机 ID
+    fn insert_plain(&self, record: UrlRecord) -> (UrlRecord, bool) {
+        let entry = self.ids.entry(record.url.clone());
+        if let Entry::Occupied(entry) = &entry {
+            if let Some(existing) = self.urls.get(entry.get()) {
+                return (existing.clone(), false);
+            }
+        }
+        let record = self.insert_random(record);
+        entry.insert(record.id.clone());
+        (record, true)
     }
-}
+
+    fn insert_random(&self, record: UrlRecord) -> UrlRecord {
+        loop {
+            let id = nanoid!(6);
+            match self.urls.entry(id.clone()) {
+                Entry::Occupied(_) => warn!("Collision on ID: {id}"),
+                Entry::Vacant(_) if self.stats.contains_key(&id) => {
+                    warn!("Collision on ID: {id}")
+                }
+                Entry::Vacant(vacant) => break vacant.insert(UrlRecord { id, ..record }).clone(),
+            }
+        }
+    }
+
+    // 过期或点击次数用完时返回 Gone，限制了点击次数的链接计数加一
+    fn click(&self, id: &str, now: DateTime<Utc>) -> Result<UrlRecord, ShortenerError> {
+        let mut record = self.urls.get_mut(id).ok_or(ShortenerError::NotFound)?;
+        if record.is_gone(now) {
+            return Err(ShortenerError::Gone);
+        }
+        if record.max_clicks.is_some() {
+            record.clicks += 1;
+        }
+        Ok(record.clone())
+    }
+
+    // 没有限制的链接不单独计数，点击次数取访问统计的总数
+    fn counted(&self, mut record: UrlRecord) -> UrlRecord {
+        if record.max_clicks.is_none() {
+            record.clicks = self.stats.get(&record.id).map_or(0, |s| s.total as i64);
+        }
+        record
+    }
+
+    // 删除链接和访问记录，已经清理的链接只删除访问记录
+    fn remove(&self, id: &str) -> bool {
+        let removed = self.remove_link(id);
+        self.stats.remove(id).is_some() || removed
+    }
+
+    // 只删除链接，访问记录保留
+    fn remove_link(&self, id: &str) -> bool {
+        match self.urls.remove(id) {
+            Some((_, record)) => {
+                self.ids.remove_if(&record.url, |_, id| *id == record.id);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // 删除在 before 之前过期的链接，返回被删除的 ID
+    fn remove_expired(&self, before: DateTime<Utc>) -> Vec<String> {
+        let expired = self
+            .urls
+            .iter()
+            .filter(|record| record.expires_at.is_some_and(|at| at <= before))
+            .map(|record| record.id.clone())
+            .collect::<Vec<_>>();
+        expired
+            .into_iter()
+            .filter(|id| self.remove_link(id))
+            .collect()
+    }
+
+    // 链接存在时计入访问统计，读锁保证不会和删除交错
+    fn record(&self, event: &ClickEvent) -> bool {
+        match self.urls.get(&event.id) {
+            Some(_record) => {
+                self.add_click(event);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn add_click(&self, event: &ClickEvent) {
+        self.stats
+            .entry(event.id.clone())
+            .or_insert_with(|| ClickStats::new(&event.id))
+            .add(event);
+    }
+
+    fn link_stats(&self, id: &str) -> Result<LinkStats, ShortenerError> {
+        if let Some(stats) = self.stats.get(id) {
+            return Ok(LinkStats::from(&*stats));
+        }
+        match self.urls.contains_key(id) {
+            true => Ok(LinkStats::from(&ClickStats::new(id))),
+            false => Err(ShortenerError::NotFound),
+        }
+    }
+
+    fn apply(&self, op: FileOp) {
+        match op {
+            FileOp::Put(record) => {
+                if record.is_plain() {
+                    self.ids.insert(record.url.clone(), record.id.clone());
+                }
+                self.urls.insert(record.id.clone(), record);
+            }
+            FileOp::Click { id } => {
+                if let Some(mut record) = self.urls.get_mut(&id) {
+                    record.clicks += 1;
+                }
+            }
+            // 已经清理的链接的访问统计也要恢复
+            FileOp::Event(event) => self.add_click(&event),
+            FileOp::Stats(stats) => {
+                self.stats.insert(stats.id.clone(), stats);
+            }
+            FileOp::Delete { id } => {
+                self.remove(&id);
+            }
+            FileOp::Purge { id } => {
+                self.remove_link(&id);
+            }
+        }
+    }
+
+    fn records(&self) -> Vec<UrlRecord> {
+        let mut records = self
+            .urls
+            .iter()
+            .map(|record| record.value().clone())
+            .collect::<Vec<_>>();
+        records.sort_by(|a, b| a.id.cmp(&b.id));
+        records
+    }
+}
+
+impl UrlStore for MemoryStore {
+    async fn shorten(&self, req: &ShortenReq) -> Result<String, ShortenerError> {
+        Ok(self.insert(req)?.0.id)
+    }
+
+    async fn lookup(&self, id: &str) -> Result<UrlRecord, ShortenerError> {
+        let record = self.urls.get(id).map(|record| record.value().clone());
+        Ok(self.counted(record.ok_or(ShortenerError::NotFound)?))
+    }
+
+    async fn visit(&self, id: &str) -> Result<UrlRecord, ShortenerError> {
+        self.click(id, Utc::now())
+    }
+
+    async fn delete(&self, id: &str) -> Result<(), ShortenerError> {
+        match self.remove(id) {
+            true => Ok(()),
+            false => Err(ShortenerError::NotFound),
+        }
+    }
+
+    async fn list(&self) -> Result<Vec<UrlRecord>, ShortenerError> {
+        let records = self.records().into_iter();
+        Ok(records.map(|record| self.counted(record)).collect())
+    }
+
+    async fn purge(&self, before: DateTime<Utc>) -> Result<u64, ShortenerError> {
+        Ok(self.remove_expired(before).len() as u64)
+    }
+
+    async fn record_clicks(&self, clicks: &[ClickEvent]) -> Result<(), ShortenerError> {
+        for click in clicks {
+            self.record(click);
+        }
+        Ok(())
+    }
+
+    async fn stats(&self, id: &str) -> Result<LinkStats, ShortenerError> {
+        self.link_stats(id)
+    }
+
+    // 访问记录重启后丢失，盐也不需要保存
+    async fn ip_salt(&self) -> Result<String, ShortenerError> {
+        Ok(nanoid!(SALT_LEN))
+    }
+}
+
+impl FileStore {
+    fn open(path: &FsPath) -> Result<Self, ShortenerError> {
+        let memory = MemoryStore::default();
+        if path.exists() {
+            for line in BufReader::new(File::open(path)?).lines() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                match serde_json::from_str::<FileOp>(&line) {
+                    Ok(op) => memory.apply(op),
+                    Err(e) => warn!("Skipping invalid record in {}: {}", path.display(), e),
+                }
+            }
+        }
+
+        let file = Self::compact(&memory, path)?;
+        Ok(Self {
+            memory: Arc::new(memory),
+            path: path.to_path_buf(),
+            file: Arc::new(Mutex::new(file)),
+        })
+    }
+
+    // 在阻塞线程中持有文件锁修改内存和文件
+    async fn write<T, F>(&self, f: F) -> Result<T, ShortenerError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&MemoryStore, &mut DataFile) -> Result<T, ShortenerError> + Send + 'static,
+    {
+        let memory = self.memory.clone();
+        let file = self.file.clone();
+        task::spawn_blocking(move || f(&memory, &mut file.lock().unwrap()))
+            .await
+            .map_err(io::Error::other)?
+    }
+
+    // 只保留现有的记录重写文件，点击次数合并到记录中
+    // 先写入临时文件再替换，重写过程中退出也不会丢失数据
+    fn compact(memory: &MemoryStore, path: &FsPath) -> Result<DataFile, ShortenerError> {
+        let tmp = path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        let mut written = 0;
+        for record in memory.records() {
+            writeln!(file, "{}", encode(&FileOp::Put(record))?)?;
+            written += 1;
+        }
+        // 每个链接的访问记录合并为一行统计
+        for stats in memory.stats.iter() {
+            writeln!(file, "{}", encode(&FileOp::Stats(stats.value().clone()))?)?;
+            written += 1;
+        }
+        file.sync_all()?;
+        fs::rename(&tmp, path)?;
+
+        let file = OpenOptions::new().append(true).open(path)?;
+        Ok(DataFile {
+            file,
+            path: path.to_path_buf(),
+            written,
+            appended: 0,
+        })
+    }
+}
+
+impl DataFile {
+    fn append(&mut self, op: &FileOp) -> Result<(), ShortenerError> {
+        writeln!(self.file, "{}", encode(op)?)?;
+        self.appended += 1;
+        Ok(())
+    }
+
+    // 追加的内容比上次重写的内容还多时才重写，重写的开销分摊到每次追加
+    fn needs_compaction(&self) -> bool {
+        self.appended >= self.written.max(COMPACT_MIN_LINES)
+    }
+}
+
+impl UrlStore for FileStore {
+    async fn shorten(&self, req: &ShortenReq) -> Result<String, ShortenerError> {
+        let req = req.clone();
+        self.write(move |memory, file| {
+            let (record, inserted) = memory.insert(&req)?;
+            if inserted {
+                if let Err(e) = file.append(&FileOp::Put(record.clone())) {
+                    // 没有写入文件的记录不应该被使用
+                    memory.remove(&record.id);
+                    return Err(e);
+                }
+            }
+            Ok(record.id)
+        })
+        .await
+    }
+
+    async fn lookup(&self, id: &str) -> Result<UrlRecord, ShortenerError> {
+        self.memory.lookup(id).await
+    }
+
+    async fn visit(&self, id: &str) -> Result<UrlRecord, ShortenerError> {
+        // 只有限制了点击次数的链接需要保存，重启后 max_clicks 依然有效
+        // 每个链接最多写入 max_clicks 行，其他链接的跳转不写文件
+        let id = id.to_string();
+        self.write(move |memory, file| {
+            let record = memory.click(&id, Utc::now())?;
+            if record.max_clicks.is_some() {
+                file.append(&FileOp::Click { id })?;
+            }
+            Ok(record)
+        })
+        .await
+    }
+
+    async fn delete(&self, id: &str) -> Result<(), ShortenerError> {
+        let id = id.to_string();
+        self.write(move |memory, file| {
+            if !memory.remove(&id) {
+                return Err(ShortenerError::NotFound);
+            }
+            file.append(&FileOp::Delete { id })
+        })
+        .await
+    }
+
+    async fn list(&self) -> Result<Vec<UrlRecord>, ShortenerError> {
+        self.memory.list().await
+    }
+
+    async fn purge(&self, before: DateTime<Utc>) -> Result<u64, ShortenerError> {
+        self.write(move |memory, file| {
+            let expired = memory.remove_expired(before);
+            for id in &expired {
+                file.append(&FileOp::Purge { id: id.clone() })?;
+            }
+            if file.needs_compaction() {
+                *file = Self::compact(memory, &file.path)?;
+            }
+            Ok(expired.len() as u64)
+        })
+        .await
+    }
+
+    async fn record_clicks(&self, clicks: &[ClickEvent]) -> Result<(), ShortenerError> {
+        let clicks = clicks.to_vec();
+        self.write(move |memory, file| {
+            for click in clicks {
+                if memory.record(&click) {
+                    file.append(&FileOp::Event(click))?;
+                }
+            }
+            // 访问记录是追加最多的内容，不等到清理时再重写
+            if file.needs_compaction() {
+                *file = Self::compact(memory, &file.path)?;
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    async fn stats(&self, id: &str) -> Result<LinkStats, ShortenerError> {
+        self.memory.link_stats(id)
+    }
+
+    // 盐保存在数据文件旁边，如 shortener.jsonl 对应 shortener.salt
+    async fn ip_salt(&self) -> Result<String, ShortenerError> {
+        let path = self.path.with_extension("salt");
+        match async_fs::read_to_string(&path).await {
+            Ok(salt) if !salt.trim().is_empty() => return Ok(salt.trim().to_string()),
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        let salt = nanoid!(SALT_LEN);
+        async_fs::write(&path, &salt).await?;
+        Ok(salt)
+    }
+}
+
+impl LruCache {
+    fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            capacity,
+            ttl,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            next: 0,
+            version: 0,
+        }
+    }
+
+    // 返回 None 表示没有缓存，Some(None) 表示缓存了 ID 不存在
+    fn get(&mut self, id: &str, now: Instant) -> Option<Option<UrlRecord>> {
+        let entry = self.entries.get_mut(id)?;
+        if entry.expires_at <= now {
+            self.remove(id);
+            return None;
+        }
+        self.order.remove(&entry.used);
+        entry.used = self.next;
+        self.order.insert(self.next, id.to_string());
+        self.next += 1;
+        Some(entry.record.clone())
+    }
+
+    fn insert(&mut self, id: &str, record: Option<UrlRecord>, now: Instant) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.remove(id);
+        while self.entries.len() >= self.capacity {
+            let Some((_, oldest)) = self.order.pop_first() else {
+                break;
+            };
+            self.entries.remove(&oldest);
+        }
+        let entry = CacheEntry {
+            record,
+            expires_at: now + self.ttl,
+            used: self.next,
+        };
+        self.entries.insert(id.to_string(), entry);
+        self.order.insert(self.next, id.to_string());
+        self.next += 1;
+    }
+
+    fn remove(&mut self, id: &str) {
+        if let Some(entry) = self.entries.remove(id) {
+            self.order.remove(&entry.used);
+        }
+    }
+
+    fn invalidate(&mut self, id: &str) {
+        self.remove(id);
+        self.version += 1;
+    }
+}
+
+impl<S> CachedStore<S> {
+    fn new(inner: S, capacity: usize, ttl: Duration) -> Self {
+        Self {
+            inner,
+            cache: Mutex::new(LruCache::new(capacity, ttl)),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    // 命中时返回缓存的结果，未命中时返回当前的版本
+    fn cached(&self, id: &str) -> Result<Option<UrlRecord>, u64> {
+        let mut cache = self.cache.lock().unwrap();
+        match cache.get(id, Instant::now()) {
+            Some(record) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                Ok(record)
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                Err(cache.version)
+            }
+        }
+    }
+
+    // 回填存储返回的结果，查询期间有失效时放弃回填，避免缓存旧的数据
+    fn remember(&self, id: &str, ret: &Result<UrlRecord, ShortenerError>, version: u64) {
+        let record = match ret {
+            Ok(record) if !record.is_limited() => Some(record.clone()),
+            Err(ShortenerError::NotFound) => None,
+            _ => return,
+        };
+        let mut cache = self.cache.lock().unwrap();
+        if cache.version == version {
+            cache.insert(id, record, Instant::now());
+        }
+    }
+
+    fn invalidate(&self, id: &str) {
+        self.cache.lock().unwrap().invalidate(id);
+    }
+
+    fn cache_stats(&self) -> CacheStats {
+        let cache = self.cache.lock().unwrap();
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: cache.entries.len(),
+            capacity: cache.capacity,
+        }
+    }
+}
+
+impl<S: UrlStore> UrlStore for CachedStore<S> {
+    async fn shorten(&self, req: &ShortenReq) -> Result<String, ShortenerError> {
+        let id = self.inner.shorten(req).await?;
+        // alias 之前可能被缓存为不存在
+        self.invalidate(&id);
+        Ok(id)
+    }
+
+    async fn lookup(&self, id: &str) -> Result<UrlRecord, ShortenerError> {
+        let version = match self.cached(id) {
+            Ok(record) => return record.ok_or(ShortenerError::NotFound),
+            Err(version) => version,
+        };
+        let ret = self.inner.lookup(id).await;
+        self.remember(id, &ret, version);
+        ret
+    }
+
+    async fn visit(&self, id: &str) -> Result<UrlRecord, ShortenerError> {
+        let version = match self.cached(id) {
+            Ok(record) => return record.ok_or(ShortenerError::NotFound),
+            Err(version) => version,
+        };
+        let ret = self.inner.visit(id).await;
+        self.remember(id, &ret, version);
+        ret
+    }
+
+    async fn delete(&self, id: &str) -> Result<(), ShortenerError> {
+        let ret = self.inner.delete(id).await;
+        self.invalidate(id);
+        ret
+    }
+
+    async fn list(&self) -> Result<Vec<UrlRecord>, ShortenerError> {
+        self.inner.list().await
+    }
+
+    // 被清理的都是有限制的链接，不会出现在缓存中
+    async fn purge(&self, before: DateTime<Utc>) -> Result<u64, ShortenerError> {
+        self.inner.purge(before).await
+    }
+
+    async fn record_clicks(&self, clicks: &[ClickEvent]) -> Result<(), ShortenerError> {
+        self.inner.record_clicks(clicks).await
+    }
+
+    async fn stats(&self, id: &str) -> Result<LinkStats, ShortenerError> {
+        self.inner.stats(id).await
+    }
+
+    async fn ip_salt(&self) -> Result<String, ShortenerError> {
+        self.inner.ip_salt().await
+    }
+}
+
+fn encode(op: &FileOp) -> Result<String, ShortenerError> {
+    Ok(serde_json::to_string(op).map_err(io::Error::from)?)
+}
+
+// 按次数从多到少排列，只保留前 TOP_STATS 个
+fn top_entries(counts: &HashMap<String, u64>) -> Vec<TopEntry> {
+    let mut entries = counts
+        .iter()
+        .map(|(name, clicks)| TopEntry {
+            name: name.clone(),
+            clicks: *clicks,
+        })
+        .collect::<Vec<_>>();
+    entries.sort_by(|a, b| b.clicks.cmp(&a.clicks).then_with(|| a.name.cmp(&b.name)));
+    entries.truncate(TOP_STATS);
+    entries
+}
+
+// 只保存哈希后的 IP，可以统计独立访客但无法还原地址
+fn hash_ip(salt: &str, ip: IpAddr) -> String {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(salt.as_bytes());
+    hasher.update(ip.to_string().as_bytes());
+    hasher.finalize().to_hex()[..16].to_string()
+}
+
+fn validate_url(url: &str) -> Result<String, ShortenerError> {
+    let parsed_url =
+        url::Url::parse(url).map_err(|_| ShortenerError::InvalidUrl(url.to_string()))?;
+    if !["http", "https"].contains(&parsed_url.scheme()) {
+        return Err(ShortenerError::InvalidUrl(url.to_string()));
+    }
+    Ok(parsed_url.into())
+}
+
+fn validate_alias(alias: &str) -> Result<(), ShortenerError> {
+    let valid = (ALIAS_MIN_LEN..=ALIAS_MAX_LEN).contains(&alias.len())
+        && alias
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        && !RESERVED_ALIASES.contains(&alias.to_ascii_lowercase().as_str());
+    match valid {
+        true => Ok(()),
+        false => Err(ShortenerError::InvalidAlias(alias.to_string())),
+    }
+}
+
+fn validate_limits(req: &ShortenReq) -> Result<(), ShortenerError> {
+    if req
+        .expires_at
+        .is_some_and(|expires_at| expires_at <= Utc::now())
+    {
+        return Err(ShortenerError::InvalidLimit(
+            "expires_at must be in the future".to_string(),
+        ));
+    }
+    if req.max_clicks.is_some_and(|max_clicks| max_clicks < 1) {
+        return Err(ShortenerError::InvalidLimit(
+            "max_clicks must be at least 1".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+// Implement `IntoResponse` for `ShortenerError`.
+impl IntoResponse for ShortenerError {
+    fn into_response(self) -> Response {
+        let (status, error_message) = match &self {
+            ShortenerError::DatabaseError(_) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Database error occurred.",
+            ),
+            ShortenerError::StorageError(_) => {
+                (StatusCode::INTERNAL_SERVER_ERROR, "Storage error occurred.")
+            }
+            ShortenerError::NotFound => (StatusCode::NOT_FOUND, "URL parsing error occurred."),
+            ShortenerError::InvalidUrl(_) => {
+                (StatusCode::UNPROCESSABLE_ENTITY, "Failed to shorten URL.")
+            }
+            ShortenerError::InvalidAlias(_) => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "Alias must be 3-32 letters, digits, '-' or '_' and not reserved.",
+            ),
+            ShortenerError::AliasTaken(_) => (StatusCode::CONFLICT, "Alias is already taken."),
+            ShortenerError::InvalidLimit(_) => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "expires_at must be in the future and max_clicks at least 1.",
+            ),
+            ShortenerError::Gone => (StatusCode::GONE, "URL has expired."),
+            ShortenerError::Unauthorized => {
+                (StatusCode::UNAUTHORIZED, "A valid admin token is required.")
+            }
+        };
+        let body = Json({
+            let mut map = std::collections::HashMap::new();
+            map.insert("error", error_message);
+            map
+        });
+        (status, body).into_response()
+    }
+}
+
+impl fmt::Display for ShortenRes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.short_url)
+    }
+}
 
 impl fmt::Display for ShortenReq {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{}", self.url)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 测试结束时删除数据文件以及旁边的盐和临时文件，断言失败时也会删除
+    struct TempFile(PathBuf);
+
+    impl std::ops::Deref for TempFile {
+        type Target = PathBuf;
+
+        fn deref(&self) -> &PathBuf {
+            &self.0
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            for path in [
+                self.0.with_extension("salt"),
+                self.0.with_extension("tmp"),
+                self.0.clone(),
+            ] {
+                let _ = fs::remove_file(path);
+            }
+        }
+    }
+
+    fn temp_file(name: &str) -> TempFile {
+        TempFile(std::env::temp_dir().join(format!("shortener-{}-{}", nanoid!(8), name)))
+    }
+
+    fn req(url: &str, alias: Option<&str>) -> ShortenReq {
+        ShortenReq {
+            url: url.into(),
+            alias: alias.map(Into::into),
+            ..Default::default()
+        }
+    }
+
+    fn app_state<S: UrlStore>(store: S) -> State<(AppState<S>, String)> {
+        let (state, clicks) = AppState::new(store, "salt", Some("admin"));
+        tokio::spawn(record_clicks(state.store.clone(), clicks));
+        State((state, "localhost:9876".to_string()))
+    }
+
+    fn admin(token: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, format!("Bearer {token}").parse().unwrap());
+        headers
+    }
+
+    fn client() -> ConnectInfo<SocketAddr> {
+        ConnectInfo("127.0.0.1:50000".parse().unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_memory_store() -> Result<()> {
+        let store = MemoryStore::default();
+        let id = store
+            .shorten(&req("https://www.rust-lang.org", None))
+            .await?;
+        assert_eq!(id.len(), 6);
+        assert_eq!(
+            store
+                .shorten(&req("https://www.rust-lang.org", None))
+                .await?,
+            id
+        );
+        let other = store.shorten(&req("https://crates.io", None)).await?;
+        assert_ne!(other, id);
+
+        assert_eq!(store.lookup(&id).await?.url, "https://www.rust-lang.org");
+        assert_eq!(store.list().await?.len(), 2);
+
+        store.delete(&id).await?;
+        assert!(matches!(
+            store.lookup(&id).await,
+            Err(ShortenerError::NotFound)
+        ));
+        assert!(matches!(
+            store.delete(&id).await,
+            Err(ShortenerError::NotFound)
+        ));
+        // 删除后同一个 URL 会得到新的 ID
+        assert_ne!(
+            store
+                .shorten(&req("https://www.rust-lang.org", None))
+                .await?,
+            id
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_file_store_replays_and_compacts() -> Result<()> {
+        let path = temp_file("urls.jsonl");
+        let store = FileStore::open(&path)?;
+        let kept = store
+            .shorten(&req("https://www.rust-lang.org", None))
+            .await?;
+        let deleted = store.shorten(&req("https://crates.io", None)).await?;
+        store.delete(&deleted).await?;
+        assert_eq!(fs::read_to_string(&*path)?.lines().count(), 3);
+        drop(store);
+
+        let store = FileStore::open(&path)?;
+        assert_eq!(
+            store.list().await?,
+            vec![UrlRecord {
+                id: kept.clone(),
+                url: "https://www.rust-lang.org".into(),
+                ..Default::default()
+            }]
+        );
+        assert_eq!(
+            store
+                .shorten(&req("https://www.rust-lang.org", None))
+                .await?,
+            kept
+        );
+        assert_eq!(fs::read_to_string(&*path)?.lines().count(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_handlers() -> Result<()> {
+        let state = app_state(MemoryStore::default());
+        let res = shorten(state.clone(), Json(req("https://www.rust-lang.org", None)))
+            .await?
+            .into_response();
+        assert_eq!(res.status(), StatusCode::CREATED);
+
+        let id = state.0 .0.store.list().await?[0].id.clone();
+        let res = redirect(Path(id.clone()), state.clone(), client(), HeaderMap::new())
+            .await?
+            .into_response();
+        assert_eq!(res.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(res.headers()[LOCATION], "https://www.rust-lang.org/");
+
+        // 解析时去掉的换行符不能留在保存的 URL 中
+        shorten(
+            state.clone(),
+            Json(req("https://a.com/\nx", Some("newline"))),
+        )
+        .await?;
+        let res = redirect(
+            Path("newline".into()),
+            state.clone(),
+            client(),
+            HeaderMap::new(),
+        )
+        .await?
+        .into_response();
+        assert_eq!(res.headers()[LOCATION], "https://a.com/x");
+
+        let res = shorten(state.clone(), Json(req("error_url://error_url", None))).await;
+        assert_eq!(
+            res.err().unwrap().into_response().status(),
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+
+        // 列出和删除链接需要 admin token
+        for headers in [HeaderMap::new(), admin("wrong")] {
+            let res = list(state.clone(), headers.clone()).await;
+            assert_eq!(
+                res.err().unwrap().into_response().status(),
+                StatusCode::UNAUTHORIZED
+            );
+            let res = delete(Path(id.clone()), state.clone(), headers).await;
+            assert_eq!(
+                res.err().unwrap().into_response().status(),
+                StatusCode::UNAUTHORIZED
+            );
+        }
+        let (no_admin, _) = AppState::new(MemoryStore::default(), "salt", None);
+        let res = list(State((no_admin, String::new())), HeaderMap::new()).await;
+        assert_eq!(
+            res.err().unwrap().into_response().status(),
+            StatusCode::UNAUTHORIZED
+        );
+        let res = list(state.clone(), admin("admin")).await?.into_response();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let res = delete(Path(id.clone()), state.clone(), admin("admin"))
+            .await?
+            .into_response();
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        let res = redirect(Path(id), state, client(), HeaderMap::new()).await;
+        assert_eq!(
+            res.err().unwrap().into_response().status(),
+            StatusCode::NOT_FOUND
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_alias() {
+        assert!(validate_alias("rust").is_ok());
+        assert!(validate_alias("my-Link_2024").is_ok());
+        assert!(validate_alias("ab").is_err());
+        assert!(validate_alias(&"a".repeat(ALIAS_MAX_LEN + 1)).is_err());
+        assert!(validate_alias("has space").is_err());
+        assert!(validate_alias("a/b").is_err());
+        assert!(validate_alias("Admin").is_err());
+        assert!(validate_alias("health").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_aliases() -> Result<()> {
+        let store = MemoryStore::default();
+        let random = store
+            .shorten(&req("https://www.rust-lang.org", None))
+            .await?;
+        let alias = store
+            .shorten(&req("https://www.rust-lang.org", Some("rust")))
+            .await?;
+        assert_eq!(alias, "rust");
+        // 同一个 alias 和 URL 重复提交不算冲突
+        assert_eq!(
+            store
+                .shorten(&req("https://www.rust-lang.org", Some("rust")))
+                .await?,
+            "rust"
+        );
+        assert!(matches!(
+            store.shorten(&req("https://crates.io", Some("rust"))).await,
+            Err(ShortenerError::AliasTaken(_))
+        ));
+        // 随机 ID 不受 alias 影响
+        assert_eq!(
+            store
+                .shorten(&req("https://www.rust-lang.org", None))
+                .await?,
+            random
+        );
+        store.delete("rust").await?;
+        assert_eq!(
+            store
+                .shorten(&req("https://www.rust-lang.org", None))
+                .await?,
+            random
+        );
+
+        let state = app_state(store);
+        let res = shorten(state.clone(), Json(req("https://crates.io", Some(&random)))).await;
+        assert_eq!(
+            res.err().unwrap().into_response().status(),
+            StatusCode::CONFLICT
+        );
+        let res = shorten(state, Json(req("https://crates.io", Some("api")))).await;
+        assert_eq!(
+            res.err().unwrap().into_response().status(),
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_limited_links() -> Result<()> {
+        let store = MemoryStore::default();
+        let plain = store
+            .shorten(&req("https://www.rust-lang.org", None))
+            .await?;
+        let limited = ShortenReq {
+            max_clicks: Some(2),
+            ..req("https://www.rust-lang.org", None)
+        };
+        // 带有限制的链接不复用已有的 ID
+        let id = store.shorten(&limited).await?;
+        assert_ne!(id, plain);
+        assert_ne!(store.shorten(&limited).await?, id);
+
+        let now = Utc::now();
+        assert_eq!(store.click(&id, now)?.clicks, 1);
+        assert_eq!(store.click(&id, now)?.clicks, 2);
+        assert!(matches!(store.click(&id, now), Err(ShortenerError::Gone)));
+        // 没有限制的链接只通过访问记录计数
+        assert_eq!(store.click(&plain, now)?.clicks, 0);
+
+        let expiring = ShortenReq {
+            expires_at: Some(now + chrono::Duration::hours(1)),
+            ..req("https://crates.io", None)
+        };
+        let expiring = store.shorten(&expiring).await?;
+        assert!(store.click(&expiring, now).is_ok());
+        let later = now + chrono::Duration::hours(2);
+        assert!(matches!(
+            store.click(&expiring, later),
+            Err(ShortenerError::Gone)
+        ));
+
+        // 只清理过期的链接，点击次数用完的链接继续返回 Gone
+        assert!(store.remove_expired(now).is_empty());
+        assert_eq!(store.remove_expired(later), vec![expiring.clone()]);
+        assert!(matches!(
+            store.lookup(&expiring).await,
+            Err(ShortenerError::NotFound)
+        ));
+        assert!(matches!(store.click(&id, later), Err(ShortenerError::Gone)));
+        assert_eq!(
+            store
+                .shorten(&req("https://www.rust-lang.org", None))
+                .await?,
+            plain
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_redirect_limited_link() -> Result<()> {
+        let state = app_state(MemoryStore::default());
+        let data = ShortenReq {
+            max_clicks: Some(1),
+            ..req("https://www.rust-lang.org", Some("once"))
+        };
+        shorten(state.clone(), Json(data)).await?;
+
+        let res = redirect(
+            Path("once".into()),
+            state.clone(),
+            client(),
+            HeaderMap::new(),
+        )
+        .await?
+        .into_response();
+        assert_eq!(res.status(), StatusCode::TEMPORARY_REDIRECT);
+        let res = redirect(
+            Path("once".into()),
+            state.clone(),
+            client(),
+            HeaderMap::new(),
+        )
+        .await;
+        assert_eq!(
+            res.err().unwrap().into_response().status(),
+            StatusCode::GONE
+        );
+
+        let data = ShortenReq {
+            expires_at: Some(Utc::now() - chrono::Duration::seconds(1)),
+            ..req("https://crates.io", None)
+        };
+        let res = shorten(state, Json(data)).await;
+        assert_eq!(
+            res.err().unwrap().into_response().status(),
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_file_store_compacts_clicks() -> Result<()> {
+        let path = temp_file("compact.jsonl");
+        let store = FileStore::open(&path)?;
+        let plain = store
+            .shorten(&req("https://www.rust-lang.org", None))
+            .await?;
+        let limited = ShortenReq {
+            max_clicks: Some(10_000),
+            ..req("https://crates.io", None)
+        };
+        let limited = store.shorten(&limited).await?;
+        let lines = || fs::read_to_string(&*path).map(|data| data.lines().count());
+
+        // 没有点击次数限制的链接跳转时不写文件
+        for _ in 0..10 {
+            store.visit(&plain).await?;
+        }
+        assert_eq!(lines()?, 2);
+
+        // 追加的行数足够多时在清理时重写，点击次数合并到记录中
+        for _ in 0..COMPACT_MIN_LINES - 3 {
+            store.visit(&limited).await?;
+        }
+        store.purge(Utc::now()).await?;
+        assert_eq!(lines()?, COMPACT_MIN_LINES - 1);
+        store.visit(&limited).await?;
+        store.purge(Utc::now()).await?;
+        assert_eq!(lines()?, 2);
+        drop(store);
+
+        let store = FileStore::open(&path)?;
+        assert_eq!(
+            store.lookup(&limited).await?.clicks,
+            COMPACT_MIN_LINES as i64 - 2
+        );
+        Ok(())
+    }
+
+    fn click(id: &str, day: u32, referrer: Option<&str>, ip: &str) -> ClickEvent {
+        ClickEvent {
+            id: id.into(),
+            timestamp: format!("2024-05-{day:02}T12:00:00Z").parse().unwrap(),
+            referrer: referrer.map(Into::into),
+            user_agent: Some("curl/8.0".into()),
+            ip_hash: hash_ip("salt", ip.parse().unwrap()),
+        }
+    }
+
+    #[test]
+    fn test_link_stats() {
+        let events = [
+            click("rust", 1, Some("https://a.com"), "10.0.0.1"),
+            click("rust", 1, Some("https://b.com"), "10.0.0.2"),
+            click("rust", 2, Some("https://b.com"), "10.0.0.1"),
+            click("rust", 3, None, "10.0.0.1"),
+        ];
+        let mut clicks = ClickStats::new("rust");
+        for event in &events {
+            clicks.add(event);
+        }
+        let stats = LinkStats::from(&clicks);
+        assert_eq!(stats.total_clicks, 4);
+        assert_eq!(stats.unique_visitors, 2);
+        let day = |d| NaiveDate::from_ymd_opt(2024, 5, d).unwrap();
+        assert_eq!(
+            stats.clicks_per_day,
+            BTreeMap::from([(day(1), 2), (day(2), 1), (day(3), 1)])
+        );
+        let names = stats
+            .top_referrers
+            .iter()
+            .map(|e| (e.name.as_str(), e.clicks))
+            .collect::<Vec<_>>();
+        assert_eq!(names, [("https://b.com", 2), ("https://a.com", 1)]);
+        assert_eq!(stats.top_user_agents[0].clicks, 4);
+
+        // 相同的盐和 IP 得到相同的哈希，不包含原始地址
+        let ip = "10.0.0.1".parse().unwrap();
+        assert_eq!(hash_ip("salt", ip), hash_ip("salt", ip));
+        assert_ne!(hash_ip("salt", ip), hash_ip("pepper", ip));
+        assert!(!hash_ip("salt", ip).contains("10.0.0.1"));
+    }
+
+    #[tokio::test]
+    async fn test_redirect_records_clicks() -> Result<()> {
+        let state = app_state(MemoryStore::default());
+        shorten(
+            state.clone(),
+            Json(req("https://www.rust-lang.org", Some("rust"))),
+        )
+        .await?;
+        let mut headers = HeaderMap::new();
+        headers.insert(REFERER, "https://news.ycombinator.com".parse()?);
+        headers.insert(USER_AGENT, "Mozilla/5.0".parse()?);
+        for _ in 0..3 {
+            redirect(
+                Path("rust".into()),
+                state.clone(),
+                client(),
+                headers.clone(),
+            )
+            .await?;
+        }
+
+        // 访问记录由后台任务异步写入
+        let mut stats = LinkStats::default();
+        for _ in 0..100 {
+            stats = state.0 .0.store.stats("rust").await?;
+            if stats.total_clicks == 3 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(stats.total_clicks, 3);
+        assert_eq!(stats.unique_visitors, 1);
+        assert_eq!(stats.top_referrers[0].name, "https://news.ycombinator.com");
+        assert_eq!(stats.top_user_agents[0].name, "Mozilla/5.0");
+
+        let res = stats_handler_status(state.clone(), "rust").await;
+        assert_eq!(res, StatusCode::OK);
+        let res = stats_handler_status(state, "missing").await;
+        assert_eq!(res, StatusCode::NOT_FOUND);
+        Ok(())
+    }
+
+    async fn stats_handler_status(
+        state: State<(AppState<MemoryStore>, String)>,
+        id: &str,
+    ) -> StatusCode {
+        match stats(Path(id.into()), state).await {
+            Ok(res) => res.into_response().status(),
+            Err(e) => e.into_response().status(),
+        }
+    }
+
+    // 重放追加的文件和读取重写后的文件都能恢复全部数据
+    #[tokio::test]
+    async fn test_file_store_reopens() -> Result<()> {
+        let path = temp_file("reopen.jsonl");
+        let store = FileStore::open(&path)?;
+        let random = store
+            .shorten(&req("https://www.rust-lang.org", None))
+            .await?;
+        store
+            .shorten(&req("https://www.rust-lang.org", Some("rust")))
+            .await?;
+        let limited = ShortenReq {
+            max_clicks: Some(2),
+            ..req("https://crates.io", None)
+        };
+        let limited = store.shorten(&limited).await?;
+        store.visit(&limited).await?;
+        store
+            .record_clicks(&[
+                click(&random, 1, Some("https://a.com"), "10.0.0.1"),
+                click("missing", 1, None, "10.0.0.1"),
+            ])
+            .await?;
+        drop(store);
+
+        for _ in 0..2 {
+            let store = FileStore::open(&path)?;
+            assert!(store.lookup("rust").await?.alias);
+            assert_eq!(
+                store
+                    .shorten(&req("https://www.rust-lang.org", None))
+                    .await?,
+                random
+            );
+            assert_eq!(store.lookup(&limited).await?.clicks, 1);
+            let stats = store.stats(&random).await?;
+            assert_eq!(stats.total_clicks, 1);
+            assert_eq!(stats.top_referrers[0].name, "https://a.com");
+            assert!(matches!(
+                store.stats("missing").await,
+                Err(ShortenerError::NotFound)
+            ));
+        }
+
+        let store = FileStore::open(&path)?;
+        assert_eq!(store.visit(&limited).await?.clicks, 2);
+        assert!(matches!(
+            store.visit(&limited).await,
+            Err(ShortenerError::Gone)
+        ));
+        assert_eq!(store.purge(Utc::now()).await?, 0);
+        store.delete(&random).await?;
+        drop(store);
+
+        let store = FileStore::open(&path)?;
+        assert!(matches!(
+            store.visit(&limited).await,
+            Err(ShortenerError::Gone)
+        ));
+        assert!(matches!(
+            store.stats(&random).await,
+            Err(ShortenerError::NotFound)
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_file_store_compacts_events() -> Result<()> {
+        let path = temp_file("events.jsonl");
+        let store = FileStore::open(&path)?;
+        let id = store
+            .shorten(&req("https://www.rust-lang.org", None))
+            .await?;
+        let clicks = (0..COMPACT_MIN_LINES)
+            .map(|i| click(&id, 1 + i as u32 % 2, None, "10.0.0.1"))
+            .collect::<Vec<_>>();
+
+        // 访问记录重写为一行统计，文件不随访问次数增长
+        store.record_clicks(&clicks).await?;
+        assert_eq!(fs::read_to_string(&*path)?.lines().count(), 2);
+        drop(store);
+
+        let store = FileStore::open(&path)?;
+        let stats = store.stats(&id).await?;
+        assert_eq!(stats.total_clicks, COMPACT_MIN_LINES as u64);
+        assert_eq!(stats.unique_visitors, 1);
+        assert_eq!(stats.clicks_per_day.len(), 2);
+        assert_eq!(store.lookup(&id).await?.clicks, COMPACT_MIN_LINES as i64);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_purge_keeps_events() -> Result<()> {
+        let path = temp_file("purge.jsonl");
+        let store = FileStore::open(&path)?;
+        let now = Utc::now();
+        let expiring = ShortenReq {
+            expires_at: Some(now + chrono::Duration::hours(1)),
+            ..req("https://www.rust-lang.org", Some("rust"))
+        };
+        store.shorten(&expiring).await?;
+        store
+            .record_clicks(&[click("rust", 1, None, "10.0.0.1")])
+            .await?;
+
+        // 过期后在宽限期内不清理
+        assert_eq!(store.purge(now).await?, 0);
+        assert_eq!(store.purge(now + chrono::Duration::hours(2)).await?, 1);
+        assert!(matches!(
+            store.lookup("rust").await,
+            Err(ShortenerError::NotFound)
+        ));
+        // 清理后访问记录保留，ID 也不能再被使用
+        assert_eq!(store.stats("rust").await?.total_clicks, 1);
+        assert!(matches!(
+            store.shorten(&req("https://crates.io", Some("rust"))).await,
+            Err(ShortenerError::AliasTaken(_))
+        ));
+        drop(store);
+
+        // 重放和重写文件后依然保留
+        for _ in 0..2 {
+            let store = FileStore::open(&path)?;
+            assert!(store.list().await?.is_empty());
+            assert_eq!(store.stats("rust").await?.total_clicks, 1);
+        }
+        let store = FileStore::open(&path)?;
+        store.delete("rust").await?;
+        assert!(matches!(
+            store.stats("rust").await,
+            Err(ShortenerError::NotFound)
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ip_salt() -> Result<()> {
+        // 不同的盐得到不同的哈希，没有盐无法通过枚举 IP 还原
+        let ip = "10.0.0.1".parse()?;
+        assert_ne!(hash_ip("salt", ip), hash_ip("pepper", ip));
+        assert_eq!(hash_ip("salt", ip), hash_ip("salt", ip));
+
+        let memory = MemoryStore::default();
+        assert_eq!(memory.ip_salt().await?.len(), SALT_LEN);
+        assert_ne!(memory.ip_salt().await?, memory.ip_salt().await?);
+
+        // 文件存储的盐在重启后保持不变
+        let path = temp_file("salt.jsonl");
+        let salt = FileStore::open(&path)?.ip_salt().await?;
+        assert_eq!(salt.len(), SALT_LEN);
+        assert_eq!(FileStore::open(&path)?.ip_salt().await?, salt);
+        assert_ne!(hash_ip(&salt, ip), hash_ip("", ip));
+        Ok(())
+    }
+
+    fn record(id: &str) -> Option<UrlRecord> {
+        Some(UrlRecord {
+            id: id.into(),
+            url: format!("https://{id}.com"),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_lru_cache() {
+        let now = Instant::now();
+        let mut cache = LruCache::new(2, Duration::from_secs(10));
+        cache.insert("a", record("a"), now);
+        cache.insert("b", None, now);
+        assert_eq!(cache.get("a", now), Some(record("a")));
+        // b 最久未使用，被淘汰
+        cache.insert("c", record("c"), now);
+        assert_eq!(cache.get("b", now), None);
+        assert_eq!(cache.get("a", now), Some(record("a")));
+        assert_eq!(cache.get("c", now), Some(record("c")));
+
+        let later = now + Duration::from_secs(10);
+        assert_eq!(cache.get("a", later), None);
+        assert_eq!(cache.entries.len(), 1);
+        assert_eq!(cache.order.len(), 1);
+
+        cache.insert("b", None, later);
+        assert_eq!(cache.get("b", later), Some(None));
+        cache.invalidate("b");
+        assert_eq!(cache.get("b", later), None);
+
+        let mut disabled = LruCache::new(0, Duration::from_secs(10));
+        disabled.insert("a", record("a"), now);
+        assert_eq!(disabled.get("a", now), None);
+    }
+
+    #[tokio::test]
+    async fn test_cached_store() -> Result<()> {
+        let store = CachedStore::new(MemoryStore::default(), 100, Duration::from_secs(60));
+        let id = store
+            .shorten(&req("https://www.rust-lang.org", None))
+            .await?;
+        store.visit(&id).await?;
+        store.visit(&id).await?;
+        // 第二次访问命中缓存，clicks 依然由访问记录统计
+        store
+            .record_clicks(&[
+                click(&id, 1, None, "10.0.0.1"),
+                click(&id, 1, None, "10.0.0.2"),
+            ])
+            .await?;
+        assert_eq!(store.list().await?[0].clicks, 2);
+
+        // 不存在的 ID 也会被缓存，创建后失效
+        assert!(store.visit("rust").await.is_err());
+        assert!(store.visit("rust").await.is_err());
+        store
+            .shorten(&req("https://www.rust-lang.org", Some("rust")))
+            .await?;
+        assert_eq!(store.visit("rust").await?.url, "https://www.rust-lang.org");
+
+        // 删除后不能再从缓存中访问
+        store.delete(&id).await?;
+        assert!(matches!(
+            store.visit(&id).await,
+            Err(ShortenerError::NotFound)
+        ));
+
+        // 有限制的链接每次都访问存储
+        let limited = ShortenReq {
+            max_clicks: Some(1),
+            ..req("https://crates.io", None)
+        };
+        let limited = store.shorten(&limited).await?;
+        store.visit(&limited).await?;
+        assert!(matches!(
+            store.visit(&limited).await,
+            Err(ShortenerError::Gone)
+        ));
+
+        let stats = store.cache_stats();
+        assert_eq!((stats.hits, stats.misses), (2, 6));
+        assert_eq!(stats.capacity, 100);
+        assert_eq!(stats.entries, 2);
+        Ok(())
+    }
+}